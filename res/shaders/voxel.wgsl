@@ -0,0 +1,78 @@
+// Default shader for the voxel render pipeline.
+// Load it through the `ResourceSystem` as "shaders/voxel.wgsl".
+
+struct CameraUniform {
+    view_projection: mat4x4<f32>;
+};
+
+struct ChunkUniform {
+    offset: vec4<f32>;
+};
+
+struct LightingUniform {
+    // Unit vector towards the sun in xyz, daylight strength in w
+    sun: vec4<f32>;
+};
+
+[[group(0), binding(0)]]
+var<uniform> camera: CameraUniform;
+
+[[group(1), binding(0)]]
+var<uniform> chunk: ChunkUniform;
+
+[[group(2), binding(0)]]
+var<uniform> lighting: LightingUniform;
+
+struct VertexInput {
+    [[location(0)]] position: vec3<f32>;
+    [[location(1)]] color: vec3<f32>;
+    [[location(2)]] normal: vec3<f32>;
+    // Sky light in x, block light in y, both from 0 to 1
+    [[location(3)]] light: vec2<f32>;
+    // Ambient occlusion of the corner, 0 when fully occluded
+    [[location(4)]] ao: f32;
+};
+
+struct VertexOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+    [[location(0)]] color: vec3<f32>;
+};
+
+// Brightness never drops below this, so unlit caves are not completely black
+let MIN_BRIGHTNESS: f32 = 0.03;
+// Share of the brightness that comes from the sun, the rest is ambient
+let SUN_WEIGHT: f32 = 0.3;
+// Brightness of fully occluded corners relative to unoccluded ones
+let MIN_OCCLUSION: f32 = 0.4;
+
+// Every light level is 80% as bright as the one above it
+fn light_curve(level: f32) -> f32 {
+    return pow(0.8, (1.0 - level) * 15.0);
+}
+
+// Fixed shading per face direction, so that edges stay visible on faces turned away from the sun
+fn face_shade(normal: vec3<f32>) -> f32 {
+    let axis_shade = dot(abs(normal), vec3<f32>(0.8, 1.0, 0.6));
+    return axis_shade - max(-normal.y, 0.0) * 0.5;
+}
+
+[[stage(vertex)]]
+fn vs_main(in: VertexInput) -> VertexOutput {
+    var out: VertexOutput;
+    let world_position = in.position + chunk.offset.xyz;
+    out.clip_position = camera.view_projection * vec4<f32>(world_position, 1.0);
+
+    let sky_light = light_curve(in.light.x) * lighting.sun.w;
+    let block_light = light_curve(in.light.y);
+    let ambient = max(sky_light, block_light) * face_shade(in.normal);
+    let sun_light = max(dot(in.normal, lighting.sun.xyz), 0.0) * sky_light;
+    let occlusion = mix(MIN_OCCLUSION, 1.0, in.ao);
+    let brightness = mix(ambient, sun_light, SUN_WEIGHT) * occlusion;
+    out.color = in.color * clamp(brightness, MIN_BRIGHTNESS, 1.0);
+    return out;
+}
+
+[[stage(fragment)]]
+fn fs_main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    return vec4<f32>(in.color, 1.0);
+}
@@ -5,7 +5,7 @@ use wgpu;
 use winit::event::Event;
 use winit::event_loop::ControlFlow;
 use winit::event_loop::{EventLoop, EventLoopWindowTarget};
-use winit::window::{Window, WindowBuilder};
+use winit::window::{Fullscreen, Window, WindowBuilder};
 
 pub use winit;
 
@@ -13,7 +13,7 @@ pub use winit;
 #[derive(Debug)]
 enum EventLoopEvent {
     CreateWindow {
-        builder: WindowBuilder,
+        builder: Box<WindowBuilder>,
         response_tx: mpsc::Sender<Result<()>>,
     },
     CreateWgpuSurface {
@@ -29,9 +29,38 @@ enum EventLoopEvent {
     GetWindowInnerSize {
         response_tx: mpsc::Sender<Result<winit::dpi::PhysicalSize<u32>>>,
     },
+    SetCursorGrab {
+        grab: bool,
+        response_tx: mpsc::Sender<Result<()>>,
+    },
+    SetCursorVisible {
+        visible: bool,
+        response_tx: mpsc::Sender<Result<()>>,
+    },
+    SetFullscreen {
+        mode: FullscreenMode,
+        response_tx: mpsc::Sender<Result<()>>,
+    },
+    SetTitle {
+        title: String,
+        response_tx: mpsc::Sender<Result<()>>,
+    },
+    SetInnerSize {
+        size: winit::dpi::Size,
+        response_tx: mpsc::Sender<Result<()>>,
+    },
     Exit,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FullscreenMode {
+    Windowed,
+    /// A borderless window covering the monitor the window is currently on
+    Borderless,
+    /// Exclusive fullscreen using the best video mode of the monitor the window is currently on
+    Exclusive,
+}
+
 type Result<T> = std::result::Result<T, EventLoopError>;
 
 #[derive(Error, Debug)]
@@ -40,8 +69,14 @@ pub enum EventLoopError {
     WindowExists,
     #[error("No valid window exists to perform operation")]
     WindowMissing,
+    #[error("The window is not on any monitor")]
+    MonitorMissing,
+    #[error("The monitor has no video modes for exclusive fullscreen")]
+    VideoModeMissing,
     #[error(transparent)]
     WinitOsError(#[from] winit::error::OsError),
+    #[error(transparent)]
+    WinitExternalError(#[from] winit::error::ExternalError),
 }
 
 //EVENT LOOP PROXY DEFINITION
@@ -54,7 +89,7 @@ impl EventLoopProxy {
         let (tx, rx) = mpsc::channel();
 
         let event = EventLoopEvent::CreateWindow {
-            builder: wb,
+            builder: Box::new(wb),
             response_tx: tx,
         };
         self.el_proxy.send_event(event).unwrap();
@@ -109,18 +144,189 @@ impl EventLoopProxy {
         rx.recv().unwrap()
     }
 
+    /// Confines the cursor to the window so that it cannot leave it.
+    /// winit 0.26 has no separate mode that locks the cursor in place, so the cursor can still move
+    /// within the window and should be hidden with `set_cursor_visible` for mouse look, using the raw
+    /// `MouseMotion` device events instead of the cursor position. macOS is the exception and keeps the
+    /// cursor at a fixed location, mobile platforms return an error.
+    pub fn set_cursor_grab(&self, grab: bool) -> Result<()> {
+        let (tx, rx) = mpsc::channel();
+
+        let event = EventLoopEvent::SetCursorGrab {
+            grab,
+            response_tx: tx,
+        };
+        self.el_proxy.send_event(event).unwrap();
+
+        rx.recv().unwrap()
+    }
+
+    pub fn set_cursor_visible(&self, visible: bool) -> Result<()> {
+        let (tx, rx) = mpsc::channel();
+
+        let event = EventLoopEvent::SetCursorVisible {
+            visible,
+            response_tx: tx,
+        };
+        self.el_proxy.send_event(event).unwrap();
+
+        rx.recv().unwrap()
+    }
+
+    pub fn set_fullscreen(&self, mode: FullscreenMode) -> Result<()> {
+        let (tx, rx) = mpsc::channel();
+
+        let event = EventLoopEvent::SetFullscreen {
+            mode,
+            response_tx: tx,
+        };
+        self.el_proxy.send_event(event).unwrap();
+
+        rx.recv().unwrap()
+    }
+
+    pub fn set_title(&self, title: &str) -> Result<()> {
+        let (tx, rx) = mpsc::channel();
+
+        let event = EventLoopEvent::SetTitle {
+            title: title.to_owned(),
+            response_tx: tx,
+        };
+        self.el_proxy.send_event(event).unwrap();
+
+        rx.recv().unwrap()
+    }
+
+    pub fn set_inner_size<S: Into<winit::dpi::Size>>(&self, size: S) -> Result<()> {
+        let (tx, rx) = mpsc::channel();
+
+        let event = EventLoopEvent::SetInnerSize {
+            size: size.into(),
+            response_tx: tx,
+        };
+        self.el_proxy.send_event(event).unwrap();
+
+        rx.recv().unwrap()
+    }
+
     pub fn exit(&self) {
         self.el_proxy.send_event(EventLoopEvent::Exit).unwrap();
     }
 }
 
+#[derive(Debug, Clone)]
 pub struct DeviceEvent {
     pub device_id: winit::event::DeviceId,
     pub event: winit::event::DeviceEvent,
 }
 
+#[derive(Debug, Clone)]
 pub enum WindowEvent {
     CloseRequested,
+    Resized(winit::dpi::PhysicalSize<u32>),
+    /// The window was moved to a monitor with a different DPI, `new_inner_size` is the size the window was given
+    ScaleFactorChanged {
+        scale_factor: f64,
+        new_inner_size: winit::dpi::PhysicalSize<u32>,
+    },
+    Focused(bool),
+    KeyboardInput {
+        device_id: winit::event::DeviceId,
+        input: winit::event::KeyboardInput,
+        is_synthetic: bool,
+    },
+    /// A unicode character was typed, this accounts for the keyboard layout and modifiers
+    ReceivedCharacter(char),
+    ModifiersChanged(winit::event::ModifiersState),
+    MouseInput {
+        device_id: winit::event::DeviceId,
+        state: winit::event::ElementState,
+        button: winit::event::MouseButton,
+    },
+    /// The cursor moved within the window, `position` is relative to the top-left corner of the window
+    CursorMoved {
+        device_id: winit::event::DeviceId,
+        position: winit::dpi::PhysicalPosition<f64>,
+    },
+    MouseWheel {
+        device_id: winit::event::DeviceId,
+        delta: winit::event::MouseScrollDelta,
+        phase: winit::event::TouchPhase,
+    },
+    CursorEntered {
+        device_id: winit::event::DeviceId,
+    },
+    CursorLeft {
+        device_id: winit::event::DeviceId,
+    },
+}
+
+impl WindowEvent {
+    fn from_winit(event: winit::event::WindowEvent) -> Option<WindowEvent> {
+        use winit::event::WindowEvent as WinitWindowEvent;
+        match event {
+            WinitWindowEvent::CloseRequested => Some(WindowEvent::CloseRequested),
+            WinitWindowEvent::Resized(size) => Some(WindowEvent::Resized(size)),
+            WinitWindowEvent::ScaleFactorChanged {
+                scale_factor,
+                new_inner_size,
+            } => Some(WindowEvent::ScaleFactorChanged {
+                scale_factor,
+                new_inner_size: *new_inner_size,
+            }),
+            WinitWindowEvent::Focused(focused) => Some(WindowEvent::Focused(focused)),
+            WinitWindowEvent::KeyboardInput {
+                device_id,
+                input,
+                is_synthetic,
+            } => Some(WindowEvent::KeyboardInput {
+                device_id,
+                input,
+                is_synthetic,
+            }),
+            WinitWindowEvent::ReceivedCharacter(character) => {
+                Some(WindowEvent::ReceivedCharacter(character))
+            }
+            WinitWindowEvent::ModifiersChanged(modifiers) => {
+                Some(WindowEvent::ModifiersChanged(modifiers))
+            }
+            WinitWindowEvent::MouseInput {
+                device_id,
+                state,
+                button,
+                ..
+            } => Some(WindowEvent::MouseInput {
+                device_id,
+                state,
+                button,
+            }),
+            WinitWindowEvent::CursorMoved {
+                device_id,
+                position,
+                ..
+            } => Some(WindowEvent::CursorMoved {
+                device_id,
+                position,
+            }),
+            WinitWindowEvent::MouseWheel {
+                device_id,
+                delta,
+                phase,
+                ..
+            } => Some(WindowEvent::MouseWheel {
+                device_id,
+                delta,
+                phase,
+            }),
+            WinitWindowEvent::CursorEntered { device_id } => {
+                Some(WindowEvent::CursorEntered { device_id })
+            }
+            WinitWindowEvent::CursorLeft { device_id } => {
+                Some(WindowEvent::CursorLeft { device_id })
+            }
+            _ => None,
+        }
+    }
 }
 
 //EVENT LOOP DEFINITION
@@ -160,14 +366,13 @@ pub fn run_event_loop(proxy_tx: mpsc::Sender<EventLoopProxy>) -> ! {
             Event::WindowEvent {
                 window_id: _,
                 event,
-            } => match event {
-                winit::event::WindowEvent::CloseRequested => {
-                    if let Some(tx) = &ctx.window_event_sender {
-                        tx.send(WindowEvent::CloseRequested).unwrap();
+            } => {
+                if let Some(tx) = &ctx.window_event_sender {
+                    if let Some(window_event) = WindowEvent::from_winit(event) {
+                        tx.send(window_event).unwrap();
                     }
                 }
-                _ => (),
-            },
+            }
             _ => (),
         };
     });
@@ -184,7 +389,7 @@ fn handle_event(
             builder,
             response_tx,
         } => {
-            let window_result = builder.build(target);
+            let window_result = (*builder).build(target);
             response_tx
                 .send(match window_result {
                     Ok(window) => {
@@ -223,6 +428,86 @@ fn handle_event(
                 })
                 .unwrap();
         }
+        EventLoopEvent::SetCursorGrab { grab, response_tx } => {
+            response_tx
+                .send(with_window(ctx, |window| {
+                    window.set_cursor_grab(grab)?;
+                    Ok(())
+                }))
+                .unwrap();
+        }
+        EventLoopEvent::SetCursorVisible {
+            visible,
+            response_tx,
+        } => {
+            response_tx
+                .send(with_window(ctx, |window| {
+                    window.set_cursor_visible(visible);
+                    Ok(())
+                }))
+                .unwrap();
+        }
+        EventLoopEvent::SetFullscreen { mode, response_tx } => {
+            response_tx
+                .send(with_window(ctx, |window| {
+                    window.set_fullscreen(get_fullscreen(window, mode)?);
+                    Ok(())
+                }))
+                .unwrap();
+        }
+        EventLoopEvent::SetTitle { title, response_tx } => {
+            response_tx
+                .send(with_window(ctx, |window| {
+                    window.set_title(&title);
+                    Ok(())
+                }))
+                .unwrap();
+        }
+        EventLoopEvent::SetInnerSize { size, response_tx } => {
+            response_tx
+                .send(with_window(ctx, |window| {
+                    window.set_inner_size(size);
+                    Ok(())
+                }))
+                .unwrap();
+        }
         EventLoopEvent::Exit => *control_flow = ControlFlow::Exit,
     }
 }
+
+fn with_window<T, F: FnOnce(&Window) -> Result<T>>(ctx: &EventLoopContext, f: F) -> Result<T> {
+    match &ctx.main_window {
+        Some(window) => f(window),
+        None => Err(EventLoopError::WindowMissing),
+    }
+}
+
+fn get_fullscreen(window: &Window, mode: FullscreenMode) -> Result<Option<Fullscreen>> {
+    match mode {
+        FullscreenMode::Windowed => Ok(None),
+        FullscreenMode::Borderless => {
+            let monitor = window
+                .current_monitor()
+                .ok_or(EventLoopError::MonitorMissing)?;
+            Ok(Some(Fullscreen::Borderless(Some(monitor))))
+        }
+        FullscreenMode::Exclusive => {
+            let monitor = window
+                .current_monitor()
+                .ok_or(EventLoopError::MonitorMissing)?;
+            //Prefer the largest resolution, then the highest refresh rate and bit depth
+            let video_mode = monitor
+                .video_modes()
+                .max_by_key(|mode| {
+                    let size = mode.size();
+                    (
+                        size.width as u64 * size.height as u64,
+                        mode.refresh_rate(),
+                        mode.bit_depth(),
+                    )
+                })
+                .ok_or(EventLoopError::VideoModeMissing)?;
+            Ok(Some(Fullscreen::Exclusive(video_mode)))
+        }
+    }
+}
@@ -0,0 +1,546 @@
+//! Tracks the state of keyboard and mouse inputs and maps them to named actions
+//!
+//! The `InputSystem` is fed with the events received through the channels of the `EventLoopProxy`.
+//! Once per frame `begin_frame` should be called before feeding it the events of that frame,
+//! after which the state of every input and action can be queried.
+//!
+//! Actions are bound to physical inputs with a bindings file, which is loaded through the `ResourceSystem`.
+//! Every line of a bindings file binds one action to a comma-separated list of inputs:
+//! ```text
+//! # Lines starting with a '#' are comments
+//! move_forward = key:W, key:Up
+//! jump = key:Space
+//! attack = mouse:Left
+//! crouch = scancode:42
+//! ```
+//! Key names are the names of the `VirtualKeyCode` variants. Mouse buttons are `Left`, `Right`,
+//! `Middle` or a number for any other button.
+
+//Uses
+use crate::event_loop::{DeviceEvent, WindowEvent};
+use crate::res::{ResourceError, ResourceLoadType, ResourceSystem};
+use std::collections::{HashMap, HashSet};
+use thiserror::Error;
+use winit::event::{ElementState, MouseButton, MouseScrollDelta, VirtualKeyCode};
+
+#[derive(Error, Debug)]
+pub enum InputError {
+    #[error("Line {line} of the bindings file is malformed: {reason}")]
+    MalformedBinding { line: usize, reason: String },
+    #[error("The action \"{0}\" is bound more than once")]
+    DuplicateAction(String),
+    #[error("The bindings resource is not a text resource")]
+    InvalidBindingsResource,
+    #[error(transparent)]
+    ResourceError(#[from] ResourceError),
+}
+
+type Result<T> = std::result::Result<T, InputError>;
+
+/// A physical input that can be pressed and released
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Input {
+    /// A key identified by its meaning in the current keyboard layout
+    Key(VirtualKeyCode),
+    /// A key identified by its physical location on the keyboard
+    ScanCode(u32),
+    Mouse(MouseButton),
+}
+
+impl Input {
+    /// Parses an input written as `key:<name>`, `scancode:<number>` or `mouse:<button>`
+    pub fn parse(s: &str) -> Option<Input> {
+        let (kind, name) = s.split_once(':')?;
+        match kind.trim() {
+            "key" => parse_key_code(name.trim()).map(Input::Key),
+            "scancode" => name.trim().parse().ok().map(Input::ScanCode),
+            "mouse" => parse_mouse_button(name.trim()).map(Input::Mouse),
+            _ => None,
+        }
+    }
+}
+
+/// Maps action names to the inputs that trigger them
+#[derive(Clone, Debug, Default)]
+pub struct Bindings {
+    actions: HashMap<String, Vec<Input>>,
+}
+
+impl Bindings {
+    pub fn new() -> Bindings {
+        Bindings {
+            actions: HashMap::new(),
+        }
+    }
+
+    /// Loads a bindings file, see the module documentation for the format
+    pub fn load(res: &mut ResourceSystem, resource_id: &str) -> Result<Bindings> {
+        let resource = res.get_loaded_resource(resource_id, ResourceLoadType::PlainText)?;
+        let text = resource
+            .data
+            .as_text()
+            .ok_or(InputError::InvalidBindingsResource)?;
+        Bindings::parse(text)
+    }
+
+    pub fn parse(text: &str) -> Result<Bindings> {
+        let mut bindings = Bindings::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let malformed = |reason: &str| InputError::MalformedBinding {
+                line: i + 1,
+                reason: reason.to_owned(),
+            };
+            let (action, inputs) = line
+                .split_once('=')
+                .ok_or_else(|| malformed("expected \"<action> = <inputs>\""))?;
+            let action = action.trim();
+            if action.is_empty() {
+                return Err(malformed("the action name is empty"));
+            }
+            let inputs = inputs
+                .split(',')
+                .map(|input| {
+                    Input::parse(input.trim())
+                        .ok_or_else(|| malformed(&format!("unknown input \"{}\"", input.trim())))
+                })
+                .collect::<Result<Vec<Input>>>()?;
+
+            if bindings.actions.contains_key(action) {
+                return Err(InputError::DuplicateAction(action.to_owned()));
+            }
+            bindings.actions.insert(action.to_owned(), inputs);
+        }
+
+        Ok(bindings)
+    }
+
+    pub fn bind(&mut self, action: &str, input: Input) {
+        self.actions
+            .entry(action.to_owned())
+            .or_default()
+            .push(input);
+    }
+
+    pub fn unbind_action(&mut self, action: &str) {
+        self.actions.remove(action);
+    }
+
+    pub fn get_inputs(&self, action: &str) -> &[Input] {
+        self.actions.get(action).map(Vec::as_slice).unwrap_or(&[])
+    }
+}
+
+/// Keeps track of which inputs are held down and which changed during the current frame
+pub struct InputSystem {
+    bindings: Bindings,
+
+    pressed: HashSet<Input>,
+    just_pressed: HashSet<Input>,
+    just_released: HashSet<Input>,
+
+    focused: bool,
+    cursor_position: Option<(f64, f64)>,
+    mouse_delta: (f64, f64),
+    scroll_delta: (f32, f32),
+    typed_text: String,
+}
+
+impl InputSystem {
+    pub fn new(bindings: Bindings) -> InputSystem {
+        InputSystem {
+            bindings,
+            pressed: HashSet::new(),
+            just_pressed: HashSet::new(),
+            just_released: HashSet::new(),
+            focused: true,
+            cursor_position: None,
+            mouse_delta: (0.0, 0.0),
+            scroll_delta: (0.0, 0.0),
+            typed_text: String::new(),
+        }
+    }
+
+    pub fn bindings(&self) -> &Bindings {
+        &self.bindings
+    }
+
+    pub fn set_bindings(&mut self, bindings: Bindings) {
+        self.bindings = bindings;
+    }
+
+    /// Clears everything that only lasts for a single frame, call this before handling the events of a frame
+    pub fn begin_frame(&mut self) {
+        self.just_pressed.clear();
+        self.just_released.clear();
+        self.mouse_delta = (0.0, 0.0);
+        self.scroll_delta = (0.0, 0.0);
+        self.typed_text.clear();
+    }
+
+    pub fn handle_window_event(&mut self, event: &WindowEvent) {
+        match event {
+            WindowEvent::KeyboardInput { input, .. } => {
+                let mut inputs = vec![Input::ScanCode(input.scancode)];
+                if let Some(key_code) = input.virtual_keycode {
+                    inputs.push(Input::Key(key_code));
+                }
+                for i in inputs {
+                    self.set_input_state(i, input.state);
+                }
+            }
+            WindowEvent::MouseInput { state, button, .. } => {
+                self.set_input_state(Input::Mouse(*button), *state);
+            }
+            WindowEvent::CursorMoved { position, .. } => {
+                self.cursor_position = Some((position.x, position.y));
+            }
+            WindowEvent::CursorLeft { .. } => self.cursor_position = None,
+            WindowEvent::MouseWheel { delta, .. } => {
+                let (x, y) = match delta {
+                    MouseScrollDelta::LineDelta(x, y) => (*x, *y),
+                    MouseScrollDelta::PixelDelta(position) => {
+                        (position.x as f32, position.y as f32)
+                    }
+                };
+                self.scroll_delta.0 += x;
+                self.scroll_delta.1 += y;
+            }
+            WindowEvent::ReceivedCharacter(character) => self.typed_text.push(*character),
+            WindowEvent::Focused(focused) => {
+                self.focused = *focused;
+                //Release events are not received while unfocused, so nothing can be considered held anymore
+                if !focused {
+                    let released: Vec<Input> = self.pressed.drain().collect();
+                    self.just_released.extend(released);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    pub fn handle_device_event(&mut self, event: &DeviceEvent) {
+        if let winit::event::DeviceEvent::MouseMotion { delta } = event.event {
+            if self.focused {
+                self.mouse_delta.0 += delta.0;
+                self.mouse_delta.1 += delta.1;
+            }
+        }
+    }
+
+    fn set_input_state(&mut self, input: Input, state: ElementState) {
+        match state {
+            ElementState::Pressed => {
+                //Key repeat sends multiple presses without a release
+                if self.pressed.insert(input) {
+                    self.just_pressed.insert(input);
+                }
+            }
+            ElementState::Released => {
+                if self.pressed.remove(&input) {
+                    self.just_released.insert(input);
+                }
+            }
+        }
+    }
+
+    pub fn is_pressed(&self, input: Input) -> bool {
+        self.pressed.contains(&input)
+    }
+
+    pub fn is_just_pressed(&self, input: Input) -> bool {
+        self.just_pressed.contains(&input)
+    }
+
+    pub fn is_just_released(&self, input: Input) -> bool {
+        self.just_released.contains(&input)
+    }
+
+    /// Returns true while any input bound to the action is held down
+    pub fn is_action_pressed(&self, action: &str) -> bool {
+        self.bindings
+            .get_inputs(action)
+            .iter()
+            .any(|input| self.is_pressed(*input))
+    }
+
+    /// Returns true if the action went from released to pressed during this frame
+    pub fn is_action_just_pressed(&self, action: &str) -> bool {
+        let inputs = self.bindings.get_inputs(action);
+        inputs.iter().any(|input| self.is_just_pressed(*input))
+            && !inputs
+                .iter()
+                .any(|input| self.is_pressed(*input) && !self.is_just_pressed(*input))
+    }
+
+    /// Returns true if the action went from pressed to released during this frame
+    pub fn is_action_just_released(&self, action: &str) -> bool {
+        let inputs = self.bindings.get_inputs(action);
+        inputs.iter().any(|input| self.is_just_released(*input))
+            && !inputs.iter().any(|input| self.is_pressed(*input))
+    }
+
+    pub fn is_focused(&self) -> bool {
+        self.focused
+    }
+
+    /// Position of the cursor relative to the top-left corner of the window, `None` if it is outside of the window
+    pub fn cursor_position(&self) -> Option<(f64, f64)> {
+        self.cursor_position
+    }
+
+    /// Raw mouse movement during this frame, unaffected by cursor acceleration or window borders
+    pub fn mouse_delta(&self) -> (f64, f64) {
+        self.mouse_delta
+    }
+
+    /// Scrolling during this frame, in lines for mouse wheels and in pixels for touchpads
+    pub fn scroll_delta(&self) -> (f32, f32) {
+        self.scroll_delta
+    }
+
+    /// Text typed during this frame
+    pub fn typed_text(&self) -> &str {
+        &self.typed_text
+    }
+}
+
+fn parse_mouse_button(name: &str) -> Option<MouseButton> {
+    match name {
+        "Left" => Some(MouseButton::Left),
+        "Right" => Some(MouseButton::Right),
+        "Middle" => Some(MouseButton::Middle),
+        other => other.parse().ok().map(MouseButton::Other),
+    }
+}
+
+macro_rules! key_codes_by_name {
+    ($($key:ident),* $(,)?) => {
+        fn parse_key_code(name: &str) -> Option<VirtualKeyCode> {
+            match name {
+                $(stringify!($key) => Some(VirtualKeyCode::$key),)*
+                _ => None,
+            }
+        }
+    };
+}
+
+key_codes_by_name! {
+    Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9, Key0, A, B, C, D, E, F, G, H, I, J, K, L,
+    M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z, Escape, F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11,
+    F12, F13, F14, F15, F16, F17, F18, F19, F20, F21, F22, F23, F24, Snapshot, Scroll, Pause,
+    Insert, Home, Delete, End, PageDown, PageUp, Left, Up, Right, Down, Back, Return, Space,
+    Compose, Caret, Numlock, Numpad0, Numpad1, Numpad2, Numpad3, Numpad4, Numpad5, Numpad6,
+    Numpad7, Numpad8, Numpad9, NumpadAdd, NumpadDivide, NumpadDecimal, NumpadComma, NumpadEnter,
+    NumpadEquals, NumpadMultiply, NumpadSubtract, AbntC1, AbntC2, Apostrophe, Apps, Asterisk, At,
+    Ax, Backslash, Calculator, Capital, Colon, Comma, Convert, Equals, Grave, Kana, Kanji, LAlt,
+    LBracket, LControl, LShift, LWin, Mail, MediaSelect, MediaStop, Minus, Mute, MyComputer,
+    NavigateForward, NavigateBackward, NextTrack, NoConvert, OEM102, Period, PlayPause, Plus,
+    Power, PrevTrack, RAlt, RBracket, RControl, RShift, RWin, Semicolon, Slash, Sleep, Stop, Sysrq,
+    Tab, Underline, Unlabeled, VolumeDown, VolumeUp, Wake, WebBack, WebFavorites, WebForward,
+    WebHome, WebRefresh, WebSearch, WebStop, Yen, Copy, Paste, Cut
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use winit::event::{DeviceId, KeyboardInput};
+
+    fn device_id() -> DeviceId {
+        //Safe to use as long as it is never passed back to winit
+        unsafe { DeviceId::dummy() }
+    }
+
+    #[allow(deprecated)]
+    fn key_event(
+        scancode: u32,
+        key_code: Option<VirtualKeyCode>,
+        state: ElementState,
+    ) -> WindowEvent {
+        WindowEvent::KeyboardInput {
+            device_id: device_id(),
+            input: KeyboardInput {
+                scancode,
+                state,
+                virtual_keycode: key_code,
+                modifiers: Default::default(),
+            },
+            is_synthetic: false,
+        }
+    }
+
+    fn mouse_event(button: MouseButton, state: ElementState) -> WindowEvent {
+        WindowEvent::MouseInput {
+            device_id: device_id(),
+            state,
+            button,
+        }
+    }
+
+    fn motion_event(dx: f64, dy: f64) -> DeviceEvent {
+        DeviceEvent {
+            device_id: device_id(),
+            event: winit::event::DeviceEvent::MouseMotion { delta: (dx, dy) },
+        }
+    }
+
+    fn jump_bindings() -> Bindings {
+        Bindings::parse("jump = key:Space, mouse:Right").unwrap()
+    }
+
+    #[test]
+    fn comments_and_whitespace_are_ignored() {
+        let text = "# movement\n\n   \n  move_forward =key:W ,  key:Up  \n\t# attack = mouse:Left\ncrouch= scancode:42\n";
+        let bindings = Bindings::parse(text).unwrap();
+        assert_eq!(
+            bindings.get_inputs("move_forward"),
+            &[
+                Input::Key(VirtualKeyCode::W),
+                Input::Key(VirtualKeyCode::Up)
+            ]
+        );
+        assert_eq!(bindings.get_inputs("crouch"), &[Input::ScanCode(42)]);
+        assert!(bindings.get_inputs("attack").is_empty());
+    }
+
+    #[test]
+    fn unknown_inputs_are_rejected_with_their_line() {
+        let result = Bindings::parse("jump = key:Space\n\nfly = key:Jetpack");
+        assert!(matches!(
+            result,
+            Err(InputError::MalformedBinding { line: 3, .. })
+        ));
+
+        for text in [
+            "jump = mouse:Sideways",
+            "jump = keyboard:A",
+            "jump = scancode:-1",
+            "jump key:A",
+            " = key:A",
+        ] {
+            assert!(
+                matches!(
+                    Bindings::parse(text),
+                    Err(InputError::MalformedBinding { line: 1, .. })
+                ),
+                "{:?} should not parse",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn duplicate_actions_are_rejected() {
+        let result = Bindings::parse("jump = key:Space\njump = mouse:Right");
+        assert!(matches!(result, Err(InputError::DuplicateAction(action)) if action == "jump"));
+    }
+
+    #[test]
+    fn presses_last_until_released_and_edges_last_one_frame() {
+        let mut input_system = InputSystem::new(jump_bindings());
+        let space = Input::Key(VirtualKeyCode::Space);
+
+        input_system.begin_frame();
+        input_system.handle_window_event(&key_event(
+            57,
+            Some(VirtualKeyCode::Space),
+            ElementState::Pressed,
+        ));
+        assert!(input_system.is_pressed(space));
+        assert!(input_system.is_pressed(Input::ScanCode(57)));
+        assert!(input_system.is_just_pressed(space));
+        assert!(input_system.is_action_just_pressed("jump"));
+
+        //Key repeat while held does not count as a new press
+        input_system.begin_frame();
+        input_system.handle_window_event(&key_event(
+            57,
+            Some(VirtualKeyCode::Space),
+            ElementState::Pressed,
+        ));
+        assert!(input_system.is_action_pressed("jump"));
+        assert!(!input_system.is_just_pressed(space));
+        assert!(!input_system.is_action_just_pressed("jump"));
+
+        input_system.begin_frame();
+        input_system.handle_window_event(&key_event(
+            57,
+            Some(VirtualKeyCode::Space),
+            ElementState::Released,
+        ));
+        assert!(!input_system.is_pressed(space));
+        assert!(input_system.is_just_released(space));
+        assert!(input_system.is_action_just_released("jump"));
+
+        input_system.begin_frame();
+        assert!(!input_system.is_just_released(space));
+        assert!(!input_system.is_action_just_released("jump"));
+    }
+
+    #[test]
+    fn actions_only_change_with_their_first_and_last_input() {
+        let mut input_system = InputSystem::new(jump_bindings());
+
+        input_system.begin_frame();
+        input_system.handle_window_event(&key_event(
+            57,
+            Some(VirtualKeyCode::Space),
+            ElementState::Pressed,
+        ));
+
+        //A second input of an action that is already held is not a new press of the action
+        input_system.begin_frame();
+        input_system.handle_window_event(&mouse_event(MouseButton::Right, ElementState::Pressed));
+        assert!(input_system.is_just_pressed(Input::Mouse(MouseButton::Right)));
+        assert!(!input_system.is_action_just_pressed("jump"));
+
+        //The action stays pressed until its last input is released
+        input_system.begin_frame();
+        input_system.handle_window_event(&key_event(
+            57,
+            Some(VirtualKeyCode::Space),
+            ElementState::Released,
+        ));
+        assert!(input_system.is_action_pressed("jump"));
+        assert!(!input_system.is_action_just_released("jump"));
+
+        input_system.begin_frame();
+        input_system.handle_window_event(&mouse_event(MouseButton::Right, ElementState::Released));
+        assert!(!input_system.is_action_pressed("jump"));
+        assert!(input_system.is_action_just_released("jump"));
+    }
+
+    #[test]
+    fn losing_focus_releases_everything() {
+        let mut input_system = InputSystem::new(jump_bindings());
+
+        input_system.begin_frame();
+        input_system.handle_window_event(&mouse_event(MouseButton::Right, ElementState::Pressed));
+        input_system.handle_window_event(&WindowEvent::Focused(false));
+        assert!(!input_system.is_focused());
+        assert!(!input_system.is_action_pressed("jump"));
+        assert!(input_system.is_action_just_released("jump"));
+
+        //Mouse motion is ignored while unfocused
+        input_system.handle_device_event(&motion_event(3.0, 4.0));
+        assert_eq!(input_system.mouse_delta(), (0.0, 0.0));
+    }
+
+    #[test]
+    fn mouse_motion_accumulates_within_a_frame() {
+        let mut input_system = InputSystem::new(Bindings::new());
+
+        input_system.begin_frame();
+        input_system.handle_device_event(&motion_event(1.5, -2.0));
+        input_system.handle_device_event(&motion_event(0.5, 1.0));
+        assert_eq!(input_system.mouse_delta(), (2.0, -1.0));
+
+        input_system.begin_frame();
+        assert_eq!(input_system.mouse_delta(), (0.0, 0.0));
+    }
+}
@@ -1,4 +1,5 @@
 pub mod event_loop;
+pub mod input;
 pub mod render;
 pub mod res;
 pub mod world;
@@ -1,8 +1,9 @@
 //Uses
 use crate::event_loop::EventLoopProxy;
 use crate::res::ResourceSystem;
+use crate::world::light::LightSystem;
 use crate::world::voxel::VoxelSystem;
-use cgmath::{Euler, Matrix4, Vector3, One, Zero};
+use cgmath::{Euler, Matrix, Matrix4, One, PerspectiveFov, Rad, Vector3};
 use pollster::block_on;
 use surface::RenderSurface;
 use voxel::VoxelRenderSystem;
@@ -12,11 +13,42 @@ use wgpu;
 mod surface;
 pub mod voxel;
 
+//cgmath produces OpenGL clip space, where depth ranges from -1 to 1, but wgpu expects 0 to 1
+#[rustfmt::skip]
+const OPENGL_TO_WGPU_MATRIX: Matrix4<f32> = Matrix4::new(
+    1.0, 0.0, 0.0, 0.0,
+    0.0, 1.0, 0.0, 0.0,
+    0.0, 0.0, 0.5, 0.0,
+    0.0, 0.0, 0.5, 1.0,
+);
+
+/// Projects camera space into OpenGL-style clip space
+#[derive(Clone, Copy)]
+pub enum Projection {
+    /// A perspective projection, `RenderSystem::resize` keeps its aspect ratio matched to the surface
+    Perspective(PerspectiveFov<f32>),
+    /// Any other projection, used as-is
+    Matrix(Matrix4<f32>),
+}
+
+impl Projection {
+    pub fn to_matrix(&self) -> Matrix4<f32> {
+        match self {
+            Projection::Perspective(perspective) => Matrix4::from(*perspective),
+            Projection::Matrix(matrix) => *matrix,
+        }
+    }
+}
+
+/// Cameras used to have a public `projection_matrix` field. Code that set it should create the camera
+/// with `Camera::new` or call `set_projection_matrix` instead, the matrix is then used as-is.
+/// Only cameras with a `Projection::Perspective` get their aspect ratio updated on resize.
 #[derive(Clone, Copy)]
 pub struct Camera {
     pub position: Vector3<f32>,
+    /// Rotation of the camera in radians
     pub orientation: Euler<f32>,
-    pub projection_matrix: Matrix4<f32>,
+    pub projection: Projection,
 }
 
 impl Camera {
@@ -24,9 +56,55 @@ impl Camera {
         Camera {
             position: Vector3::new(0.0, 0.0, 0.0),
             orientation: Euler::new(0.0, 0.0, 0.0),
-            projection_matrix: Matrix4::one(),
+            projection: Projection::Matrix(Matrix4::one()),
+        }
+    }
+
+    /// Creates a camera with a fixed projection matrix
+    pub fn new(
+        position: Vector3<f32>,
+        orientation: Euler<f32>,
+        projection_matrix: Matrix4<f32>,
+    ) -> Camera {
+        Camera {
+            position,
+            orientation,
+            projection: Projection::Matrix(projection_matrix),
         }
     }
+
+    pub fn projection_matrix(&self) -> Matrix4<f32> {
+        self.projection.to_matrix()
+    }
+
+    /// Replaces the projection with a fixed projection matrix
+    pub fn set_projection_matrix(&mut self, projection_matrix: Matrix4<f32>) {
+        self.projection = Projection::Matrix(projection_matrix);
+    }
+
+    /// Updates the aspect ratio of a perspective projection, other projections are left untouched
+    pub fn set_aspect_ratio(&mut self, aspect: f32) {
+        if let Projection::Perspective(perspective) = &mut self.projection {
+            perspective.aspect = aspect;
+        }
+    }
+
+    /// Transforms world space into camera space
+    pub fn view_matrix(&self) -> Matrix4<f32> {
+        let orientation = Euler::new(
+            Rad(self.orientation.x),
+            Rad(self.orientation.y),
+            Rad(self.orientation.z),
+        );
+        //The inverse of a rotation is its transpose
+        let inverse_rotation = Matrix4::from(orientation).transpose();
+        inverse_rotation * Matrix4::from_translation(-self.position)
+    }
+
+    /// Transforms world space into wgpu clip space
+    pub fn view_projection_matrix(&self) -> Matrix4<f32> {
+        OPENGL_TO_WGPU_MATRIX * self.projection.to_matrix() * self.view_matrix()
+    }
 }
 
 pub struct RenderSystem {
@@ -78,6 +156,7 @@ impl RenderSystem {
             &device,
             voxel::PipelineInitParams {
                 output_texture_format: surface_format,
+                depth_texture_format: surface::DEPTH_FORMAT,
             },
         );
 
@@ -89,8 +168,56 @@ impl RenderSystem {
         }
     }
 
-    pub fn update(&mut self, voxel_system: &VoxelSystem) {
-        self.voxel_system.update(voxel_system, self.device.as_ref().unwrap(), &self.queue);
+    /// Rebuilds meshes that are out of date, call after the light system has been updated
+    pub fn update(&mut self, voxel_system: &VoxelSystem, light_system: &LightSystem) {
+        self.voxel_system
+            .update(voxel_system, light_system, self.device.as_ref().unwrap());
+    }
+
+    pub fn voxel_mesher(&self) -> voxel::Mesher {
+        self.voxel_system.mesher()
+    }
+
+    pub fn set_voxel_mesher(&mut self, mesher: voxel::Mesher) {
+        self.voxel_system.set_mesher(mesher);
+    }
+
+    pub fn sun_direction(&self) -> Vector3<f32> {
+        self.voxel_system.sun_direction()
+    }
+
+    /// Sets the direction towards the sun, it does not need to be normalized
+    pub fn set_sun_direction(&mut self, direction: Vector3<f32>) {
+        self.voxel_system.set_sun_direction(direction);
+    }
+
+    pub fn daylight(&self) -> f32 {
+        self.voxel_system.daylight()
+    }
+
+    /// Sets the strength of sky light, from 0 to 1
+    pub fn set_daylight(&mut self, daylight: f32) {
+        self.voxel_system.set_daylight(daylight);
+    }
+
+    /// Resizes the render surface, should be called whenever the window is resized.
+    /// The aspect ratio of the camera is matched to the new size if it uses a perspective projection.
+    pub fn resize(&mut self, size_x: u32, size_y: u32, camera: &mut Camera) {
+        //A surface cannot be configured with a size of zero, which happens when the window is minimized
+        if size_x == 0 || size_y == 0 {
+            return;
+        }
+        self.surface
+            .resize(self.device.as_ref().unwrap(), size_x, size_y);
+        camera.set_aspect_ratio(self.surface.aspect_ratio());
+    }
+
+    pub fn surface_size(&self) -> (u32, u32) {
+        self.surface.size()
+    }
+
+    pub fn aspect_ratio(&self) -> f32 {
+        self.surface.aspect_ratio()
     }
 
     pub fn render(&self, camera: Camera) {
@@ -99,9 +226,13 @@ impl RenderSystem {
             .texture
             .create_view(&wgpu::TextureViewDescriptor::default());
 
-        let voxel_commands =
-            self.voxel_system
-                .encode_commands(self.device.as_ref().unwrap(), texture_view, &camera);
+        let voxel_commands = self.voxel_system.encode_commands(
+            self.device.as_ref().unwrap(),
+            &self.queue,
+            texture_view,
+            self.surface.get_depth_view(),
+            &camera,
+        );
         self.queue.submit(std::iter::once(voxel_commands));
         surface_texture.present();
     }
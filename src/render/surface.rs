@@ -1,11 +1,17 @@
 //! Handles the top-level surface
 
+pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+
 pub struct RenderSurface {
     //Surface constants
     surface: wgpu::Surface,
     surface_format: wgpu::TextureFormat,
     size_x: u32,
     size_y: u32,
+
+    //Depth buffer matching the surface size
+    depth_texture: wgpu::Texture,
+    depth_view: wgpu::TextureView,
 }
 
 impl RenderSurface {
@@ -16,11 +22,14 @@ impl RenderSurface {
         size_y: u32,
         format: wgpu::TextureFormat,
     ) -> RenderSurface {
+        let (depth_texture, depth_view) = create_depth_texture(device, size_x, size_y);
         let ctx = RenderSurface {
             surface,
             surface_format: format,
             size_x,
             size_y,
+            depth_texture,
+            depth_view,
         };
 
         ctx.configure_surface(device);
@@ -32,6 +41,10 @@ impl RenderSurface {
         self.size_x = new_size_x;
         self.size_y = new_size_y;
         self.configure_surface(device);
+
+        let (depth_texture, depth_view) = create_depth_texture(device, new_size_x, new_size_y);
+        std::mem::replace(&mut self.depth_texture, depth_texture).destroy();
+        self.depth_view = depth_view;
     }
 
     fn configure_surface(&self, device: &wgpu::Device) {
@@ -45,7 +58,41 @@ impl RenderSurface {
         self.surface.configure(device, &surface_config);
     }
 
+    pub fn size(&self) -> (u32, u32) {
+        (self.size_x, self.size_y)
+    }
+
+    pub fn aspect_ratio(&self) -> f32 {
+        self.size_x as f32 / self.size_y as f32
+    }
+
     pub fn get_surface_texture(&self) -> wgpu::SurfaceTexture {
         self.surface.get_current_texture().unwrap()
     }
+
+    pub fn get_depth_view(&self) -> &wgpu::TextureView {
+        &self.depth_view
+    }
+}
+
+fn create_depth_texture(
+    device: &wgpu::Device,
+    size_x: u32,
+    size_y: u32,
+) -> (wgpu::Texture, wgpu::TextureView) {
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("Depth texture"),
+        size: wgpu::Extent3d {
+            width: size_x,
+            height: size_y,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: DEPTH_FORMAT,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+    });
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    (texture, view)
 }
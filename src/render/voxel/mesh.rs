@@ -1,7 +1,8 @@
 //Uses
 use crate::world::chunk::size::*;
+use crate::world::light::{LightArray, LightLevel, MAX_LIGHT};
 use crate::world::voxel::AttributeRegistry;
-use crate::world::voxel::VoxelArray;
+use crate::world::voxel::{Voxel, VoxelArray};
 use bytemuck::{Pod, Zeroable};
 use wgpu::vertex_attr_array;
 use wgpu;
@@ -11,11 +12,24 @@ use wgpu;
 pub struct Vertex {
     position: [f32; 3],
     color: [f32; 3],
+    /// Unit normal of the face the vertex belongs to
+    normal: [f32; 3],
+    /// Sky and block light in front of the face, scaled to 0 to 1
+    light: [f32; 2],
+    /// Ambient occlusion of the corner, from 0 when fully occluded to 1
+    ao: f32,
 }
 
 impl Vertex {
-    pub fn vertex_attribute_array(location_pos: u32, location_col: u32) -> [wgpu::VertexAttribute; 2] {
-        vertex_attr_array![location_pos => Float32x3, location_col => Float32x3]
+    /// Position, color, normal, light and occlusion, bound to consecutive locations starting at `first_location`
+    pub fn vertex_attribute_array(first_location: u32) -> [wgpu::VertexAttribute; 5] {
+        vertex_attr_array![
+            first_location => Float32x3,
+            first_location + 1 => Float32x3,
+            first_location + 2 => Float32x3,
+            first_location + 3 => Float32x2,
+            first_location + 4 => Float32
+        ]
     }
 }
 
@@ -36,82 +50,357 @@ pub enum AppearanceAttribute {
     None,
 }
 
-//The origin of this model is on the negative corner
-const CUBE_VERTICES: [[f32; 3]; 36] = [
-    //Bottom plane
-    [0.0, 0.0, 0.0],
-    [1.0, 0.0, 0.0],
-    [1.0, 0.0, 1.0],
-    
-    [0.0, 0.0, 0.0],
-    [1.0, 0.0, 1.0],
-    [0.0, 0.0, 1.0],
-
-    //Top plane
-    [0.0, 1.0, 0.0],
-    [0.0, 1.0, 1.0],
-    [1.0, 1.0, 1.0],
-
-    [0.0, 1.0, 0.0],
-    [1.0, 1.0, 1.0],
-    [1.0, 1.0, 0.0],
-
-    //Front plane
-    [0.0, 0.0, 0.0],
-    [1.0, 0.0, 0.0],
-    [1.0, 1.0, 0.0],
-
-    [0.0, 0.0, 0.0],
-    [1.0, 1.0, 0.0],
-    [0.0, 1.0, 0.0],
-
-    //Back plane
-    [0.0, 0.0, 1.0],
-    [1.0, 1.0, 0.0],
-    [1.0, 0.0, 0.0],
-
-    [0.0, 0.0, 1.0],
-    [0.0, 1.0, 1.0],
-    [1.0, 1.0, 1.0],
-
-    //Left plane
-    [0.0, 0.0, 1.0],
-    [0.0, 0.0, 0.0],
-    [0.0, 1.0, 0.0],
-
-    [0.0, 0.0, 1.0],
-    [0.0, 1.0, 0.0],
-    [0.0, 1.0, 1.0],
-
-    //Right plane
-    [1.0, 0.0, 0.0],
-    [1.0, 0.0, 1.0],
-    [1.0, 1.0, 1.0],
-
-    [1.0, 0.0, 0.0],
-    [1.0, 1.0, 1.0],
-    [1.0, 1.0, 0.0]
+/// Light used for faces whose light is unknown, so meshes without light data are fully lit by the sky
+const UNKNOWN_LIGHT: LightLevel = LightLevel {
+    sky: MAX_LIGHT,
+    block: 0,
+};
+
+/// Index into the neighborhood arrays of the chunk at the given offset from the center chunk, each from -1 to 1
+const fn neighbor_index(dx: i32, dy: i32, dz: i32) -> usize {
+    ((dx + 1) + (dy + 1) * 3 + (dz + 1) * 9) as usize
+}
+
+const CENTER_INDEX: usize = neighbor_index(0, 0, 0);
+
+/// Offsets of every chunk in a neighborhood, in the order of `neighbor_index`
+fn neighbor_offsets() -> impl Iterator<Item = (i32, i32, i32)> {
+    (-1..=1).flat_map(|dz| (-1..=1).flat_map(move |dy| (-1..=1).map(move |dx| (dx, dy, dz))))
+}
+
+/// A chunk together with the 26 chunks around it, used to look at voxels across chunk borders and corners
+pub(super) struct ChunkNeighborhood<'a> {
+    center: &'a VoxelArray,
+    /// Indexed by `neighbor_index`, the entry of the center chunk is never used
+    neighbors: [Option<&'a VoxelArray>; 27],
+    /// Indexed by `neighbor_index`, including the center chunk
+    lights: [Option<&'a LightArray>; 27],
+}
+
+impl<'a> ChunkNeighborhood<'a> {
+    pub fn new(center: &'a VoxelArray) -> ChunkNeighborhood<'a> {
+        ChunkNeighborhood {
+            center,
+            neighbors: [None; 27],
+            lights: [None; 27],
+        }
+    }
+
+    /// Builds a neighborhood by looking up the neighbors of the chunk at the given chunk coordinates
+    pub fn gather<F>(
+        center: &'a VoxelArray,
+        x: i32,
+        y: i32,
+        z: i32,
+        mut get_chunk: F,
+    ) -> ChunkNeighborhood<'a>
+    where
+        F: FnMut(i32, i32, i32) -> Option<&'a VoxelArray>,
+    {
+        let mut neighborhood = ChunkNeighborhood::new(center);
+        for (neighbor, (dx, dy, dz)) in neighborhood.neighbors.iter_mut().zip(neighbor_offsets()) {
+            if (dx, dy, dz) != (0, 0, 0) {
+                *neighbor = get_chunk(x + dx, y + dy, z + dz);
+            }
+        }
+        neighborhood
+    }
+
+    /// Looks up the light arrays of the center chunk and its neighbors at the given chunk coordinates
+    pub fn gather_light<F>(&mut self, x: i32, y: i32, z: i32, mut get_light: F)
+    where
+        F: FnMut(i32, i32, i32) -> Option<&'a LightArray>,
+    {
+        for (light, (dx, dy, dz)) in self.lights.iter_mut().zip(neighbor_offsets()) {
+            *light = get_light(x + dx, y + dy, z + dz);
+        }
+    }
+
+    /// Finds the chunk a position relative to the origin of the center chunk lies in.
+    /// Returns the `neighbor_index` of the chunk and the position within it.
+    /// The coordinates may be at most one voxel outside of the chunk on every axis.
+    fn locate(x: i32, y: i32, z: i32) -> (usize, usize, usize, usize) {
+        let split = |c: i32, size: usize| {
+            let size = size as i32;
+            if c < 0 {
+                (-1, c + size)
+            } else if c >= size {
+                (1, c - size)
+            } else {
+                (0, c)
+            }
+        };
+        let ((dx, x), (dy, y), (dz, z)) = (
+            split(x, CHUNK_SIZE_X),
+            split(y, CHUNK_SIZE_Y),
+            split(z, CHUNK_SIZE_Z),
+        );
+        (
+            neighbor_index(dx, dy, dz),
+            x as usize,
+            y as usize,
+            z as usize,
+        )
+    }
+
+    /// Looks up a voxel relative to the origin of the center chunk.
+    /// Returns `None` if the voxel lies in a neighbor that is not loaded.
+    fn get_voxel(&self, x: i32, y: i32, z: i32) -> Option<&'a Voxel> {
+        let (index, x, y, z) = Self::locate(x, y, z);
+        let array = match index {
+            CENTER_INDEX => self.center,
+            _ => self.neighbors[index]?,
+        };
+        Some(array.get_voxel_at_position(x, y, z))
+    }
+
+    /// Looks up the light of a voxel relative to the origin of the center chunk.
+    /// Returns `UNKNOWN_LIGHT` if there is no light data for the chunk the voxel lies in.
+    fn get_light(&self, x: i32, y: i32, z: i32) -> LightLevel {
+        let (index, x, y, z) = Self::locate(x, y, z);
+        self.lights[index].map_or(UNKNOWN_LIGHT, |array| array.get_light_at_position(x, y, z))
+    }
+}
+
+struct Face {
+    /// Offset to the voxel this face is touching
+    normal: (i32, i32, i32),
+    /// Index of the axis the normal lies on
+    axis: usize,
+    /// Corners of the face relative to the negative corner of the voxel, counter-clockwise when seen from outside
+    corners: [[f32; 3]; 4],
+}
+
+//Ordered East, West, Up, Down, North, South
+const FACES: [Face; 6] = [
+    Face {
+        normal: (1, 0, 0),
+        axis: 0,
+        corners: [
+            [1.0, 0.0, 0.0],
+            [1.0, 1.0, 0.0],
+            [1.0, 1.0, 1.0],
+            [1.0, 0.0, 1.0],
+        ],
+    },
+    Face {
+        normal: (-1, 0, 0),
+        axis: 0,
+        corners: [
+            [0.0, 0.0, 0.0],
+            [0.0, 0.0, 1.0],
+            [0.0, 1.0, 1.0],
+            [0.0, 1.0, 0.0],
+        ],
+    },
+    Face {
+        normal: (0, 1, 0),
+        axis: 1,
+        corners: [
+            [0.0, 1.0, 0.0],
+            [0.0, 1.0, 1.0],
+            [1.0, 1.0, 1.0],
+            [1.0, 1.0, 0.0],
+        ],
+    },
+    Face {
+        normal: (0, -1, 0),
+        axis: 1,
+        corners: [
+            [0.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [1.0, 0.0, 1.0],
+            [0.0, 0.0, 1.0],
+        ],
+    },
+    Face {
+        normal: (0, 0, 1),
+        axis: 2,
+        corners: [
+            [0.0, 0.0, 1.0],
+            [1.0, 0.0, 1.0],
+            [1.0, 1.0, 1.0],
+            [0.0, 1.0, 1.0],
+        ],
+    },
+    Face {
+        normal: (0, 0, -1),
+        axis: 2,
+        corners: [
+            [0.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0],
+            [1.0, 1.0, 0.0],
+            [1.0, 0.0, 0.0],
+        ],
+    },
 ];
 
-fn append_solid_color_cube_model(
+const CHUNK_SIZE: [usize; 3] = [CHUNK_SIZE_X, CHUNK_SIZE_Y, CHUNK_SIZE_Z];
+
+//Two triangles per quad, split along the diagonal from corner 0 to 2 or from corner 1 to 3
+const QUAD_INDICES: [usize; 6] = [0, 1, 2, 0, 2, 3];
+const FLIPPED_QUAD_INDICES: [usize; 6] = [1, 2, 3, 1, 3, 0];
+
+/// Ambient occlusion of a corner that no voxel occludes
+const MAX_AO: u8 = 3;
+
+/// Selects the algorithm that is used to turn chunks into meshes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Mesher {
+    /// Emits one quad for every visible voxel face
+    #[default]
+    Culled,
+    /// Merges coplanar visible faces with the same appearance into larger quads
+    Greedy,
+}
+
+impl Mesher {
+    pub(super) fn generate_mesh(
+        self,
+        neighborhood: &ChunkNeighborhood,
+        appearance_registry: &AttributeRegistry<AppearanceAttribute>,
+    ) -> Vec<Vertex> {
+        match self {
+            Mesher::Culled => generate_mesh(neighborhood, appearance_registry),
+            Mesher::Greedy => generate_greedy_mesh(neighborhood, appearance_registry),
+        }
+    }
+}
+
+/// Everything that determines how a single voxel face is drawn.
+/// Greedy meshing only merges faces that look exactly the same.
+#[derive(Clone, Copy, PartialEq)]
+struct FaceAppearance {
+    color: [f32; 3],
+    /// The light of the voxel in front of the face
+    light: LightLevel,
+    /// Ambient occlusion of every corner, in the same order as `Face::corners`
+    ao: [u8; 4],
+}
+
+fn is_opaque(voxel: &Voxel, appearance_registry: &AttributeRegistry<AppearanceAttribute>) -> bool {
+    matches!(
+        appearance_registry.find(voxel.id),
+        Ok(AppearanceAttribute::SolidColorCube(_))
+    )
+}
+
+/// Returns the appearance of a face of the voxel at the given local position, or `None` if the face is hidden.
+/// Faces on the chunk border are kept if the neighboring chunk is not loaded.
+fn visible_face(
+    neighborhood: &ChunkNeighborhood,
+    appearance_registry: &AttributeRegistry<AppearanceAttribute>,
+    face: &Face,
+    xyz_local: [usize; 3],
+) -> Option<FaceAppearance> {
+    let voxel = neighborhood
+        .center
+        .get_voxel_at_position(xyz_local[0], xyz_local[1], xyz_local[2]);
+    let solid_model = match appearance_registry.find(voxel.id).unwrap() {
+        AppearanceAttribute::SolidColorCube(solid_color_cube_model) => solid_color_cube_model,
+        AppearanceAttribute::None => return None,
+    };
+
+    let (neighbor_x, neighbor_y, neighbor_z) = (
+        xyz_local[0] as i32 + face.normal.0,
+        xyz_local[1] as i32 + face.normal.1,
+        xyz_local[2] as i32 + face.normal.2,
+    );
+    if let Some(neighbor) = neighborhood.get_voxel(neighbor_x, neighbor_y, neighbor_z) {
+        if is_opaque(neighbor, appearance_registry) {
+            return None;
+        }
+    }
+
+    Some(FaceAppearance {
+        color: solid_model.get_color_array(),
+        light: neighborhood.get_light(neighbor_x, neighbor_y, neighbor_z),
+        ao: face_ao(
+            neighborhood,
+            appearance_registry,
+            face,
+            [neighbor_x, neighbor_y, neighbor_z],
+        ),
+    })
+}
+
+/// Computes the ambient occlusion of every corner of a face from the voxels around the voxel in front of it.
+/// Each corner is darkened by the two voxels next to it and the one diagonal to it within that layer.
+/// Voxels in neighbors that are not loaded don't occlude anything.
+fn face_ao(
+    neighborhood: &ChunkNeighborhood,
+    appearance_registry: &AttributeRegistry<AppearanceAttribute>,
+    face: &Face,
+    front: [i32; 3],
+) -> [u8; 4] {
+    let (axis_u, axis_v) = ((face.axis + 1) % 3, (face.axis + 2) % 3);
+    let occludes = |du: i32, dv: i32| {
+        let mut xyz = front;
+        xyz[axis_u] += du;
+        xyz[axis_v] += dv;
+        neighborhood
+            .get_voxel(xyz[0], xyz[1], xyz[2])
+            .is_some_and(|voxel| is_opaque(voxel, appearance_registry))
+    };
+
+    face.corners.map(|corner| {
+        let du = if corner[axis_u] > 0.0 { 1 } else { -1 };
+        let dv = if corner[axis_v] > 0.0 { 1 } else { -1 };
+        let (side_u, side_v, diagonal) = (occludes(du, 0), occludes(0, dv), occludes(du, dv));
+        //With both sides blocked the corner is fully hidden, whatever the diagonal voxel is
+        if side_u && side_v {
+            0
+        } else {
+            MAX_AO - side_u as u8 - side_v as u8 - diagonal as u8
+        }
+    })
+}
+
+/// Appends a quad for the given face, scaling the unit face by `extent` and moving it to `origin`
+fn append_quad(
     vec: &mut Vec<Vertex>,
-    solid_model: &SolidColorCubeModel,
-    offset_x: f32,
-    offset_y: f32,
-    offset_z: f32,
+    face: &Face,
+    appearance: &FaceAppearance,
+    origin: [f32; 3],
+    extent: [f32; 3],
 ) {
-    vec.reserve(CUBE_VERTICES.len());
-    for vertex_position in CUBE_VERTICES {
-        let translated_vertex_position = [vertex_position[0] + offset_x, vertex_position[1] + offset_y, vertex_position[2] + offset_z];
+    let normal = [
+        face.normal.0 as f32,
+        face.normal.1 as f32,
+        face.normal.2 as f32,
+    ];
+    let light = [
+        appearance.light.sky as f32 / MAX_LIGHT as f32,
+        appearance.light.block as f32 / MAX_LIGHT as f32,
+    ];
+    //Occlusion is interpolated along the shared diagonal, so split along the brighter one.
+    //This keeps a single occluded corner in its own triangle no matter how the face is rotated.
+    let ao = appearance.ao;
+    let indices = if ao[1] + ao[3] > ao[0] + ao[2] {
+        FLIPPED_QUAD_INDICES
+    } else {
+        QUAD_INDICES
+    };
+    vec.reserve(indices.len());
+    for i in indices {
+        let corner = face.corners[i];
         vec.push(Vertex {
-            position: translated_vertex_position,
-            color: solid_model.get_color_array(),
+            position: [
+                corner[0] * extent[0] + origin[0],
+                corner[1] * extent[1] + origin[1],
+                corner[2] * extent[2] + origin[2],
+            ],
+            color: appearance.color,
+            normal,
+            light,
+            ao: ao[i] as f32 / MAX_AO as f32,
         });
     }
 }
 
+/// Generates a mesh for the center chunk of the neighborhood, leaving out every face that touches an opaque voxel.
 pub(super) fn generate_mesh(
-    array: &VoxelArray,
+    neighborhood: &ChunkNeighborhood,
     appearance_registry: &AttributeRegistry<AppearanceAttribute>,
 ) -> Vec<Vertex> {
     let mut mesh = Vec::new();
@@ -119,17 +408,426 @@ pub(super) fn generate_mesh(
     for x in 0..CHUNK_SIZE_X {
         for y in 0..CHUNK_SIZE_Y {
             for z in 0..CHUNK_SIZE_Z {
-                let voxel = array.get_voxel_at_position(x, y, z);
-                let voxel_appearance = appearance_registry.find(voxel.id).unwrap();
-                match voxel_appearance {
-                    AppearanceAttribute::SolidColorCube(solid_color_cube_model) => {
-                        append_solid_color_cube_model(&mut mesh, solid_color_cube_model, x as f32, y as f32, z as f32)
+                for face in FACES.iter() {
+                    if let Some(appearance) =
+                        visible_face(neighborhood, appearance_registry, face, [x, y, z])
+                    {
+                        append_quad(
+                            &mut mesh,
+                            face,
+                            &appearance,
+                            [x as f32, y as f32, z as f32],
+                            [1.0; 3],
+                        );
                     }
-                    AppearanceAttribute::None => (),
                 }
             }
         }
     }
 
     mesh
-}
\ No newline at end of file
+}
+
+/// Generates the same surface as `generate_mesh`, but merges neighboring faces
+/// that lie in the same plane and look the same into as few rectangles as possible.
+pub(super) fn generate_greedy_mesh(
+    neighborhood: &ChunkNeighborhood,
+    appearance_registry: &AttributeRegistry<AppearanceAttribute>,
+) -> Vec<Vertex> {
+    let mut mesh = Vec::new();
+
+    for face in FACES.iter() {
+        //The face plane is spanned by the u and v axes
+        let axis_u = (face.axis + 1) % 3;
+        let axis_v = (face.axis + 2) % 3;
+        let (size_u, size_v) = (CHUNK_SIZE[axis_u], CHUNK_SIZE[axis_v]);
+        let mut mask: Vec<Option<FaceAppearance>> = vec![None; size_u * size_v];
+
+        for slice in 0..CHUNK_SIZE[face.axis] {
+            let to_local = |u: usize, v: usize| {
+                let mut xyz_local = [0; 3];
+                xyz_local[face.axis] = slice;
+                xyz_local[axis_u] = u;
+                xyz_local[axis_v] = v;
+                xyz_local
+            };
+
+            for v in 0..size_v {
+                for u in 0..size_u {
+                    mask[v * size_u + u] =
+                        visible_face(neighborhood, appearance_registry, face, to_local(u, v));
+                }
+            }
+
+            for v in 0..size_v {
+                let mut u = 0;
+                while u < size_u {
+                    let appearance = match mask[v * size_u + u] {
+                        Some(appearance) => appearance,
+                        None => {
+                            u += 1;
+                            continue;
+                        }
+                    };
+
+                    //Occlusion is interpolated across the whole quad,
+                    //so only faces that are equally occluded at every corner can be merged
+                    let mergeable = appearance.ao.iter().all(|&ao| ao == appearance.ao[0]);
+
+                    //Grow along u first, then grow the whole row along v
+                    let mut width = 1;
+                    while mergeable
+                        && u + width < size_u
+                        && mask[v * size_u + u + width] == Some(appearance)
+                    {
+                        width += 1;
+                    }
+                    let mut height = 1;
+                    while mergeable
+                        && v + height < size_v
+                        && (u..u + width)
+                            .all(|i| mask[(v + height) * size_u + i] == Some(appearance))
+                    {
+                        height += 1;
+                    }
+
+                    for row in v..v + height {
+                        for cell in &mut mask[row * size_u + u..row * size_u + u + width] {
+                            *cell = None;
+                        }
+                    }
+
+                    let origin = to_local(u, v).map(|c| c as f32);
+                    let mut extent = [1.0; 3];
+                    extent[axis_u] = width as f32;
+                    extent[axis_v] = height as f32;
+                    append_quad(&mut mesh, face, &appearance, origin, extent);
+
+                    u += width;
+                }
+            }
+        }
+    }
+
+    mesh
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::test_util::XorShift;
+    use std::collections::HashSet;
+
+    const AIR: Voxel = Voxel { id: 0, data: 0 };
+    const RED: Voxel = Voxel { id: 1, data: 0 };
+    const BLUE: Voxel = Voxel { id: 2, data: 0 };
+
+    fn appearance_registry() -> AttributeRegistry<AppearanceAttribute> {
+        let mut registry = AttributeRegistry::new("appearance");
+        registry
+            .register(AIR.id, AppearanceAttribute::None)
+            .unwrap();
+        registry
+            .register(
+                RED.id,
+                AppearanceAttribute::SolidColorCube(SolidColorCubeModel {
+                    color: (1.0, 0.0, 0.0),
+                }),
+            )
+            .unwrap();
+        registry
+            .register(
+                BLUE.id,
+                AppearanceAttribute::SolidColorCube(SolidColorCubeModel {
+                    color: (0.0, 0.0, 1.0),
+                }),
+            )
+            .unwrap();
+        registry
+    }
+
+    fn chunk_from_fn<F: Fn(usize, usize, usize) -> Voxel>(f: F) -> VoxelArray {
+        let mut array = VoxelArray::new(AIR);
+        for x in 0..CHUNK_SIZE_X {
+            for y in 0..CHUNK_SIZE_Y {
+                for z in 0..CHUNK_SIZE_Z {
+                    array.set_voxel_at_position(x, y, z, f(x, y, z));
+                }
+            }
+        }
+        array
+    }
+
+    /// Face normal, minimum corner, and color bits of a unit square
+    type UnitFace = ((i32, i32, i32), [i32; 3], [u32; 3]);
+
+    /// Splits a mesh into the unit squares it covers, keyed by the face normal and the minimum corner.
+    /// Panics if any unit square is covered twice.
+    fn covered_unit_faces(mesh: &[Vertex]) -> HashSet<UnitFace> {
+        assert_eq!(mesh.len() % QUAD_INDICES.len(), 0);
+        let mut covered = HashSet::new();
+        for quad in mesh.chunks(QUAD_INDICES.len()) {
+            let [a, b, c] = [quad[0].position, quad[1].position, quad[2].position];
+            let ab = [b[0] - a[0], b[1] - a[1], b[2] - a[2]];
+            let ac = [c[0] - a[0], c[1] - a[1], c[2] - a[2]];
+            let sign = |f: f32| (f > 0.0) as i32 - (f < 0.0) as i32;
+            let normal = (
+                sign(ab[1] * ac[2] - ab[2] * ac[1]),
+                sign(ab[2] * ac[0] - ab[0] * ac[2]),
+                sign(ab[0] * ac[1] - ab[1] * ac[0]),
+            );
+
+            let mut min = [f32::MAX; 3];
+            let mut max = [f32::MIN; 3];
+            for vertex in quad {
+                for axis in 0..3 {
+                    min[axis] = min[axis].min(vertex.position[axis]);
+                    max[axis] = max[axis].max(vertex.position[axis]);
+                }
+            }
+            let color = quad[0].color.map(|c| c.to_bits());
+
+            let flat_axis = (0..3).find(|&axis| min[axis] == max[axis]).unwrap();
+            let range = |axis: usize| {
+                if axis == flat_axis {
+                    min[axis] as i32..min[axis] as i32 + 1
+                } else {
+                    min[axis] as i32..max[axis] as i32
+                }
+            };
+            for x in range(0) {
+                for y in range(1) {
+                    for z in range(2) {
+                        assert!(
+                            covered.insert((normal, [x, y, z], color)),
+                            "Face covered twice"
+                        );
+                    }
+                }
+            }
+        }
+        covered
+    }
+
+    fn compare_meshers(neighborhood: &ChunkNeighborhood) -> (usize, usize) {
+        let registry = appearance_registry();
+        let culled = generate_mesh(neighborhood, &registry);
+        let greedy = generate_greedy_mesh(neighborhood, &registry);
+
+        assert_eq!(covered_unit_faces(&culled), covered_unit_faces(&greedy));
+        assert!(greedy.len() <= culled.len());
+        (culled.len(), greedy.len())
+    }
+
+    fn chunk_with(voxels: &[(usize, usize, usize)]) -> VoxelArray {
+        chunk_from_fn(|x, y, z| {
+            if voxels.contains(&(x, y, z)) {
+                RED
+            } else {
+                AIR
+            }
+        })
+    }
+
+    /// Returns the occlusion level of the upward facing corner at the given position.
+    /// Checks that both meshers agree and that every vertex at the corner has the same occlusion.
+    fn top_corner_ao(neighborhood: &ChunkNeighborhood, position: [f32; 3]) -> u8 {
+        let registry = appearance_registry();
+        let meshes = [
+            generate_mesh(neighborhood, &registry),
+            generate_greedy_mesh(neighborhood, &registry),
+        ];
+        let levels: HashSet<u8> = meshes
+            .iter()
+            .flatten()
+            .filter(|vertex| vertex.normal == [0.0, 1.0, 0.0] && vertex.position == position)
+            .map(|vertex| (vertex.ao * MAX_AO as f32).round() as u8)
+            .collect();
+        assert_eq!(
+            levels.len(),
+            1,
+            "Corner {:?} has occlusion levels {:?}",
+            position,
+            levels
+        );
+        levels.into_iter().next().unwrap()
+    }
+
+    #[test]
+    fn empty_chunk_has_no_vertices() {
+        let chunk = VoxelArray::new(AIR);
+        assert_eq!(compare_meshers(&ChunkNeighborhood::new(&chunk)), (0, 0));
+    }
+
+    #[test]
+    fn single_voxel_is_a_cube() {
+        let chunk = chunk_from_fn(|x, y, z| if (x, y, z) == (3, 4, 5) { RED } else { AIR });
+        assert_eq!(compare_meshers(&ChunkNeighborhood::new(&chunk)), (36, 36));
+    }
+
+    #[test]
+    fn flat_layer_merges_into_one_quad_per_side() {
+        let chunk = chunk_from_fn(|_, y, _| if y == 0 { RED } else { AIR });
+        let faces = 2 * CHUNK_SIZE_X * CHUNK_SIZE_Z + 2 * CHUNK_SIZE_X + 2 * CHUNK_SIZE_Z;
+        assert_eq!(
+            compare_meshers(&ChunkNeighborhood::new(&chunk)),
+            (faces * QUAD_INDICES.len(), 6 * QUAD_INDICES.len())
+        );
+    }
+
+    #[test]
+    fn different_appearances_are_not_merged() {
+        let chunk = chunk_from_fn(|x, y, _| match (y, x < CHUNK_SIZE_X / 2) {
+            (0, true) => RED,
+            (0, false) => BLUE,
+            _ => AIR,
+        });
+        let (_, greedy) = compare_meshers(&ChunkNeighborhood::new(&chunk));
+        //Top, bottom, and the four outer sides of each half
+        assert_eq!(greedy, 2 * 6 * QUAD_INDICES.len() - 2 * QUAD_INDICES.len());
+    }
+
+    #[test]
+    fn different_light_is_not_merged() {
+        let chunk = chunk_from_fn(|_, y, _| if y == 0 { RED } else { AIR });
+        let mut light = LightArray::new();
+        for x in 0..CHUNK_SIZE_X {
+            for z in 0..CHUNK_SIZE_Z {
+                let sky = if z < CHUNK_SIZE_Z / 2 { MAX_LIGHT } else { 0 };
+                light.set_light_at_position(x, 1, z, LightLevel { sky, block: 0 });
+            }
+        }
+        let mut neighborhood = ChunkNeighborhood::new(&chunk);
+        neighborhood.lights[CENTER_INDEX] = Some(&light);
+
+        let greedy = generate_greedy_mesh(&neighborhood, &appearance_registry());
+        let top: Vec<&[Vertex]> = greedy
+            .chunks(QUAD_INDICES.len())
+            .filter(|quad| quad[0].normal == [0.0, 1.0, 0.0])
+            .collect();
+        assert_eq!(top.len(), 2);
+        assert!(top.iter().any(|quad| quad[0].light == [1.0, 0.0]));
+        assert!(top.iter().any(|quad| quad[0].light == [0.0, 0.0]));
+    }
+
+    #[test]
+    fn open_voxel_is_not_occluded() {
+        let chunk = chunk_with(&[(3, 4, 5)]);
+        let mesh = generate_mesh(&ChunkNeighborhood::new(&chunk), &appearance_registry());
+        assert!(mesh.iter().all(|vertex| vertex.ao == 1.0));
+    }
+
+    #[test]
+    fn side_and_diagonal_voxels_occlude_one_level_each() {
+        //The top face of the voxel at (5, 5, 5) spans from (5, 6, 5) to (6, 6, 6)
+        let chunk = chunk_with(&[(5, 5, 5), (6, 6, 5)]);
+        let neighborhood = ChunkNeighborhood::new(&chunk);
+        assert_eq!(top_corner_ao(&neighborhood, [6.0, 6.0, 5.0]), 2);
+        assert_eq!(top_corner_ao(&neighborhood, [6.0, 6.0, 6.0]), 2);
+        assert_eq!(top_corner_ao(&neighborhood, [5.0, 6.0, 5.0]), 3);
+        assert_eq!(top_corner_ao(&neighborhood, [5.0, 6.0, 6.0]), 3);
+
+        let chunk = chunk_with(&[(5, 5, 5), (4, 6, 4)]);
+        let neighborhood = ChunkNeighborhood::new(&chunk);
+        assert_eq!(top_corner_ao(&neighborhood, [5.0, 6.0, 5.0]), 2);
+        assert_eq!(top_corner_ao(&neighborhood, [6.0, 6.0, 5.0]), 3);
+        assert_eq!(top_corner_ao(&neighborhood, [5.0, 6.0, 6.0]), 3);
+        assert_eq!(top_corner_ao(&neighborhood, [6.0, 6.0, 6.0]), 3);
+
+        //Voxels that don't touch the corner don't occlude it
+        let chunk = chunk_with(&[(5, 5, 5), (6, 6, 5), (7, 6, 6), (6, 6, 7)]);
+        let neighborhood = ChunkNeighborhood::new(&chunk);
+        assert_eq!(top_corner_ao(&neighborhood, [6.0, 6.0, 6.0]), 2);
+    }
+
+    #[test]
+    fn two_sides_fully_occlude_a_corner() {
+        let chunk = chunk_with(&[(5, 5, 5), (6, 6, 5), (5, 6, 6)]);
+        let neighborhood = ChunkNeighborhood::new(&chunk);
+        assert_eq!(top_corner_ao(&neighborhood, [6.0, 6.0, 6.0]), 0);
+        assert_eq!(top_corner_ao(&neighborhood, [6.0, 6.0, 5.0]), 2);
+        assert_eq!(top_corner_ao(&neighborhood, [5.0, 6.0, 6.0]), 2);
+        assert_eq!(top_corner_ao(&neighborhood, [5.0, 6.0, 5.0]), 3);
+    }
+
+    #[test]
+    fn quads_are_split_along_the_brighter_diagonal() {
+        //Occlude each corner of the top face in turn, the dark corner must only be part of one triangle
+        for (dx, dz) in [(-1, -1), (1, -1), (1, 1), (-1, 1)] {
+            let occluder = ((5 + dx) as usize, 6, (5 + dz) as usize);
+            let chunk = chunk_with(&[(5, 5, 5), occluder]);
+            let mesh = generate_mesh(&ChunkNeighborhood::new(&chunk), &appearance_registry());
+            let top: Vec<&Vertex> = mesh
+                .iter()
+                .filter(|vertex| vertex.normal == [0.0, 1.0, 0.0] && vertex.position[1] == 6.0)
+                .collect();
+            assert_eq!(top.len(), QUAD_INDICES.len());
+            assert_eq!(top.iter().filter(|vertex| vertex.ao < 1.0).count(), 1);
+        }
+    }
+
+    #[test]
+    fn neighbor_chunks_occlude_border_corners() {
+        //The top face of the voxel at (15, 15, 5) lies on the border to the chunk above
+        let chunk = chunk_with(&[(15, 15, 5)]);
+        let diagonal = chunk_with(&[(0, 0, 5)]);
+        let above = chunk_with(&[(15, 0, 6)]);
+
+        let mut neighborhood = ChunkNeighborhood::new(&chunk);
+        assert_eq!(top_corner_ao(&neighborhood, [16.0, 16.0, 5.0]), 3);
+
+        neighborhood.neighbors[neighbor_index(1, 1, 0)] = Some(&diagonal);
+        assert_eq!(top_corner_ao(&neighborhood, [16.0, 16.0, 5.0]), 2);
+        assert_eq!(top_corner_ao(&neighborhood, [16.0, 16.0, 6.0]), 2);
+        assert_eq!(top_corner_ao(&neighborhood, [15.0, 16.0, 5.0]), 3);
+
+        neighborhood.neighbors[neighbor_index(0, 1, 0)] = Some(&above);
+        assert_eq!(top_corner_ao(&neighborhood, [16.0, 16.0, 6.0]), 0);
+        assert_eq!(top_corner_ao(&neighborhood, [15.0, 16.0, 6.0]), 2);
+        assert_eq!(top_corner_ao(&neighborhood, [16.0, 16.0, 5.0]), 2);
+    }
+
+    #[test]
+    fn solid_neighbors_hide_border_faces() {
+        let chunk = chunk_from_fn(|_, _, _| RED);
+        let neighbor = chunk_from_fn(|_, _, _| RED);
+        let neighborhood = ChunkNeighborhood {
+            center: &chunk,
+            neighbors: [Some(&neighbor); 27],
+            ..ChunkNeighborhood::new(&chunk)
+        };
+        assert_eq!(compare_meshers(&neighborhood), (0, 0));
+
+        let open_neighborhood = ChunkNeighborhood::new(&chunk);
+        let faces = 2
+            * (CHUNK_SIZE_X * CHUNK_SIZE_Y
+                + CHUNK_SIZE_Y * CHUNK_SIZE_Z
+                + CHUNK_SIZE_X * CHUNK_SIZE_Z);
+        assert_eq!(
+            compare_meshers(&open_neighborhood),
+            (faces * QUAD_INDICES.len(), 6 * QUAD_INDICES.len())
+        );
+    }
+
+    #[test]
+    fn random_chunks_cover_the_same_area() {
+        let mut rng = XorShift(0x9E37_79B9);
+
+        for _ in 0..8 {
+            let voxels: Vec<Voxel> = (0..CHUNK_SIZE_X * CHUNK_SIZE_Y * CHUNK_SIZE_Z)
+                .map(|_| [AIR, RED, BLUE][(rng.next() % 3) as usize])
+                .collect();
+            let neighbor_voxels: Vec<Voxel> = (0..CHUNK_SIZE_X * CHUNK_SIZE_Y * CHUNK_SIZE_Z)
+                .map(|_| [AIR, RED][(rng.next() % 2) as usize])
+                .collect();
+            let chunk = chunk_from_fn(|x, y, z| voxels[VoxelArray::get_voxel_index(x, y, z)]);
+            let neighbor =
+                chunk_from_fn(|x, y, z| neighbor_voxels[VoxelArray::get_voxel_index(x, y, z)]);
+
+            let mut neighborhood = ChunkNeighborhood::new(&chunk);
+            neighborhood.neighbors[neighbor_index(1, 0, 0)] = Some(&neighbor);
+            neighborhood.neighbors[neighbor_index(0, -1, 0)] = Some(&neighbor);
+            compare_meshers(&neighborhood);
+        }
+    }
+}
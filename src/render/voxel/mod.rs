@@ -1,10 +1,17 @@
 //Uses
 use super::Camera;
 use crate::res;
+use crate::world::chunk::size::*;
 use crate::world::chunk::ChunkArray;
+use crate::world::coords;
+use crate::world::light;
+use crate::world::light::LightSystem;
 use crate::world::voxel;
 use crate::world::voxel::VoxelSystem;
-use log::{debug, trace};
+use bytemuck::{Pod, Zeroable};
+use cgmath::{InnerSpace, Vector3};
+use log::trace;
+use std::collections::BTreeSet;
 
 use wgpu;
 use wgpu::util::DeviceExt;
@@ -13,23 +20,68 @@ use wgpu::util::DeviceExt;
 mod mesh;
 
 //Exports
-pub use mesh::{AppearanceAttribute, SolidColorCubeModel};
+pub use mesh::{AppearanceAttribute, Mesher, SolidColorCubeModel};
 
 struct ChunkData {
     buffer: wgpu::Buffer,
     vertex_count: u64,
+
+    //Translation from chunk-local to world space, bound to group 1
+    offset_buffer: wgpu::Buffer,
+    offset_bind_group: wgpu::BindGroup,
+}
+
+impl ChunkData {
+    fn destroy(self) {
+        self.buffer.destroy();
+        self.offset_buffer.destroy();
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Zeroable, Pod)]
+struct CameraUniform {
+    view_projection: [[f32; 4]; 4],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Zeroable, Pod)]
+struct LightingUniform {
+    //Unit vector pointing towards the sun in xyz, daylight strength in w
+    sun: [f32; 4],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Zeroable, Pod)]
+struct ChunkUniform {
+    //Padded to a vec4 for uniform buffer alignment
+    offset: [f32; 4],
 }
 
 pub(super) struct VoxelRenderSystem {
     //Chunk array
     chunks: ChunkArray<ChunkData>,
 
+    //Meshing settings
+    mesher: Mesher,
+    remesh_all: bool,
+
+    //Lighting settings
+    sun_direction: Vector3<f32>,
+    daylight: f32,
+
     //WGPU resources
     pipeline: wgpu::RenderPipeline,
+    camera_buffer: wgpu::Buffer,
+    camera_bind_group: wgpu::BindGroup,
+    lighting_buffer: wgpu::Buffer,
+    lighting_bind_group: wgpu::BindGroup,
+    chunk_bind_group_layout: wgpu::BindGroupLayout,
 }
 
 pub(super) struct PipelineInitParams {
     pub output_texture_format: wgpu::TextureFormat,
+    pub depth_texture_format: wgpu::TextureFormat,
 }
 
 impl VoxelRenderSystem {
@@ -38,18 +90,122 @@ impl VoxelRenderSystem {
         device: &wgpu::Device,
         pipeline_init: PipelineInitParams,
     ) -> VoxelRenderSystem {
-        let pipeline = create_render_pipeline(device, res, &pipeline_init);
+        let camera_bind_group_layout = create_uniform_bind_group_layout(
+            device,
+            "Voxel camera bind group layout",
+            std::mem::size_of::<CameraUniform>(),
+        );
+        let chunk_bind_group_layout = create_uniform_bind_group_layout(
+            device,
+            "Voxel chunk bind group layout",
+            std::mem::size_of::<ChunkUniform>(),
+        );
+        let lighting_bind_group_layout = create_uniform_bind_group_layout(
+            device,
+            "Voxel lighting bind group layout",
+            std::mem::size_of::<LightingUniform>(),
+        );
+        let pipeline = create_render_pipeline(
+            device,
+            res,
+            &pipeline_init,
+            &[
+                &camera_bind_group_layout,
+                &chunk_bind_group_layout,
+                &lighting_bind_group_layout,
+            ],
+        );
+
+        let camera_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Voxel camera uniform"),
+            size: std::mem::size_of::<CameraUniform>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let camera_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Voxel camera bind group"),
+            layout: &camera_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: camera_buffer.as_entire_binding(),
+            }],
+        });
+
+        let lighting_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Voxel lighting uniform"),
+            size: std::mem::size_of::<LightingUniform>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let lighting_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Voxel lighting bind group"),
+            layout: &lighting_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: lighting_buffer.as_entire_binding(),
+            }],
+        });
 
         VoxelRenderSystem {
             chunks: ChunkArray::new(),
+            mesher: Mesher::default(),
+            remesh_all: false,
+            sun_direction: Vector3::new(0.3, 1.0, 0.5).normalize(),
+            daylight: 1.0,
             pipeline,
+            camera_buffer,
+            camera_bind_group,
+            lighting_buffer,
+            lighting_bind_group,
+            chunk_bind_group_layout,
         }
     }
 
-    pub fn update(&mut self, voxel_system: &VoxelSystem, device: &wgpu::Device, queue: &wgpu::Queue) {
-        let appearance_registry = voxel_system
-            .get_attribute_registry::<AppearanceAttribute>()
-            .unwrap();
+    pub fn mesher(&self) -> Mesher {
+        self.mesher
+    }
+
+    /// Changes the meshing algorithm, all chunks are remeshed on the next update
+    pub fn set_mesher(&mut self, mesher: Mesher) {
+        if self.mesher != mesher {
+            self.mesher = mesher;
+            self.remesh_all = true;
+        }
+    }
+
+    pub fn sun_direction(&self) -> Vector3<f32> {
+        self.sun_direction
+    }
+
+    /// Sets the direction towards the sun, faces pointing towards it are lit more brightly
+    pub fn set_sun_direction(&mut self, direction: Vector3<f32>) {
+        if direction.magnitude2() > 0.0 {
+            self.sun_direction = direction.normalize();
+        }
+    }
+
+    pub fn daylight(&self) -> f32 {
+        self.daylight
+    }
+
+    /// Sets how bright sky light is, from 0 at night to 1 at noon. Block light is not affected.
+    pub fn set_daylight(&mut self, daylight: f32) {
+        self.daylight = daylight.clamp(0.0, 1.0);
+    }
+
+    pub fn update(
+        &mut self,
+        voxel_system: &VoxelSystem,
+        light_system: &LightSystem,
+        device: &wgpu::Device,
+    ) {
+        //Collect the chunks whose meshes are out of date first so that each is only rebuilt once
+        let mut dirty_chunks = BTreeSet::new();
+
+        if self.remesh_all {
+            dirty_chunks.extend(self.chunks.iter().map(|(coords, _)| *coords));
+            self.remesh_all = false;
+        }
 
         let voxel_events = voxel_system.get_events();
         for ev in voxel_events.iter() {
@@ -59,35 +215,177 @@ impl VoxelRenderSystem {
                     coords_y,
                     coords_z,
                 } => {
-                    let voxel_array =
-                        voxel_system.get_chunk(*coords_x, *coords_y, *coords_z).unwrap();
-                    let mesh = mesh::generate_mesh(voxel_array, appearance_registry.as_ref());
-                    let buffer = device.create_buffer_init(
-                        &wgpu::util::BufferInitDescriptor {
-                            label: Some("Voxel mesh"),
-                            contents: bytemuck::cast_slice(&mesh[..]),
-                            usage: wgpu::BufferUsages::VERTEX
-                        }
-                    );
+                    mark_chunk_and_neighbors(&mut dirty_chunks, (*coords_x, *coords_y, *coords_z));
+                    trace!(
+                        "Chunk loaded at coordinates: {:?}",
+                        (*coords_x, *coords_y, *coords_z)
+                    )
+                }
+                voxel::Event::ChunkUnloaded {
+                    coords_x,
+                    coords_y,
+                    coords_z,
+                } => {
+                    mark_chunk_and_neighbors(&mut dirty_chunks, (*coords_x, *coords_y, *coords_z));
+                    trace!(
+                        "Chunk unloaded at coordinates: {:?}",
+                        (*coords_x, *coords_y, *coords_z)
+                    )
+                }
+                voxel::Event::VoxelChanged {
+                    global_x,
+                    global_y,
+                    global_z,
+                    ..
+                } => {
+                    let (xyz_local, xyz_chunk) =
+                        coords::global_to_local(*global_x, *global_y, *global_z);
 
-                    let chunk_data = ChunkData {
-                        buffer: buffer,
-                        vertex_count: mesh.len() as u64
+                    //Voxels on the chunk border can hide or reveal faces of the neighboring chunks,
+                    //and occlude corners of faces in the chunks diagonal to them
+                    let (x, y, z) = xyz_chunk;
+                    let border_offsets = |local: u32, size: usize| match local {
+                        0 => vec![0, -1],
+                        local if local as usize == size - 1 => vec![0, 1],
+                        _ => vec![0],
                     };
-                    self.chunks.add(chunk_data, *coords_x, *coords_y, *coords_z);
-                    trace!("Chunk loaded at coordinates: {:?}", (*coords_x, *coords_y, *coords_z))
+                    for dx in border_offsets(xyz_local.0, CHUNK_SIZE_X) {
+                        for dy in border_offsets(xyz_local.1, CHUNK_SIZE_Y) {
+                            for dz in border_offsets(xyz_local.2, CHUNK_SIZE_Z) {
+                                dirty_chunks.insert((x + dx, y + dy, z + dz));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        //Light events already include neighbors whose border faces are affected
+        for ev in light_system.get_events().iter() {
+            match ev {
+                light::Event::LightChanged {
+                    coords_x,
+                    coords_y,
+                    coords_z,
+                } => {
+                    dirty_chunks.insert((*coords_x, *coords_y, *coords_z));
                 }
-                _ => (),
             }
         }
+
+        for (x, y, z) in dirty_chunks {
+            self.rebuild_chunk(voxel_system, light_system, device, x, y, z);
+        }
+    }
+
+    fn rebuild_chunk(
+        &mut self,
+        voxel_system: &VoxelSystem,
+        light_system: &LightSystem,
+        device: &wgpu::Device,
+        x: i32,
+        y: i32,
+        z: i32,
+    ) {
+        let voxel_array = match voxel_system.get_chunk(x, y, z) {
+            Some(voxel_array) => voxel_array,
+            None => {
+                //The chunk is gone, so release its GPU resources as well
+                if let Some(chunk_data) = self.chunks.remove(x, y, z) {
+                    chunk_data.destroy();
+                }
+                return;
+            }
+        };
+        let appearance_registry = voxel_system
+            .get_attribute_registry::<AppearanceAttribute>()
+            .unwrap();
+
+        let mut neighborhood = mesh::ChunkNeighborhood::gather(voxel_array, x, y, z, |x, y, z| {
+            voxel_system.get_chunk(x, y, z)
+        });
+        neighborhood.gather_light(x, y, z, |x, y, z| light_system.get_chunk(x, y, z));
+        let mesh = self
+            .mesher
+            .generate_mesh(&neighborhood, appearance_registry.as_ref());
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Voxel mesh"),
+            contents: bytemuck::cast_slice(&mesh[..]),
+            usage: wgpu::BufferUsages::VERTEX,
+        });
+
+        match self.chunks.get_mut(x, y, z) {
+            Some(existing) => {
+                //The chunk offset never changes, so only the mesh needs replacing
+                std::mem::replace(&mut existing.buffer, buffer).destroy();
+                existing.vertex_count = mesh.len() as u64;
+            }
+            None => {
+                let (offset_buffer, offset_bind_group) = self.create_chunk_offset(device, x, y, z);
+                let chunk_data = ChunkData {
+                    buffer,
+                    vertex_count: mesh.len() as u64,
+                    offset_buffer,
+                    offset_bind_group,
+                };
+                self.chunks.add(chunk_data, x, y, z);
+            }
+        }
+    }
+
+    fn create_chunk_offset(
+        &self,
+        device: &wgpu::Device,
+        x: i32,
+        y: i32,
+        z: i32,
+    ) -> (wgpu::Buffer, wgpu::BindGroup) {
+        let (origin_x, origin_y, origin_z) = coords::local_to_global((0, 0, 0), (x, y, z));
+        let uniform = ChunkUniform {
+            offset: [origin_x as f32, origin_y as f32, origin_z as f32, 0.0],
+        };
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Voxel chunk uniform"),
+            contents: bytemuck::bytes_of(&uniform),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Voxel chunk bind group"),
+            layout: &self.chunk_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: buffer.as_entire_binding(),
+            }],
+        });
+        (buffer, bind_group)
     }
 
     pub(super) fn encode_commands(
         &self,
         device: &wgpu::Device,
+        queue: &wgpu::Queue,
         color_buf: wgpu::TextureView,
+        depth_buf: &wgpu::TextureView,
         camera: &Camera,
     ) -> wgpu::CommandBuffer {
+        let camera_uniform = CameraUniform {
+            view_projection: camera.view_projection_matrix().into(),
+        };
+        queue.write_buffer(&self.camera_buffer, 0, bytemuck::bytes_of(&camera_uniform));
+        let lighting_uniform = LightingUniform {
+            sun: [
+                self.sun_direction.x,
+                self.sun_direction.y,
+                self.sun_direction.z,
+                self.daylight,
+            ],
+        };
+        queue.write_buffer(
+            &self.lighting_buffer,
+            0,
+            bytemuck::bytes_of(&lighting_uniform),
+        );
+
         let mut command_encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
             label: Some("VoxelRenderSystem"),
         });
@@ -99,15 +397,28 @@ impl VoxelRenderSystem {
                     view: &color_buf,
                     resolve_target: None,
                     ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Load,
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
                         store: true,
                     },
                 }],
-                depth_stencil_attachment: None,
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: depth_buf,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
             });
 
             render_pass.set_pipeline(&self.pipeline);
-            for (coords, chunk_data) in self.chunks.iter() {
+            render_pass.set_bind_group(0, &self.camera_bind_group, &[]);
+            render_pass.set_bind_group(2, &self.lighting_bind_group, &[]);
+            for (_coords, chunk_data) in self.chunks.iter() {
+                if chunk_data.vertex_count == 0 {
+                    continue;
+                }
+                render_pass.set_bind_group(1, &chunk_data.offset_bind_group, &[]);
                 render_pass.set_vertex_buffer(0, chunk_data.buffer.slice(..));
                 render_pass.draw(0..chunk_data.vertex_count as u32, 0..1);
             }
@@ -117,10 +428,46 @@ impl VoxelRenderSystem {
     }
 }
 
+/// Marks a chunk and every chunk touching it, including diagonally, since their meshes depend on it
+fn mark_chunk_and_neighbors(
+    dirty_chunks: &mut BTreeSet<(i32, i32, i32)>,
+    xyz_chunk: (i32, i32, i32),
+) {
+    let (x, y, z) = xyz_chunk;
+    for dz in -1..=1 {
+        for dy in -1..=1 {
+            for dx in -1..=1 {
+                dirty_chunks.insert((x + dx, y + dy, z + dz));
+            }
+        }
+    }
+}
+
+fn create_uniform_bind_group_layout(
+    device: &wgpu::Device,
+    label: &str,
+    size: usize,
+) -> wgpu::BindGroupLayout {
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: Some(label),
+        entries: &[wgpu::BindGroupLayoutEntry {
+            binding: 0,
+            visibility: wgpu::ShaderStages::VERTEX,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: wgpu::BufferSize::new(size as u64),
+            },
+            count: None,
+        }],
+    })
+}
+
 fn create_render_pipeline(
     device: &wgpu::Device,
     resource_system: &mut res::ResourceSystem,
     pipeline_init: &PipelineInitParams,
+    bind_group_layouts: &[&wgpu::BindGroupLayout],
 ) -> wgpu::RenderPipeline {
     let shader_source_res = resource_system
         .get_loaded_resource("shaders/voxel.wgsl", res::ResourceLoadType::PlainText)
@@ -132,13 +479,13 @@ fn create_render_pipeline(
     });
     let render_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
         label: None,
-        bind_group_layouts: &[],
+        bind_group_layouts,
         push_constant_ranges: &[],
     });
     let vertex_buffer_layout = wgpu::VertexBufferLayout {
         array_stride: std::mem::size_of::<mesh::Vertex>() as wgpu::BufferAddress,
         step_mode: wgpu::VertexStepMode::Vertex,
-        attributes: &mesh::Vertex::vertex_attribute_array(0, 1)
+        attributes: &mesh::Vertex::vertex_attribute_array(0),
     };
     device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
         label: Some("Voxel rendering pipeline"),
@@ -166,7 +513,13 @@ fn create_render_pipeline(
             unclipped_depth: false,
             conservative: false,
         },
-        depth_stencil: None,
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: pipeline_init.depth_texture_format,
+            depth_write_enabled: true,
+            depth_compare: wgpu::CompareFunction::Less,
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+        }),
         multisample: wgpu::MultisampleState {
             count: 1,
             mask: !0,
@@ -13,7 +13,8 @@ pub mod size {
 }
 
 //Uses
-use size::*;
+use super::voxel::Error;
+use std::collections::btree_map::Entry;
 use std::collections::BTreeMap;
 
 pub struct ChunkArray<T> {
@@ -39,21 +40,44 @@ impl<T> ChunkArray<T> {
         self.try_add(chunk, x, y, z).unwrap();
     }
 
-    pub fn try_add(&mut self, chunk: T, x: i32, y: i32, z: i32) -> Result<(), ()> {
-        let key = (x, y, z);
-        if self.chunks.contains_key(&key) {
-            Err(())
-        } else {
-            self.chunks.insert(key, chunk);
-            Ok(())
+    /// Adds a chunk, fails with `ChunkAlreadyLoaded` if there already is one at the coordinates
+    pub fn try_add(&mut self, chunk: T, x: i32, y: i32, z: i32) -> Result<(), Error> {
+        match self.chunks.entry((x, y, z)) {
+            Entry::Occupied(_) => Err(Error::ChunkAlreadyLoaded(x, y, z)),
+            Entry::Vacant(entry) => {
+                entry.insert(chunk);
+                Ok(())
+            }
         }
     }
 
-    pub fn iter<'a>(&'a self) -> Iter<'a, T> {
+    pub fn remove(&mut self, x: i32, y: i32, z: i32) -> Option<T> {
+        self.chunks.remove(&(x, y, z))
+    }
+
+    pub fn contains(&self, x: i32, y: i32, z: i32) -> bool {
+        self.chunks.contains_key(&(x, y, z))
+    }
+
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    pub fn iter(&self) -> Iter<'_, T> {
         Iter::new(self)
     }
 }
 
+impl<T> Default for ChunkArray<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 
 #[derive(Clone)]
 pub struct Iter<'a, T> {
@@ -20,6 +20,10 @@
 //! When there are multiple sets of coordinates passed to a function, it is okay to pass them as tuples
 //! and to call them `xyz_*`
 //!
+//! Where mixing up the coordinate frames is a concern, the `LocalPos`, `ChunkPos` and `GlobalPos` types
+//! can be used instead. Conversions between them are checked, so a `LocalPos` is always inside of a chunk
+//! and no conversion silently overflows.
+//!
 //! The 6 cardinal directions are referred to as such:
 //! - `X+` is _East_
 //! - `X-` is _West_
@@ -30,28 +34,447 @@
 
 //Uses
 use super::chunk::size::*;
+use std::ops::Add;
+
+/// One of the six cardinal directions, ordered East, West, Up, Down, North, South
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Direction {
+    East,
+    West,
+    Up,
+    Down,
+    North,
+    South,
+}
+
+impl Direction {
+    pub const ALL: [Direction; 6] = [
+        Direction::East,
+        Direction::West,
+        Direction::Up,
+        Direction::Down,
+        Direction::North,
+        Direction::South,
+    ];
+
+    /// The offset of one step in this direction
+    pub fn offset(self) -> (i32, i32, i32) {
+        match self {
+            Direction::East => (1, 0, 0),
+            Direction::West => (-1, 0, 0),
+            Direction::Up => (0, 1, 0),
+            Direction::Down => (0, -1, 0),
+            Direction::North => (0, 0, 1),
+            Direction::South => (0, 0, -1),
+        }
+    }
+
+    pub fn from_offset(offset: (i32, i32, i32)) -> Option<Direction> {
+        Direction::ALL
+            .into_iter()
+            .find(|direction| direction.offset() == offset)
+    }
+
+    pub fn opposite(self) -> Direction {
+        match self {
+            Direction::East => Direction::West,
+            Direction::West => Direction::East,
+            Direction::Up => Direction::Down,
+            Direction::Down => Direction::Up,
+            Direction::North => Direction::South,
+            Direction::South => Direction::North,
+        }
+    }
+
+    /// The axis this direction lies on, 0 for X, 1 for Y and 2 for Z
+    pub fn axis(self) -> usize {
+        match self {
+            Direction::East | Direction::West => 0,
+            Direction::Up | Direction::Down => 1,
+            Direction::North | Direction::South => 2,
+        }
+    }
+
+    pub fn is_positive(self) -> bool {
+        matches!(self, Direction::East | Direction::Up | Direction::North)
+    }
+
+    /// The position of this direction in `Direction::ALL`
+    pub fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Chunk-local voxel coordinates, always inside of the chunk
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct LocalPos {
+    x: u32,
+    y: u32,
+    z: u32,
+}
+
+impl LocalPos {
+    /// Returns `None` if the coordinates are outside of a chunk
+    pub fn new(x: u32, y: u32, z: u32) -> Option<LocalPos> {
+        if (x as usize) < CHUNK_SIZE_X && (y as usize) < CHUNK_SIZE_Y && (z as usize) < CHUNK_SIZE_Z
+        {
+            Some(LocalPos { x, y, z })
+        } else {
+            None
+        }
+    }
+
+    /// Converts an index into a voxel buffer back into coordinates, the inverse of `LocalPos::index`
+    pub fn from_index(i: usize) -> Option<LocalPos> {
+        if i >= CHUNK_SIZE_X * CHUNK_SIZE_Y * CHUNK_SIZE_Z {
+            return None;
+        }
+        Some(LocalPos {
+            x: (i % CHUNK_SIZE_X) as u32,
+            y: (i / CHUNK_SIZE_X % CHUNK_SIZE_Y) as u32,
+            z: (i / (CHUNK_SIZE_X * CHUNK_SIZE_Y)) as u32,
+        })
+    }
+
+    pub fn x(self) -> u32 {
+        self.x
+    }
+
+    pub fn y(self) -> u32 {
+        self.y
+    }
+
+    pub fn z(self) -> u32 {
+        self.z
+    }
+
+    pub fn to_tuple(self) -> (u32, u32, u32) {
+        (self.x, self.y, self.z)
+    }
+
+    /// The index of this voxel in a voxel buffer, matching `VoxelArray::get_voxel_index`
+    pub fn index(self) -> usize {
+        self.z as usize * (CHUNK_SIZE_X * CHUNK_SIZE_Y)
+            + self.y as usize * CHUNK_SIZE_X
+            + self.x as usize
+    }
+
+    /// Moves by the given offset, returns `None` if the result is outside of the chunk
+    pub fn checked_add(self, offset: (i32, i32, i32)) -> Option<LocalPos> {
+        LocalPos::new(
+            self.x.checked_add_signed(offset.0)?,
+            self.y.checked_add_signed(offset.1)?,
+            self.z.checked_add_signed(offset.2)?,
+        )
+    }
+
+    /// The neighboring voxel in the given direction, or `None` if it lies in another chunk
+    pub fn neighbor(self, direction: Direction) -> Option<LocalPos> {
+        self.checked_add(direction.offset())
+    }
+
+    /// Returns `None` if the global coordinates would overflow
+    pub fn to_global(self, chunk: ChunkPos) -> Option<GlobalPos> {
+        let origin = chunk.origin()?;
+        origin.checked_add((self.x as i32, self.y as i32, self.z as i32))
+    }
+
+    /// Iterates over every position in a chunk in voxel buffer order
+    pub fn iter_all() -> impl Iterator<Item = LocalPos> {
+        (0..CHUNK_SIZE_X * CHUNK_SIZE_Y * CHUNK_SIZE_Z).map(|i| LocalPos::from_index(i).unwrap())
+    }
+}
+
+/// Global chunk coordinates
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ChunkPos {
+    pub x: i32,
+    pub y: i32,
+    pub z: i32,
+}
+
+impl ChunkPos {
+    pub fn new(x: i32, y: i32, z: i32) -> ChunkPos {
+        ChunkPos { x, y, z }
+    }
+
+    pub fn to_tuple(self) -> (i32, i32, i32) {
+        (self.x, self.y, self.z)
+    }
+
+    /// The global coordinates of the negative corner of this chunk, or `None` if they would overflow
+    pub fn origin(self) -> Option<GlobalPos> {
+        Some(GlobalPos {
+            x: self.x.checked_mul(CHUNK_SIZE_X as i32)?,
+            y: self.y.checked_mul(CHUNK_SIZE_Y as i32)?,
+            z: self.z.checked_mul(CHUNK_SIZE_Z as i32)?,
+        })
+    }
+
+    pub fn checked_add(self, offset: (i32, i32, i32)) -> Option<ChunkPos> {
+        Some(ChunkPos {
+            x: self.x.checked_add(offset.0)?,
+            y: self.y.checked_add(offset.1)?,
+            z: self.z.checked_add(offset.2)?,
+        })
+    }
+
+    /// The neighboring chunk in the given direction, panics on overflow like integer addition
+    pub fn neighbor(self, direction: Direction) -> ChunkPos {
+        self + direction
+    }
+}
+
+impl From<(i32, i32, i32)> for ChunkPos {
+    fn from(xyz: (i32, i32, i32)) -> ChunkPos {
+        ChunkPos::new(xyz.0, xyz.1, xyz.2)
+    }
+}
+
+impl Add<Direction> for ChunkPos {
+    type Output = ChunkPos;
+
+    fn add(self, direction: Direction) -> ChunkPos {
+        let offset = direction.offset();
+        ChunkPos::new(self.x + offset.0, self.y + offset.1, self.z + offset.2)
+    }
+}
+
+/// Global voxel coordinates
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct GlobalPos {
+    pub x: i32,
+    pub y: i32,
+    pub z: i32,
+}
+
+impl GlobalPos {
+    pub fn new(x: i32, y: i32, z: i32) -> GlobalPos {
+        GlobalPos { x, y, z }
+    }
+
+    pub fn to_tuple(self) -> (i32, i32, i32) {
+        (self.x, self.y, self.z)
+    }
+
+    /// The chunk containing this voxel
+    pub fn chunk(self) -> ChunkPos {
+        ChunkPos {
+            x: self.x.div_euclid(CHUNK_SIZE_X as i32),
+            y: self.y.div_euclid(CHUNK_SIZE_Y as i32),
+            z: self.z.div_euclid(CHUNK_SIZE_Z as i32),
+        }
+    }
+
+    /// The position of this voxel within its chunk
+    pub fn local(self) -> LocalPos {
+        LocalPos {
+            x: self.x.rem_euclid(CHUNK_SIZE_X as i32) as u32,
+            y: self.y.rem_euclid(CHUNK_SIZE_Y as i32) as u32,
+            z: self.z.rem_euclid(CHUNK_SIZE_Z as i32) as u32,
+        }
+    }
+
+    /// Splits into the containing chunk and the position within it
+    pub fn split(self) -> (ChunkPos, LocalPos) {
+        (self.chunk(), self.local())
+    }
+
+    pub fn checked_add(self, offset: (i32, i32, i32)) -> Option<GlobalPos> {
+        Some(GlobalPos {
+            x: self.x.checked_add(offset.0)?,
+            y: self.y.checked_add(offset.1)?,
+            z: self.z.checked_add(offset.2)?,
+        })
+    }
+
+    /// The neighboring voxel in the given direction, panics on overflow like integer addition
+    pub fn neighbor(self, direction: Direction) -> GlobalPos {
+        self + direction
+    }
+}
+
+impl From<(i32, i32, i32)> for GlobalPos {
+    fn from(xyz: (i32, i32, i32)) -> GlobalPos {
+        GlobalPos::new(xyz.0, xyz.1, xyz.2)
+    }
+}
+
+impl Add<Direction> for GlobalPos {
+    type Output = GlobalPos;
+
+    fn add(self, direction: Direction) -> GlobalPos {
+        let offset = direction.offset();
+        GlobalPos::new(self.x + offset.0, self.y + offset.1, self.z + offset.2)
+    }
+}
 
 /// Takes a combination of local coordinates and chunk coordinates and returns the global coordinates for it
 pub fn local_to_global(xyz_local: (u32, u32, u32), xyz_chunk: (i32, i32, i32)) -> (i32, i32, i32) {
     (
         xyz_local.0 as i32 + xyz_chunk.0 * CHUNK_SIZE_X as i32,
-        xyz_local.1 as i32 + xyz_chunk.1 * CHUNK_SIZE_X as i32,
-        xyz_local.2 as i32 + xyz_chunk.2 * CHUNK_SIZE_X as i32,
+        xyz_local.1 as i32 + xyz_chunk.1 * CHUNK_SIZE_Y as i32,
+        xyz_local.2 as i32 + xyz_chunk.2 * CHUNK_SIZE_Z as i32,
     )
 }
 
+/// Takes global coordinates and splits them into local coordinates and the chunk coordinates of the containing chunk
 pub fn global_to_local(x: i32, y: i32, z: i32) -> ((u32, u32, u32), (i32, i32, i32)) {
-    let chunk_coords = (
-        x.div_euclid(CHUNK_SIZE_X as i32),
-        y.div_euclid(CHUNK_SIZE_Y as i32),
-        z.div_euclid(CHUNK_SIZE_Z as i32),
-    );
-
-    let local_coords = (
-        x.rem_euclid(CHUNK_SIZE_X as i32) as u32,
-        y.div_euclid(CHUNK_SIZE_Y as i32) as u32,
-        z.div_euclid(CHUNK_SIZE_Z as i32) as u32,
-    );
-
-    (local_coords, chunk_coords)
+    let (chunk, local) = GlobalPos::new(x, y, z).split();
+    (local.to_tuple(), chunk.to_tuple())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHUNK_SAMPLES: [i32; 9] = [
+        0,
+        1,
+        -1,
+        7,
+        -8,
+        i32::MAX / CHUNK_SIZE_X as i32,
+        i32::MIN / CHUNK_SIZE_X as i32,
+        i32::MAX / CHUNK_SIZE_X as i32 - 1,
+        i32::MIN / CHUNK_SIZE_X as i32 + 1,
+    ];
+
+    #[test]
+    fn local_and_chunk_round_trip_through_global() {
+        for &chunk_x in CHUNK_SAMPLES.iter() {
+            for &chunk_y in CHUNK_SAMPLES.iter() {
+                for &chunk_z in [0, -3, 5].iter() {
+                    let chunk = ChunkPos::new(chunk_x, chunk_y, chunk_z);
+                    for local in LocalPos::iter_all() {
+                        let global = local.to_global(chunk).unwrap();
+                        assert_eq!(global.split(), (chunk, local));
+                        assert_eq!(
+                            local_to_global(local.to_tuple(), chunk.to_tuple()),
+                            global.to_tuple()
+                        );
+                        assert_eq!(
+                            global_to_local(global.x, global.y, global.z),
+                            (local.to_tuple(), chunk.to_tuple())
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn negative_global_coordinates_split_on_every_axis() {
+        let size = CHUNK_SIZE_X as i32;
+        assert_eq!(global_to_local(-1, -1, -1), ((15, 15, 15), (-1, -1, -1)));
+        assert_eq!(
+            global_to_local(-size, -size, -size),
+            ((0, 0, 0), (-1, -1, -1))
+        );
+        assert_eq!(
+            global_to_local(-size - 1, -2, -size + 3),
+            ((15, 14, 3), (-2, -1, -1))
+        );
+        assert_eq!(global_to_local(5, -17, -33), ((5, 15, 15), (0, -2, -3)));
+        assert_eq!(
+            local_to_global((15, 14, 3), (-2, -1, -1)),
+            (-size - 1, -2, -size + 3)
+        );
+    }
+
+    #[test]
+    fn global_round_trips_through_local_and_chunk() {
+        let range = -2 * CHUNK_SIZE_X as i32..2 * CHUNK_SIZE_X as i32 + 1;
+        for x in range.clone() {
+            for y in range.clone() {
+                for z in range.clone() {
+                    let global = GlobalPos::new(x, y, z);
+                    let (chunk, local) = global.split();
+                    assert_eq!(local.to_global(chunk), Some(global));
+                }
+            }
+        }
+
+        for extreme in [i32::MIN, i32::MIN + 1, i32::MAX - 1, i32::MAX] {
+            let global = GlobalPos::new(extreme, extreme, extreme);
+            let (chunk, local) = global.split();
+            assert_eq!(local.to_global(chunk), Some(global));
+        }
+    }
+
+    #[test]
+    fn overflowing_conversions_are_rejected() {
+        let chunk = ChunkPos::new(i32::MAX, 0, 0);
+        assert_eq!(chunk.origin(), None);
+        assert_eq!(LocalPos::new(0, 0, 0).unwrap().to_global(chunk), None);
+        assert_eq!(GlobalPos::new(i32::MAX, 0, 0).checked_add((1, 0, 0)), None);
+        assert_eq!(ChunkPos::new(0, i32::MIN, 0).checked_add((0, -1, 0)), None);
+    }
+
+    #[test]
+    fn local_positions_are_checked() {
+        assert!(LocalPos::new(CHUNK_SIZE_X as u32, 0, 0).is_none());
+        assert!(LocalPos::new(0, CHUNK_SIZE_Y as u32, 0).is_none());
+        assert!(LocalPos::new(0, 0, CHUNK_SIZE_Z as u32).is_none());
+        assert!(LocalPos::from_index(CHUNK_SIZE_X * CHUNK_SIZE_Y * CHUNK_SIZE_Z).is_none());
+
+        for (i, local) in LocalPos::iter_all().enumerate() {
+            assert_eq!(local.index(), i);
+            assert_eq!(LocalPos::from_index(i), Some(local));
+            assert_eq!(LocalPos::new(local.x(), local.y(), local.z()), Some(local));
+        }
+    }
+
+    #[test]
+    fn directions_are_consistent() {
+        for (i, direction) in Direction::ALL.into_iter().enumerate() {
+            assert_eq!(direction.index(), i);
+            assert_eq!(direction.opposite().opposite(), direction);
+            assert_ne!(direction.opposite(), direction);
+            assert_eq!(Direction::from_offset(direction.offset()), Some(direction));
+
+            let offset = direction.offset();
+            let opposite_offset = direction.opposite().offset();
+            assert_eq!(
+                (
+                    offset.0 + opposite_offset.0,
+                    offset.1 + opposite_offset.1,
+                    offset.2 + opposite_offset.2
+                ),
+                (0, 0, 0)
+            );
+
+            let components = [offset.0, offset.1, offset.2];
+            let expected = if direction.is_positive() { 1 } else { -1 };
+            assert_eq!(components[direction.axis()], expected);
+            assert_eq!(components.iter().map(|c| c.abs()).sum::<i32>(), 1);
+        }
+        assert_eq!(Direction::from_offset((1, 1, 0)), None);
+    }
+
+    #[test]
+    fn neighbors_round_trip() {
+        for local in LocalPos::iter_all() {
+            let chunk = ChunkPos::new(-1, 0, 1);
+            let global = local.to_global(chunk).unwrap();
+            for direction in Direction::ALL {
+                let global_neighbor = global.neighbor(direction);
+                assert_eq!(global_neighbor.neighbor(direction.opposite()), global);
+
+                //Local neighbors only exist if they stay within the same chunk
+                match local.neighbor(direction) {
+                    Some(local_neighbor) => {
+                        assert_eq!(global_neighbor.split(), (chunk, local_neighbor));
+                        assert_eq!(local_neighbor.neighbor(direction.opposite()), Some(local));
+                    }
+                    None => {
+                        assert_eq!(
+                            global_neighbor.split(),
+                            (chunk.neighbor(direction), global_neighbor.local())
+                        );
+                    }
+                }
+            }
+        }
+    }
 }
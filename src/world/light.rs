@@ -0,0 +1,728 @@
+//! Sky light and block light
+//!
+//! Every loaded chunk has a `LightArray` holding two light levels from 0 to 15 per voxel.
+//! Sky light enters chunks from above. It travels straight down at full strength until a voxel blocks it,
+//! and spreads sideways and upwards like block light, so overhangs and cave mouths are lit dimly.
+//! Block light starts at voxels with an emission and spreads to neighboring voxels
+//! that let light through, losing one level per step.
+//!
+//! The top layer of a chunk gets sky light from the chunk above it if that is loaded.
+//! Otherwise it is only lit by the sky if the generator's `surface_height` puts it above the terrain.
+//! Without a generator that knows its heights, unloaded chunks count as dark, so chunks deep underground
+//! never light up and leak sky light into caves just because the chunks above them aren't loaded yet.
+//!
+//! Light is updated incrementally from the events of the `VoxelSystem`. Removed light is flooded out
+//! first, then light is spread again from every voxel bordering the dark area.
+//! Light freely crosses chunk borders, as long as both chunks are loaded.
+//! When a chunk is unloaded, light it spread into its neighbors stays until they change.
+
+//Uses
+use super::chunk::{size::*, ChunkArray};
+use super::coords::{ChunkPos, Direction, GlobalPos, LocalPos};
+use super::voxel::{self, AttributeRegistry, VoxelSystem};
+use std::any::type_name;
+use std::collections::{BTreeSet, VecDeque};
+use std::sync::Arc;
+
+pub const MAX_LIGHT: u8 = 15;
+
+/// How a voxel type interacts with light. Voxels without this attribute block light and don't emit any.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LightAttribute {
+    /// The block light level the voxel emits, from 0 to 15
+    pub emission: u8,
+    /// Whether light passes through the voxel
+    pub transparent: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct LightLevel {
+    pub sky: u8,
+    pub block: u8,
+}
+
+/// The light levels of every voxel in a chunk, in the same order as the voxels in a `VoxelArray`
+#[derive(Clone)]
+pub struct LightArray {
+    /// Sky light in the upper 4 bits, block light in the lower 4 bits
+    values: Box<[u8]>,
+}
+
+impl LightArray {
+    pub fn new() -> LightArray {
+        LightArray {
+            values: vec![0; CHUNK_SIZE_X * CHUNK_SIZE_Y * CHUNK_SIZE_Z].into_boxed_slice(),
+        }
+    }
+
+    pub fn get_light_at_index(&self, i: usize) -> LightLevel {
+        LightLevel {
+            sky: self.values[i] >> 4,
+            block: self.values[i] & 0xF,
+        }
+    }
+
+    pub fn get_light_at_position(&self, x: usize, y: usize, z: usize) -> LightLevel {
+        self.get_light_at_index(voxel::VoxelArray::get_voxel_index(x, y, z))
+    }
+
+    pub fn set_light_at_position(&mut self, x: usize, y: usize, z: usize, light: LightLevel) {
+        let i = voxel::VoxelArray::get_voxel_index(x, y, z);
+        self.set(Channel::Sky, i, light.sky.min(MAX_LIGHT));
+        self.set(Channel::Block, i, light.block.min(MAX_LIGHT));
+    }
+
+    fn get(&self, channel: Channel, i: usize) -> u8 {
+        match channel {
+            Channel::Sky => self.values[i] >> 4,
+            Channel::Block => self.values[i] & 0xF,
+        }
+    }
+
+    fn set(&mut self, channel: Channel, i: usize, level: u8) {
+        self.values[i] = match channel {
+            Channel::Sky => (self.values[i] & 0x0F) | (level << 4),
+            Channel::Block => (self.values[i] & 0xF0) | level,
+        };
+    }
+}
+
+impl Default for LightArray {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub enum Event {
+    /// Light levels that the chunk's mesh depends on have changed.
+    /// This includes light changes in neighboring chunks right at the border.
+    LightChanged {
+        coords_x: i32,
+        coords_y: i32,
+        coords_z: i32,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Channel {
+    Sky,
+    Block,
+}
+
+const CHANNELS: [Channel; 2] = [Channel::Sky, Channel::Block];
+
+pub struct LightSystem {
+    chunks: ChunkArray<LightArray>,
+    recorded_events: Vec<Event>,
+}
+
+impl LightSystem {
+    pub fn new() -> LightSystem {
+        LightSystem {
+            chunks: ChunkArray::new(),
+            recorded_events: Vec::new(),
+        }
+    }
+
+    pub fn get_chunk(&self, x: i32, y: i32, z: i32) -> Option<&LightArray> {
+        self.chunks.get(x, y, z)
+    }
+
+    /// Returns the light at the given global coordinates, or `None` if its chunk is not loaded
+    pub fn get_light(&self, x: i32, y: i32, z: i32) -> Option<LightLevel> {
+        let (chunk, local) = GlobalPos::new(x, y, z).split();
+        Some(
+            self.chunks
+                .get(chunk.x, chunk.y, chunk.z)?
+                .get_light_at_index(local.index()),
+        )
+    }
+
+    pub fn reset_events(&mut self) {
+        self.recorded_events.clear();
+    }
+
+    pub fn get_events(&self) -> &Vec<Event> {
+        &self.recorded_events
+    }
+
+    /// Updates light for the events the voxel system has recorded since its events were last reset.
+    /// Voxels with a `LightAttribute` registered are lit according to it, every other voxel is opaque.
+    pub fn update(&mut self, voxel_system: &VoxelSystem) -> Result<(), voxel::Error> {
+        let attributes = voxel_system
+            .get_attribute_registry::<LightAttribute>()
+            .ok_or(voxel::Error::RegistryMissing(type_name::<LightAttribute>()))?;
+        let mut propagator = Propagator {
+            voxels: voxel_system,
+            attributes,
+            chunks: &mut self.chunks,
+            changed: BTreeSet::new(),
+            add_queue: VecDeque::new(),
+            remove_queue: VecDeque::new(),
+        };
+
+        for event in voxel_system.get_events() {
+            match event {
+                voxel::Event::ChunkLoaded {
+                    coords_x,
+                    coords_y,
+                    coords_z,
+                } => propagator.load_chunk(*coords_x, *coords_y, *coords_z),
+                voxel::Event::ChunkUnloaded {
+                    coords_x,
+                    coords_y,
+                    coords_z,
+                } => propagator.unload_chunk(*coords_x, *coords_y, *coords_z),
+                voxel::Event::VoxelChanged {
+                    global_x,
+                    global_y,
+                    global_z,
+                    ..
+                } => propagator.relight_voxel(GlobalPos::new(*global_x, *global_y, *global_z)),
+            }
+        }
+        propagator.propagate();
+
+        for (x, y, z) in propagator.changed {
+            if self.chunks.contains(x, y, z) {
+                self.recorded_events.push(Event::LightChanged {
+                    coords_x: x,
+                    coords_y: y,
+                    coords_z: z,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for LightSystem {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Propagator<'a> {
+    voxels: &'a VoxelSystem,
+    attributes: Arc<AttributeRegistry<LightAttribute>>,
+    chunks: &'a mut ChunkArray<LightArray>,
+    changed: BTreeSet<(i32, i32, i32)>,
+    /// Voxels whose light has to be spread to their neighbors
+    add_queue: VecDeque<(Channel, GlobalPos)>,
+    /// Voxels that were darkened, with the level they had
+    remove_queue: VecDeque<(Channel, GlobalPos, u8)>,
+}
+
+impl<'a> Propagator<'a> {
+    fn attribute(&self, pos: GlobalPos) -> Option<LightAttribute> {
+        let voxel = self.voxels.get_voxel(pos.x, pos.y, pos.z)?;
+        Some(
+            self.attributes
+                .find(voxel.id)
+                .copied()
+                .unwrap_or(LightAttribute {
+                    emission: 0,
+                    transparent: false,
+                }),
+        )
+    }
+
+    fn is_transparent(&self, pos: GlobalPos) -> bool {
+        self.attribute(pos)
+            .is_some_and(|attribute| attribute.transparent)
+    }
+
+    fn get(&self, channel: Channel, pos: GlobalPos) -> Option<u8> {
+        let (chunk, local) = pos.split();
+        Some(
+            self.chunks
+                .get(chunk.x, chunk.y, chunk.z)?
+                .get(channel, local.index()),
+        )
+    }
+
+    fn set(&mut self, channel: Channel, pos: GlobalPos, level: u8) {
+        let (chunk, local) = pos.split();
+        let light = match self.chunks.get_mut(chunk.x, chunk.y, chunk.z) {
+            Some(light) => light,
+            None => return,
+        };
+        light.set(channel, local.index(), level);
+
+        //Meshes of neighboring chunks sample the light of voxels at the border
+        self.changed.insert(chunk.to_tuple());
+        let (local_x, local_y, local_z) = local.to_tuple();
+        let borders = [
+            (local_x == CHUNK_SIZE_X as u32 - 1, Direction::East),
+            (local_x == 0, Direction::West),
+            (local_y == CHUNK_SIZE_Y as u32 - 1, Direction::Up),
+            (local_y == 0, Direction::Down),
+            (local_z == CHUNK_SIZE_Z as u32 - 1, Direction::North),
+            (local_z == 0, Direction::South),
+        ];
+        for (at_border, direction) in borders {
+            if at_border {
+                if let Some(neighbor) = chunk.checked_add(direction.offset()) {
+                    self.changed.insert(neighbor.to_tuple());
+                }
+            }
+        }
+    }
+
+    /// The light level a voxel has on its own, regardless of its neighbors
+    fn source_level(&self, channel: Channel, pos: GlobalPos) -> u8 {
+        let attribute = match self.attribute(pos) {
+            Some(attribute) => attribute,
+            None => return 0,
+        };
+        match channel {
+            Channel::Block => attribute.emission.min(MAX_LIGHT),
+            Channel::Sky => {
+                let (chunk, local) = pos.split();
+                let sky_above = local.y() == CHUNK_SIZE_Y as u32 - 1
+                    && chunk
+                        .checked_add(Direction::Up.offset())
+                        .is_none_or(|above| !self.chunks.contains(above.x, above.y, above.z))
+                    && self.above_surface(pos);
+                if sky_above && attribute.transparent {
+                    MAX_LIGHT
+                } else {
+                    0
+                }
+            }
+        }
+    }
+
+    /// Whether the generator puts the voxel above the terrain, `false` if there is no surface height to go by
+    fn above_surface(&self, pos: GlobalPos) -> bool {
+        self.voxels
+            .generator()
+            .and_then(|generator| {
+                generator.surface_height(pos.x as i64, pos.z as i64, self.voxels.seed())
+            })
+            .is_some_and(|height| pos.y as i64 > height)
+    }
+
+    /// Darkens a voxel and lights it again from its own source and its neighbors
+    fn relight_voxel(&mut self, pos: GlobalPos) {
+        for channel in CHANNELS {
+            let old_level = match self.get(channel, pos) {
+                Some(level) => level,
+                None => return,
+            };
+            self.set(channel, pos, 0);
+            if old_level > 0 {
+                self.remove_queue.push_back((channel, pos, old_level));
+            }
+            self.reseed(channel, pos);
+
+            if self.is_transparent(pos) {
+                for direction in Direction::ALL {
+                    if let Some(neighbor) = pos.checked_add(direction.offset()) {
+                        self.add_queue.push_back((channel, neighbor));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sets a voxel to its source level if it has one
+    fn reseed(&mut self, channel: Channel, pos: GlobalPos) {
+        let source_level = self.source_level(channel, pos);
+        if source_level > 0 {
+            self.set(channel, pos, source_level);
+            self.add_queue.push_back((channel, pos));
+        }
+    }
+
+    fn load_chunk(&mut self, x: i32, y: i32, z: i32) {
+        if self.voxels.get_chunk(x, y, z).is_none() || self.chunks.contains(x, y, z) {
+            return;
+        }
+        self.chunks.add(LightArray::new(), x, y, z);
+        self.changed.insert((x, y, z));
+
+        let chunk = ChunkPos::new(x, y, z);
+        let chunk_voxels: Vec<GlobalPos> = LocalPos::iter_all()
+            .filter_map(|local| local.to_global(chunk))
+            .collect();
+        for pos in chunk_voxels.iter().copied() {
+            for channel in CHANNELS {
+                self.reseed(channel, pos);
+            }
+        }
+
+        //Pull in light from the borders of loaded neighbors
+        for pos in chunk_voxels.iter().copied() {
+            for direction in Direction::ALL {
+                let neighbor = match pos.checked_add(direction.offset()) {
+                    Some(neighbor) if neighbor.chunk() != chunk => neighbor,
+                    _ => continue,
+                };
+                for channel in CHANNELS {
+                    self.add_queue.push_back((channel, neighbor));
+                }
+            }
+        }
+
+        //The chunk below may have been lit by the sky until now, its top layer has to be lit from this chunk instead
+        for pos in top_layer_below(chunk) {
+            if let Some(level) = self.get(Channel::Sky, pos) {
+                self.set(Channel::Sky, pos, 0);
+                if level > 0 {
+                    self.remove_queue.push_back((Channel::Sky, pos, level));
+                }
+            }
+        }
+    }
+
+    fn unload_chunk(&mut self, x: i32, y: i32, z: i32) {
+        if self.chunks.remove(x, y, z).is_none() {
+            return;
+        }
+
+        //The top layer of the chunk below goes back to being lit by the surface height
+        for pos in top_layer_below(ChunkPos::new(x, y, z)) {
+            if self.get(Channel::Sky, pos).is_some() {
+                self.reseed(Channel::Sky, pos);
+            }
+        }
+    }
+
+    /// Floods out removed light, then spreads light from the queued voxels
+    fn propagate(&mut self) {
+        while let Some((channel, pos, level)) = self.remove_queue.pop_front() {
+            for direction in Direction::ALL {
+                let neighbor = match pos.checked_add(direction.offset()) {
+                    Some(neighbor) => neighbor,
+                    None => continue,
+                };
+                let neighbor_level = match self.get(channel, neighbor) {
+                    Some(neighbor_level) if neighbor_level > 0 => neighbor_level,
+                    _ => continue,
+                };
+
+                let lit_by_pos = neighbor_level < level
+                    || (is_unattenuated(channel, direction, level) && neighbor_level == MAX_LIGHT);
+                if lit_by_pos {
+                    self.set(channel, neighbor, 0);
+                    self.remove_queue
+                        .push_back((channel, neighbor, neighbor_level));
+                    self.reseed(channel, neighbor);
+                } else {
+                    //The neighbor is lit from somewhere else and can light the darkened area again
+                    self.add_queue.push_back((channel, neighbor));
+                }
+            }
+        }
+
+        while let Some((channel, pos)) = self.add_queue.pop_front() {
+            let level = match self.get(channel, pos) {
+                Some(level) if level > 0 => level,
+                _ => continue,
+            };
+            for direction in Direction::ALL {
+                let neighbor_level = if is_unattenuated(channel, direction, level) {
+                    level
+                } else if level > 1 {
+                    level - 1
+                } else {
+                    continue;
+                };
+                let neighbor = match pos.checked_add(direction.offset()) {
+                    Some(neighbor) => neighbor,
+                    None => continue,
+                };
+                match self.get(channel, neighbor) {
+                    Some(current) if current < neighbor_level && self.is_transparent(neighbor) => {
+                        self.set(channel, neighbor, neighbor_level);
+                        self.add_queue.push_back((channel, neighbor));
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+/// Full sky light travels straight down without getting weaker, in every other direction it loses a level
+fn is_unattenuated(channel: Channel, direction: Direction, level: u8) -> bool {
+    channel == Channel::Sky && direction == Direction::Down && level == MAX_LIGHT
+}
+
+/// The topmost layer of voxels in the chunk below the given one
+fn top_layer_below(chunk: ChunkPos) -> Vec<GlobalPos> {
+    let below = match chunk.checked_add(Direction::Down.offset()) {
+        Some(below) => below,
+        None => return Vec::new(),
+    };
+    LocalPos::iter_all()
+        .filter(|local| local.y() == CHUNK_SIZE_Y as u32 - 1)
+        .filter_map(|local| local.to_global(below))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::test_util::{self, attributes, AIR, STONE};
+    use crate::world::voxel::{ChunkGenerator, Voxel, VoxelArray};
+
+    const LAMP: Voxel = Voxel { id: 2, data: 0 };
+
+    /// Stone up to the surface height and air above it, or only air if the surface is unknown
+    struct FlatGenerator {
+        surface: Option<i64>,
+    }
+
+    impl ChunkGenerator for FlatGenerator {
+        fn generate(&self, _x: i32, y: i32, _z: i32, _seed: u64) -> VoxelArray {
+            let mut voxels = VoxelArray::new(AIR);
+            for local_y in 0..CHUNK_SIZE_Y {
+                let global_y = y as i64 * CHUNK_SIZE_Y as i64 + local_y as i64;
+                if self.surface.is_none_or(|surface| global_y > surface) {
+                    continue;
+                }
+                for x in 0..CHUNK_SIZE_X {
+                    for z in 0..CHUNK_SIZE_Z {
+                        voxels.set_voxel_at_position(x, local_y, z, STONE);
+                    }
+                }
+            }
+            voxels
+        }
+
+        fn surface_height(&self, _x: i64, _z: i64, _seed: u64) -> Option<i64> {
+            self.surface
+        }
+    }
+
+    /// A voxel system whose generator puts the surface far below the chunks the tests load
+    fn voxel_system() -> VoxelSystem {
+        voxel_system_with_surface(Some(-100))
+    }
+
+    fn voxel_system_with_surface(surface: Option<i64>) -> VoxelSystem {
+        let lights = vec![
+            (
+                AIR,
+                LightAttribute {
+                    emission: 0,
+                    transparent: true,
+                },
+            ),
+            (
+                LAMP,
+                LightAttribute {
+                    emission: 14,
+                    transparent: false,
+                },
+            ),
+        ];
+        let mut voxels = test_util::voxel_system(attributes("light", lights), &[]);
+        voxels.set_generator(Arc::new(FlatGenerator { surface }), 0);
+        voxels
+    }
+
+    fn update(voxels: &mut VoxelSystem, light: &mut LightSystem) {
+        light.reset_events();
+        light.update(voxels).unwrap();
+        voxels.reset_events();
+    }
+
+    fn sky(light: &LightSystem, x: i32, y: i32, z: i32) -> u8 {
+        light.get_light(x, y, z).unwrap().sky
+    }
+
+    fn block(light: &LightSystem, x: i32, y: i32, z: i32) -> u8 {
+        light.get_light(x, y, z).unwrap().block
+    }
+
+    fn changed_chunks(light: &LightSystem) -> Vec<(i32, i32, i32)> {
+        light
+            .get_events()
+            .iter()
+            .map(
+                |Event::LightChanged {
+                     coords_x,
+                     coords_y,
+                     coords_z,
+                 }| { (*coords_x, *coords_y, *coords_z) },
+            )
+            .collect()
+    }
+
+    /// Loads an air chunk with a stone chunk above it, so that it only gets block light
+    fn load_covered(voxels: &mut VoxelSystem, x: i32, y: i32, z: i32) {
+        voxels
+            .load_chunk(VoxelArray::new(STONE), x, y + 1, z)
+            .unwrap();
+        voxels.load_chunk(VoxelArray::new(AIR), x, y, z).unwrap();
+    }
+
+    #[test]
+    fn placing_and_removing_an_emitter() {
+        let (mut voxels, mut light) = (voxel_system(), LightSystem::new());
+        load_covered(&mut voxels, 0, 0, 0);
+        update(&mut voxels, &mut light);
+        assert_eq!(block(&light, 8, 8, 8), 0);
+
+        voxels.set_voxel(8, 8, 8, LAMP).unwrap();
+        update(&mut voxels, &mut light);
+        assert_eq!(block(&light, 8, 8, 8), 14);
+        assert_eq!(block(&light, 9, 8, 8), 13);
+        assert_eq!(block(&light, 10, 10, 8), 10);
+        assert_eq!(block(&light, 0, 0, 0), 0);
+        //Light doesn't enter opaque voxels
+        assert_eq!(block(&light, 8, 16, 8), 0);
+        assert_eq!(sky(&light, 8, 8, 8), 0);
+        //The chunk above samples the light at its bottom border
+        assert_eq!(changed_chunks(&light), vec![(0, 0, 0), (0, 1, 0)]);
+
+        voxels.set_voxel(8, 8, 8, AIR).unwrap();
+        update(&mut voxels, &mut light);
+        for (x, y, z) in [(8, 8, 8), (9, 8, 8), (10, 10, 8), (15, 15, 15)] {
+            assert_eq!(block(&light, x, y, z), 0);
+        }
+    }
+
+    #[test]
+    fn light_crosses_chunk_seams() {
+        let (mut voxels, mut light) = (voxel_system(), LightSystem::new());
+        load_covered(&mut voxels, -1, 0, 0);
+        load_covered(&mut voxels, 0, 0, 0);
+        update(&mut voxels, &mut light);
+
+        voxels.set_voxel(-1, 4, 4, LAMP).unwrap();
+        update(&mut voxels, &mut light);
+        assert_eq!(block(&light, 0, 4, 4), 13);
+        assert_eq!(block(&light, 5, 4, 4), 8);
+        assert_eq!(
+            changed_chunks(&light),
+            vec![(-1, 0, 0), (-1, 1, 0), (0, 0, 0), (0, 1, 0)]
+        );
+
+        //A chunk loaded next to a lit one pulls in its light
+        voxels.set_voxel(-1, 4, 4, AIR).unwrap();
+        voxels.set_voxel(15, 4, 4, LAMP).unwrap();
+        update(&mut voxels, &mut light);
+        assert_eq!(block(&light, 0, 4, 4), 0);
+        load_covered(&mut voxels, 1, 0, 0);
+        update(&mut voxels, &mut light);
+        assert_eq!(block(&light, 16, 4, 4), 13);
+        assert_eq!(block(&light, 20, 4, 4), 9);
+
+        //Removing the emitter darkens both chunks
+        voxels.set_voxel(15, 4, 4, AIR).unwrap();
+        update(&mut voxels, &mut light);
+        assert_eq!(block(&light, 16, 4, 4), 0);
+        assert_eq!(block(&light, 14, 4, 4), 0);
+    }
+
+    #[test]
+    fn sky_light_goes_down_and_spreads_sideways() {
+        let (mut voxels, mut light) = (voxel_system(), LightSystem::new());
+        //A roof over the whole chunk, except one hole
+        let mut roofed = VoxelArray::new(AIR);
+        for x in 0..CHUNK_SIZE_X {
+            for z in 0..CHUNK_SIZE_Z {
+                if (x, z) != (4, 4) {
+                    roofed.set_voxel_at_position(x, CHUNK_SIZE_Y - 1, z, STONE);
+                }
+            }
+        }
+        voxels.load_chunk(roofed, 0, 0, 0).unwrap();
+        voxels.load_chunk(VoxelArray::new(AIR), 0, -1, 0).unwrap();
+        update(&mut voxels, &mut light);
+
+        assert_eq!(sky(&light, 4, 15, 4), MAX_LIGHT);
+        assert_eq!(sky(&light, 4, -16, 4), MAX_LIGHT);
+        //Below the roof, voxels next to the open column are lit one level less
+        assert_eq!(sky(&light, 5, 0, 4), 14);
+        assert_eq!(sky(&light, 4, 14, 3), 14);
+        assert_eq!(sky(&light, 3, -16, 4), 14);
+        assert_eq!(sky(&light, 12, 0, 4), 7);
+        assert_eq!(sky(&light, 15, 0, 15), 0);
+        assert_eq!(sky(&light, 5, 15, 4), 0);
+    }
+
+    #[test]
+    fn placing_and_removing_a_blocker_under_the_sky() {
+        let (mut voxels, mut light) = (voxel_system(), LightSystem::new());
+        voxels.load_chunk(VoxelArray::new(AIR), 0, 0, 0).unwrap();
+        voxels.load_chunk(VoxelArray::new(AIR), 0, -1, 0).unwrap();
+        update(&mut voxels, &mut light);
+        assert_eq!(sky(&light, 5, -16, 5), MAX_LIGHT);
+
+        voxels.set_voxel(5, 10, 5, STONE).unwrap();
+        update(&mut voxels, &mut light);
+        assert_eq!(sky(&light, 5, 11, 5), MAX_LIGHT);
+        assert_eq!(sky(&light, 5, 10, 5), 0);
+        //The shadow below the blocker is lit from the open columns around it
+        for y in [9, 0, -1, -16] {
+            assert_eq!(sky(&light, 5, y, 5), 14);
+        }
+        assert_eq!(sky(&light, 6, 9, 5), MAX_LIGHT);
+        assert_eq!(changed_chunks(&light), vec![(0, -1, 0), (0, 0, 0)]);
+
+        voxels.set_voxel(5, 10, 5, AIR).unwrap();
+        update(&mut voxels, &mut light);
+        for y in [10, 9, 0, -1, -16] {
+            assert_eq!(sky(&light, 5, y, 5), MAX_LIGHT);
+        }
+    }
+
+    #[test]
+    fn loading_a_chunk_above_a_lit_chunk() {
+        let (mut voxels, mut light) = (voxel_system(), LightSystem::new());
+        voxels.load_chunk(VoxelArray::new(AIR), 0, 0, 0).unwrap();
+        update(&mut voxels, &mut light);
+        //Nothing is known above the chunk, so it is lit by the sky
+        assert_eq!(sky(&light, 3, 0, 3), MAX_LIGHT);
+
+        voxels.load_chunk(VoxelArray::new(STONE), 0, 1, 0).unwrap();
+        update(&mut voxels, &mut light);
+        assert_eq!(sky(&light, 3, 15, 3), 0);
+        assert_eq!(sky(&light, 3, 0, 3), 0);
+        assert!(changed_chunks(&light).contains(&(0, 0, 0)));
+
+        //Unloading the chunk above lets the sky back in
+        voxels.unload_chunk(0, 1, 0).unwrap();
+        update(&mut voxels, &mut light);
+        assert_eq!(sky(&light, 3, 0, 3), MAX_LIGHT);
+
+        //Sky light passes through a transparent chunk above
+        voxels.load_chunk(VoxelArray::new(AIR), 0, 1, 0).unwrap();
+        update(&mut voxels, &mut light);
+        assert_eq!(sky(&light, 3, 31, 3), MAX_LIGHT);
+        assert_eq!(sky(&light, 3, 0, 3), MAX_LIGHT);
+    }
+
+    #[test]
+    fn unloaded_chunks_below_the_surface_are_dark() {
+        //The surface is above the chunk, so the unloaded chunk above it is solid ground
+        let (mut voxels, mut light) = (voxel_system_with_surface(Some(40)), LightSystem::new());
+        voxels.load_chunk(VoxelArray::new(AIR), 0, 0, 0).unwrap();
+        voxels.load_chunk(VoxelArray::new(AIR), 1, 0, 0).unwrap();
+        update(&mut voxels, &mut light);
+        for (x, y, z) in [(3, 15, 3), (3, 0, 3), (20, 8, 8)] {
+            assert_eq!(sky(&light, x, y, z), 0);
+        }
+
+        //Without a surface height, nothing says the chunk is under the open sky
+        let (mut voxels, mut light) = (voxel_system_with_surface(None), LightSystem::new());
+        voxels.load_chunk(VoxelArray::new(AIR), 0, 0, 0).unwrap();
+        update(&mut voxels, &mut light);
+        assert_eq!(sky(&light, 3, 15, 3), 0);
+
+        //Once the chunk above is loaded it decides, here it is open to the sky
+        let (mut voxels, mut light) = (voxel_system_with_surface(Some(15)), LightSystem::new());
+        voxels.load_chunk(VoxelArray::new(AIR), 0, 0, 0).unwrap();
+        update(&mut voxels, &mut light);
+        assert_eq!(sky(&light, 3, 15, 3), 0);
+        voxels.load_chunk(VoxelArray::new(AIR), 0, 1, 0).unwrap();
+        update(&mut voxels, &mut light);
+        assert_eq!(sky(&light, 3, 31, 3), MAX_LIGHT);
+        assert_eq!(sky(&light, 3, 0, 3), MAX_LIGHT);
+    }
+}
@@ -7,13 +7,19 @@
 //Modules
 pub mod chunk;
 pub mod coords;
+pub mod light;
+pub mod physics;
+#[cfg(test)]
+pub(crate) mod test_util;
 pub mod voxel;
 
 //Uses
 use crate::render::RenderSystem;
+use light::LightSystem;
 use voxel::VoxelSystem;
 
 pub struct CoreSystems {
     pub voxel: VoxelSystem,
+    pub light: LightSystem,
     pub render: Option<RenderSystem>,
 }
\ No newline at end of file
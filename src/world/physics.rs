@@ -0,0 +1,318 @@
+//! Collision of axis-aligned boxes with the voxel world
+//!
+//! Boxes are moved one axis at a time, Y first and then X and Z. On every axis the box is swept
+//! through the layers of voxels in front of it and stops at the first solid one, so fast movement
+//! can't tunnel through thin walls. Moving one axis at a time lets boxes slide along walls and floors.
+//!
+//! Whether a voxel is solid is decided by its `CollisionAttribute`. Voxels without the attribute
+//! and voxels in chunks that are not loaded are solid, so nothing falls out of the world
+//! while chunks are still loading.
+
+//Uses
+use super::voxel::{AttributeRegistry, Error, VoxelSystem};
+use cgmath::{Point3, Vector3};
+use std::any::type_name;
+
+/// Keeps positions that touch a voxel boundary from counting as overlapping the voxel
+const EPSILON: f64 = 1e-6;
+
+/// How moving boxes collide with a voxel type
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CollisionAttribute {
+    Solid,
+    Passable,
+}
+
+/// An axis-aligned box in global voxel coordinates
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Point3<f32>,
+    pub max: Point3<f32>,
+}
+
+impl Aabb {
+    pub fn new(min: Point3<f32>, max: Point3<f32>) -> Aabb {
+        Aabb { min, max }
+    }
+
+    /// Creates a box of the given size whose bottom face is centered on `position`, like a standing entity
+    pub fn from_feet(position: Point3<f32>, width: f32, height: f32) -> Aabb {
+        let half_width = width / 2.0;
+        Aabb {
+            min: Point3::new(position.x - half_width, position.y, position.z - half_width),
+            max: Point3::new(
+                position.x + half_width,
+                position.y + height,
+                position.z + half_width,
+            ),
+        }
+    }
+
+    pub fn size(&self) -> Vector3<f32> {
+        self.max - self.min
+    }
+
+    pub fn center(&self) -> Point3<f32> {
+        self.min + self.size() / 2.0
+    }
+
+    pub fn translated(&self, offset: Vector3<f32>) -> Aabb {
+        Aabb {
+            min: self.min + offset,
+            max: self.max + offset,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MoveResult {
+    /// The box after moving
+    pub aabb: Aabb,
+    /// The movement that was actually applied
+    pub movement: Vector3<f32>,
+    /// The box was stopped while moving down
+    pub on_ground: bool,
+    /// The box was stopped while moving up
+    pub hit_ceiling: bool,
+    /// The box was stopped while moving along X or Z
+    pub hit_wall: bool,
+}
+
+/// Moves a box through the world, stopping it at solid voxels
+pub fn move_aabb(
+    voxels: &VoxelSystem,
+    aabb: Aabb,
+    movement: Vector3<f32>,
+) -> Result<MoveResult, Error> {
+    let registry = voxels
+        .get_attribute_registry::<CollisionAttribute>()
+        .ok_or(Error::RegistryMissing(type_name::<CollisionAttribute>()))?;
+    let is_solid = |x: i32, y: i32, z: i32| is_voxel_solid(voxels, &registry, x, y, z);
+
+    let mut min = [aabb.min.x as f64, aabb.min.y as f64, aabb.min.z as f64];
+    let mut max = [aabb.max.x as f64, aabb.max.y as f64, aabb.max.z as f64];
+    let wanted = [movement.x as f64, movement.y as f64, movement.z as f64];
+    let mut applied = [0.0; 3];
+    let mut blocked = [false; 3];
+
+    for axis in [1, 0, 2] {
+        if wanted[axis] == 0.0 {
+            continue;
+        }
+        let distance = sweep_axis(&min, &max, axis, wanted[axis], &is_solid);
+        blocked[axis] = distance != wanted[axis];
+        applied[axis] = distance;
+        min[axis] += distance;
+        max[axis] += distance;
+    }
+
+    let movement = Vector3::new(applied[0] as f32, applied[1] as f32, applied[2] as f32);
+    Ok(MoveResult {
+        aabb: aabb.translated(movement),
+        movement,
+        on_ground: blocked[1] && wanted[1] < 0.0,
+        hit_ceiling: blocked[1] && wanted[1] > 0.0,
+        hit_wall: blocked[0] || blocked[2],
+    })
+}
+
+fn is_voxel_solid(
+    voxels: &VoxelSystem,
+    registry: &AttributeRegistry<CollisionAttribute>,
+    x: i32,
+    y: i32,
+    z: i32,
+) -> bool {
+    match voxels.get_voxel(x, y, z) {
+        Some(voxel) => !matches!(registry.find(voxel.id), Ok(CollisionAttribute::Passable)),
+        None => true,
+    }
+}
+
+/// The range of voxels the box overlaps on an axis
+fn cell_range(min: [f64; 3], max: [f64; 3], axis: usize) -> (i64, i64) {
+    (
+        (min[axis] + EPSILON).floor() as i64,
+        (max[axis] - EPSILON).floor() as i64,
+    )
+}
+
+/// Returns how far the box can move along the axis, up to `distance`
+fn sweep_axis<F>(min: &[f64; 3], max: &[f64; 3], axis: usize, distance: f64, is_solid: &F) -> f64
+where
+    F: Fn(i32, i32, i32) -> bool,
+{
+    let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+    let (u_range, v_range) = (cell_range(*min, *max, u), cell_range(*min, *max, v));
+
+    //The layers of voxels the leading face passes through, in the order they are reached
+    let (first_layer, last_layer, step) = if distance > 0.0 {
+        (
+            (max[axis] - EPSILON).floor() as i64 + 1,
+            (max[axis] + distance - EPSILON).floor() as i64,
+            1,
+        )
+    } else {
+        (
+            (min[axis] + EPSILON).floor() as i64 - 1,
+            (min[axis] + distance + EPSILON).floor() as i64,
+            -1,
+        )
+    };
+
+    let mut layer = first_layer;
+    while (step > 0 && layer <= last_layer) || (step < 0 && layer >= last_layer) {
+        for cell_u in u_range.0..=u_range.1 {
+            for cell_v in v_range.0..=v_range.1 {
+                let mut cell = [0; 3];
+                cell[axis] = layer;
+                cell[u] = cell_u;
+                cell[v] = cell_v;
+                let in_range = cell
+                    .iter()
+                    .all(|c| (i32::MIN as i64..=i32::MAX as i64).contains(c));
+                if !in_range || is_solid(cell[0] as i32, cell[1] as i32, cell[2] as i32) {
+                    //Stop flush against the solid layer, never moving backwards
+                    return if step > 0 {
+                        (layer as f64 - max[axis]).max(0.0)
+                    } else {
+                        (layer as f64 + 1.0 - min[axis]).min(0.0)
+                    };
+                }
+            }
+        }
+        layer += step;
+    }
+    distance
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::test_util::{attributes, voxel_system, AIR, STONE};
+
+    /// Stone below y = 0 and air above it, in chunks from -1 to 0 on x and z
+    fn world() -> VoxelSystem {
+        let collision = vec![
+            (AIR, CollisionAttribute::Passable),
+            (STONE, CollisionAttribute::Solid),
+        ];
+        voxel_system(
+            attributes("collision", collision),
+            &[
+                ((-1, -1, -1), (0, -1, 0), STONE),
+                ((-1, 0, -1), (0, 0, 0), AIR),
+            ],
+        )
+    }
+
+    fn player(x: f32, y: f32, z: f32) -> Aabb {
+        Aabb::from_feet(Point3::new(x, y, z), 0.6, 1.8)
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-4,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn falling_boxes_land_on_the_ground() {
+        let system = world();
+        let result = move_aabb(
+            &system,
+            player(-3.5, 5.0, -3.5),
+            Vector3::new(0.0, -50.0, 0.0),
+        )
+        .unwrap();
+        assert_close(result.movement.y, -5.0);
+        assert_close(result.aabb.min.y, 0.0);
+        assert!(result.on_ground && !result.hit_ceiling && !result.hit_wall);
+
+        //Standing on the ground keeps the box there
+        let result = move_aabb(&system, result.aabb, Vector3::new(0.0, -0.1, 0.0)).unwrap();
+        assert_eq!(result.movement.y, 0.0);
+        assert!(result.on_ground);
+
+        let result = move_aabb(
+            &system,
+            player(-3.5, 5.0, -3.5),
+            Vector3::new(0.0, -4.0, 0.0),
+        )
+        .unwrap();
+        assert_close(result.movement.y, -4.0);
+        assert!(!result.on_ground);
+    }
+
+    #[test]
+    fn boxes_collide_across_chunk_borders() {
+        let mut system = world();
+        system.set_voxel(-1, 0, -1, STONE).unwrap();
+        system.set_voxel(-1, 4, -1, STONE).unwrap();
+
+        //The box overlaps four chunks and only the corner in chunk (-1, 0, -1) has a pillar
+        let result =
+            move_aabb(&system, player(0.0, 2.0, 0.0), Vector3::new(0.0, -5.0, 0.0)).unwrap();
+        assert_close(result.aabb.min.y, 1.0);
+        assert!(result.on_ground);
+
+        let result = move_aabb(&system, result.aabb, Vector3::new(0.0, 5.0, 0.0)).unwrap();
+        assert_close(result.aabb.max.y, 4.0);
+        assert!(result.hit_ceiling);
+
+        //Moving out of the corner clears the pillar
+        let result =
+            move_aabb(&system, player(0.4, 2.0, 0.0), Vector3::new(0.0, -5.0, 0.0)).unwrap();
+        assert_close(result.aabb.min.y, 0.0);
+    }
+
+    #[test]
+    fn boxes_slide_along_walls() {
+        let mut system = world();
+        for y in 0..3 {
+            for z in -16..16 {
+                system.set_voxel(2, y, z, STONE).unwrap();
+                system.set_voxel(-5, y, z, STONE).unwrap();
+            }
+        }
+
+        let result =
+            move_aabb(&system, player(0.5, 0.0, 0.5), Vector3::new(3.0, -1.0, 2.0)).unwrap();
+        assert_close(result.movement.x, 1.2);
+        assert_close(result.movement.z, 2.0);
+        assert_close(result.aabb.max.x, 2.0);
+        assert!(result.hit_wall && result.on_ground);
+
+        let result = move_aabb(
+            &system,
+            player(-3.5, 0.0, -0.5),
+            Vector3::new(-3.0, 0.0, -1.5),
+        )
+        .unwrap();
+        assert_close(result.movement.x, -0.2);
+        assert_close(result.movement.z, -1.5);
+        assert_close(result.aabb.min.x, -4.0);
+        assert!(result.hit_wall && !result.on_ground);
+    }
+
+    #[test]
+    fn unloaded_chunks_are_solid() {
+        let system = world();
+        let result =
+            move_aabb(&system, player(15.5, 0.0, 0.5), Vector3::new(3.0, 0.0, 0.0)).unwrap();
+        assert_close(result.aabb.max.x, 16.0);
+        assert!(result.hit_wall);
+
+        let result = move_aabb(
+            &system,
+            player(0.5, 10.0, 0.5),
+            Vector3::new(0.0, 20.0, 0.0),
+        )
+        .unwrap();
+        assert_close(result.aabb.max.y, 16.0);
+        assert!(result.hit_ceiling);
+    }
+}
@@ -0,0 +1,70 @@
+//! Voxel systems and helpers shared by the tests of the world's systems
+
+//Uses
+use super::chunk::size::*;
+use super::voxel::{
+    Attribute, AttributeRegistries, AttributeRegistry, NameRegistry, Voxel, VoxelArray, VoxelSystem,
+};
+
+pub(crate) const AIR: Voxel = Voxel { id: 0, data: 0 };
+pub(crate) const STONE: Voxel = Voxel { id: 1, data: 0 };
+
+type ChunkCoords = (i32, i32, i32);
+
+/// Attribute registries holding a single registry, with the given attribute for every voxel
+pub(crate) fn attributes<A: Attribute>(
+    label: &str,
+    voxels: Vec<(Voxel, A)>,
+) -> AttributeRegistries {
+    let mut registry = AttributeRegistry::new(label);
+    for (voxel, attribute) in voxels {
+        registry.register(voxel.id, attribute).unwrap();
+    }
+    let mut attributes = AttributeRegistries::new();
+    attributes.add_registry(registry).unwrap();
+    attributes
+}
+
+/// Creates a voxel system and loads uniform chunks into it.
+/// Every entry of `chunks` is a box of chunk coordinates from its first to its second corner,
+/// both inclusive, and the voxel the chunks are filled with.
+pub(crate) fn voxel_system(
+    attributes: AttributeRegistries,
+    chunks: &[(ChunkCoords, ChunkCoords, Voxel)],
+) -> VoxelSystem {
+    let mut system = VoxelSystem::new(NameRegistry::new(), attributes);
+    for &(min, max, voxel) in chunks {
+        for x in min.0..=max.0 {
+            for y in min.1..=max.1 {
+                for z in min.2..=max.2 {
+                    system.load_chunk(VoxelArray::new(voxel), x, y, z).unwrap();
+                }
+            }
+        }
+    }
+    system
+}
+
+/// Asserts that both chunks hold the same voxels, naming the first index that differs
+pub(crate) fn assert_same_voxels(a: &VoxelArray, b: &VoxelArray) {
+    for i in 0..CHUNK_SIZE_X * CHUNK_SIZE_Y * CHUNK_SIZE_Z {
+        assert_eq!(
+            a.get_voxel_at_index(i),
+            b.get_voxel_at_index(i),
+            "Voxel {} differs",
+            i
+        );
+    }
+}
+
+/// A small random number generator, so that tests with random chunks are reproducible
+pub(crate) struct XorShift(pub(crate) u32);
+
+impl XorShift {
+    pub(crate) fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+}
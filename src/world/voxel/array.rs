@@ -1,36 +1,438 @@
+//! Palette-compressed storage of the voxels of a single chunk
+//!
+//! Chunks that consist of a single voxel type are stored as just that voxel.
+//! Other chunks store every distinct voxel once in a palette, and every voxel position
+//! holds a bit-packed index into that palette. The index width grows as more distinct voxels
+//! are added, so a chunk with a handful of voxel types only uses a few bits per voxel.
+//! A reverse map from voxels to palette indices keeps lookups fast even with large palettes.
+
 //Uses
 use super::Voxel;
 use crate::world::chunk::size::*;
+use std::collections::HashMap;
+
+pub(super) const VOXEL_COUNT: usize = CHUNK_SIZE_X * CHUNK_SIZE_Y * CHUNK_SIZE_Z;
+
+/// Index widths that divide a `u64` evenly, so no index is split between two words
+const INDEX_BITS: [u32; 5] = [1, 2, 4, 8, 16];
+
+#[derive(Clone)]
+struct PackedIndices {
+    bits: u32,
+    words: Box<[u64]>,
+}
+
+impl PackedIndices {
+    fn new(bits: u32) -> PackedIndices {
+        let indices_per_word = (u64::BITS / bits) as usize;
+        PackedIndices {
+            bits,
+            words: vec![0; VOXEL_COUNT.div_ceil(indices_per_word)].into_boxed_slice(),
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        1 << self.bits
+    }
+
+    fn get(&self, i: usize) -> usize {
+        let indices_per_word = (u64::BITS / self.bits) as usize;
+        let shift = (i % indices_per_word) as u32 * self.bits;
+        let mask = (1u64 << self.bits) - 1;
+        ((self.words[i / indices_per_word] >> shift) & mask) as usize
+    }
 
+    fn set(&mut self, i: usize, palette_index: usize) {
+        let indices_per_word = (u64::BITS / self.bits) as usize;
+        let shift = (i % indices_per_word) as u32 * self.bits;
+        let mask = (1u64 << self.bits) - 1;
+        let word = &mut self.words[i / indices_per_word];
+        *word = (*word & !(mask << shift)) | ((palette_index as u64 & mask) << shift);
+    }
+}
+
+#[derive(Clone)]
+enum Storage {
+    /// Every voxel in the chunk is the same
+    Uniform(Voxel),
+    Paletted {
+        palette: Vec<Voxel>,
+        /// The palette index of every voxel in the palette
+        lookup: HashMap<Voxel, usize>,
+        indices: PackedIndices,
+    },
+}
+
+impl Storage {
+    fn paletted(palette: Vec<Voxel>, indices: PackedIndices) -> Storage {
+        //Duplicate entries can come from loaded chunks, the first one is used for new voxels
+        let mut lookup = HashMap::with_capacity(palette.len());
+        for (palette_index, voxel) in palette.iter().enumerate() {
+            lookup.entry(*voxel).or_insert(palette_index);
+        }
+        Storage::Paletted {
+            palette,
+            lookup,
+            indices,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct VoxelArray {
-    array: Box<[Voxel]>,
+    storage: Storage,
 }
 
 impl VoxelArray {
     pub fn new(default_voxel: Voxel) -> VoxelArray {
         VoxelArray {
-            array: vec![default_voxel; CHUNK_SIZE_X * CHUNK_SIZE_Y * CHUNK_SIZE_Z]
-                .into_boxed_slice(),
+            storage: Storage::Uniform(default_voxel),
         }
     }
 
     pub fn get_voxel_at_position(&self, x: usize, y: usize, z: usize) -> &Voxel {
-        &self.array[VoxelArray::get_voxel_index(x, y, z)]
+        self.get_voxel_at_index(VoxelArray::get_voxel_index(x, y, z))
     }
 
-    pub fn get_voxel_at_position_mut(&mut self, x: usize, y: usize, z: usize) -> &mut Voxel {
-        &mut self.array[VoxelArray::get_voxel_index(x, y, z)]
+    /// Replaces the voxel at the given position and returns the old voxel
+    pub fn set_voxel_at_position(&mut self, x: usize, y: usize, z: usize, voxel: Voxel) -> Voxel {
+        self.set_voxel_at_index(VoxelArray::get_voxel_index(x, y, z), voxel)
     }
 
     pub fn get_voxel_at_index(&self, i: usize) -> &Voxel {
-        &self.array[i]
+        assert!(i < VOXEL_COUNT, "Voxel index {} is out of bounds", i);
+        match &self.storage {
+            Storage::Uniform(voxel) => voxel,
+            Storage::Paletted {
+                palette, indices, ..
+            } => &palette[indices.get(i)],
+        }
+    }
+
+    /// Replaces the voxel at the given index and returns the old voxel
+    pub fn set_voxel_at_index(&mut self, i: usize, voxel: Voxel) -> Voxel {
+        let old_voxel = *self.get_voxel_at_index(i);
+        if old_voxel == voxel {
+            return old_voxel;
+        }
+
+        let palette_index = self.find_or_insert_palette_entry(voxel);
+        if let Storage::Paletted { indices, .. } = &mut self.storage {
+            indices.set(i, palette_index);
+        }
+
+        old_voxel
     }
 
-    pub fn get_voxel_at_index_mut(&mut self, i: usize) -> &mut Voxel {
-        &mut self.array[i]
+    /// Builds an array from a palette and one palette index per voxel, the indices must be valid
+    pub(super) fn from_palette(palette: Vec<Voxel>, palette_indices: &[u16]) -> VoxelArray {
+        assert_eq!(palette_indices.len(), VOXEL_COUNT);
+        if palette.len() == 1 {
+            return VoxelArray::new(palette[0]);
+        }
+
+        let mut indices = PackedIndices::new(minimum_index_bits(palette.len()));
+        for (i, palette_index) in palette_indices.iter().enumerate() {
+            indices.set(i, *palette_index as usize);
+        }
+        let mut array = VoxelArray {
+            storage: Storage::paletted(palette, indices),
+        };
+        array.compact();
+        array
     }
 
     pub fn get_voxel_index(x: usize, y: usize, z: usize) -> usize {
         z * (CHUNK_SIZE_X * CHUNK_SIZE_Y) + y * CHUNK_SIZE_X + x
     }
+
+    /// The inverse of `get_voxel_index`
+    pub fn get_voxel_position(i: usize) -> (usize, usize, usize) {
+        (
+            i % CHUNK_SIZE_X,
+            i / CHUNK_SIZE_X % CHUNK_SIZE_Y,
+            i / (CHUNK_SIZE_X * CHUNK_SIZE_Y),
+        )
+    }
+
+    /// Replaces every voxel with the result of `f`, which is called once per distinct voxel
+    pub fn map_voxels<F: FnMut(Voxel) -> Voxel>(&mut self, mut f: F) {
+        match &mut self.storage {
+            Storage::Uniform(voxel) => *voxel = f(*voxel),
+            Storage::Paletted {
+                palette,
+                lookup,
+                indices,
+            } => {
+                for entry in palette.iter_mut() {
+                    *entry = f(*entry);
+                }
+
+                //Entries that were mapped to the same voxel are merged
+                let mut unique: HashMap<Voxel, u16> = HashMap::new();
+                let mut new_palette = Vec::new();
+                let remap: Vec<u16> = palette
+                    .iter()
+                    .map(|voxel| {
+                        *unique.entry(*voxel).or_insert_with(|| {
+                            new_palette.push(*voxel);
+                            (new_palette.len() - 1) as u16
+                        })
+                    })
+                    .collect();
+                if new_palette.len() == palette.len() {
+                    //Without merges the palette keeps its order, only the reverse map changes
+                    *lookup = unique
+                        .into_iter()
+                        .map(|(voxel, palette_index)| (voxel, palette_index as usize))
+                        .collect();
+                    return;
+                }
+                let palette_indices: Vec<u16> =
+                    (0..VOXEL_COUNT).map(|i| remap[indices.get(i)]).collect();
+                *self = VoxelArray::from_palette(new_palette, &palette_indices);
+            }
+        }
+    }
+
+    /// Returns true if every voxel in the chunk is the same
+    pub fn is_uniform(&self) -> bool {
+        matches!(self.storage, Storage::Uniform(_))
+    }
+
+    /// The number of bits used to store each voxel, 0 for uniform chunks
+    pub fn bits_per_voxel(&self) -> u32 {
+        match &self.storage {
+            Storage::Uniform(_) => 0,
+            Storage::Paletted { indices, .. } => indices.bits,
+        }
+    }
+
+    /// Drops palette entries that are no longer used and shrinks the index width to the minimum.
+    /// Chunks that only contain a single voxel type go back to uniform storage.
+    pub fn compact(&mut self) {
+        let (palette, indices) = match &self.storage {
+            Storage::Uniform(_) => return,
+            Storage::Paletted {
+                palette, indices, ..
+            } => (palette, indices),
+        };
+
+        //Maps old palette indices to new ones
+        let mut remap: Vec<Option<usize>> = vec![None; palette.len()];
+        let mut new_palette = Vec::new();
+        for i in 0..VOXEL_COUNT {
+            let old_index = indices.get(i);
+            if remap[old_index].is_none() {
+                remap[old_index] = Some(new_palette.len());
+                new_palette.push(palette[old_index]);
+            }
+        }
+
+        if new_palette.len() == 1 {
+            self.storage = Storage::Uniform(new_palette[0]);
+            return;
+        }
+
+        let bits = minimum_index_bits(new_palette.len());
+        let mut new_indices = PackedIndices::new(bits);
+        for i in 0..VOXEL_COUNT {
+            new_indices.set(i, remap[indices.get(i)].unwrap());
+        }
+        self.storage = Storage::paletted(new_palette, new_indices);
+    }
+
+    fn find_or_insert_palette_entry(&mut self, voxel: Voxel) -> usize {
+        if let Storage::Paletted { lookup, .. } = &self.storage {
+            if let Some(palette_index) = lookup.get(&voxel) {
+                return *palette_index;
+            }
+        }
+
+        //Unused entries are only dropped once the palette is full, before growing the indices
+        if let Storage::Paletted {
+            palette, indices, ..
+        } = &self.storage
+        {
+            if palette.len() == indices.capacity() {
+                self.compact();
+            }
+        }
+        //Uniform chunks need a palette now, compacting may also have turned the chunk uniform
+        if let Storage::Uniform(uniform_voxel) = self.storage {
+            self.storage = Storage::paletted(vec![uniform_voxel], PackedIndices::new(INDEX_BITS[0]));
+        }
+
+        match &mut self.storage {
+            Storage::Paletted {
+                palette,
+                lookup,
+                indices,
+            } => {
+                if palette.len() == indices.capacity() {
+                    let mut new_indices = PackedIndices::new(minimum_index_bits(palette.len() + 1));
+                    for i in 0..VOXEL_COUNT {
+                        new_indices.set(i, indices.get(i));
+                    }
+                    *indices = new_indices;
+                }
+                palette.push(voxel);
+                lookup.insert(voxel, palette.len() - 1);
+                palette.len() - 1
+            }
+            Storage::Uniform(_) => unreachable!(),
+        }
+    }
+}
+
+fn minimum_index_bits(palette_len: usize) -> u32 {
+    *INDEX_BITS
+        .iter()
+        .find(|bits| 1 << **bits >= palette_len)
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn voxel(id: u16) -> Voxel {
+        Voxel { id, data: 0 }
+    }
+
+    /// Checks every voxel against the expected contents
+    fn assert_voxels(array: &VoxelArray, expected: &[Voxel]) {
+        for (i, voxel) in expected.iter().enumerate() {
+            assert_eq!(array.get_voxel_at_index(i), voxel, "voxel {}", i);
+        }
+    }
+
+    #[test]
+    fn single_voxel_type_stays_uniform() {
+        let mut array = VoxelArray::new(voxel(3));
+        assert!(array.is_uniform());
+        assert_eq!(array.bits_per_voxel(), 0);
+        assert_eq!(*array.get_voxel_at_position(15, 15, 15), voxel(3));
+
+        //Setting the voxel that is already there must not create a palette
+        assert_eq!(array.set_voxel_at_position(4, 5, 6, voxel(3)), voxel(3));
+        assert!(array.is_uniform());
+    }
+
+    #[test]
+    fn index_width_grows_with_the_palette() {
+        let mut array = VoxelArray::new(voxel(0));
+        let mut expected = vec![voxel(0); VOXEL_COUNT];
+
+        //The palette holds the initial voxel plus one entry per distinct voxel set so far
+        let mut palette_len = 1;
+        for (distinct_voxels, bits) in [(2, 1), (3, 2), (5, 4), (17, 8), (257, 16), (4096, 16)] {
+            while palette_len < distinct_voxels {
+                let i = palette_len * 7 % VOXEL_COUNT;
+                let new_voxel = voxel(palette_len as u16);
+                assert_eq!(array.set_voxel_at_index(i, new_voxel), expected[i]);
+                expected[i] = new_voxel;
+                palette_len += 1;
+            }
+            assert_eq!(
+                array.bits_per_voxel(),
+                bits,
+                "{} distinct voxels",
+                distinct_voxels
+            );
+            assert_voxels(&array, &expected);
+        }
+    }
+
+    #[test]
+    fn every_voxel_can_be_distinct() {
+        let mut array = VoxelArray::new(voxel(0));
+        for i in 0..VOXEL_COUNT {
+            array.set_voxel_at_index(
+                i,
+                Voxel {
+                    id: i as u16,
+                    data: 1,
+                },
+            );
+        }
+        assert_eq!(array.bits_per_voxel(), 16);
+        for i in 0..VOXEL_COUNT {
+            assert_eq!(
+                *array.get_voxel_at_index(i),
+                Voxel {
+                    id: i as u16,
+                    data: 1
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn compact_returns_to_uniform_storage() {
+        let mut array = VoxelArray::new(voxel(0));
+        for i in 0..20 {
+            array.set_voxel_at_index(i, voxel(i as u16 + 1));
+        }
+        assert_eq!(array.bits_per_voxel(), 8);
+
+        for i in 0..20 {
+            array.set_voxel_at_index(i, voxel(9));
+        }
+        array.set_voxel_at_index(VOXEL_COUNT - 1, voxel(9));
+        assert_eq!(*array.get_voxel_at_index(0), voxel(9));
+        array.compact();
+        assert_eq!(array.bits_per_voxel(), 1);
+
+        for i in 0..VOXEL_COUNT {
+            array.set_voxel_at_index(i, voxel(9));
+        }
+        array.compact();
+        assert!(array.is_uniform());
+        assert_voxels(&array, &vec![voxel(9); VOXEL_COUNT]);
+    }
+
+    #[test]
+    fn full_palette_reuses_unused_entries_before_growing() {
+        let mut array = VoxelArray::new(voxel(0));
+        array.set_voxel_at_index(0, voxel(1));
+        assert_eq!(array.bits_per_voxel(), 1);
+
+        //Voxel 1 is no longer used when voxel 2 arrives, so its entry is dropped instead of growing
+        array.set_voxel_at_index(0, voxel(0));
+        array.set_voxel_at_index(1, voxel(2));
+        assert_eq!(array.bits_per_voxel(), 1);
+        assert_eq!(*array.get_voxel_at_index(0), voxel(0));
+        assert_eq!(*array.get_voxel_at_index(1), voxel(2));
+
+        //A chunk that became uniform again goes back through the uniform fast path
+        array.set_voxel_at_index(1, voxel(0));
+        array.set_voxel_at_index(2, voxel(3));
+        assert_eq!(array.bits_per_voxel(), 1);
+        assert_eq!(*array.get_voxel_at_index(2), voxel(3));
+        assert_eq!(*array.get_voxel_at_index(1), voxel(0));
+    }
+
+    #[test]
+    fn map_voxels_merges_entries_and_keeps_lookups_working() {
+        let mut array = VoxelArray::new(voxel(0));
+        array.set_voxel_at_index(0, voxel(1));
+        array.set_voxel_at_index(1, voxel(2));
+
+        //Without merges the palette is only renamed
+        array.map_voxels(|v| voxel(v.id + 10));
+        assert_eq!(*array.get_voxel_at_index(1), voxel(12));
+        array.set_voxel_at_index(2, voxel(11));
+        assert_eq!(array.bits_per_voxel(), 2);
+
+        array.map_voxels(|v| if v.id == 10 { v } else { voxel(11) });
+        assert_eq!(array.bits_per_voxel(), 1);
+        assert_eq!(*array.get_voxel_at_index(1), voxel(11));
+        array.set_voxel_at_index(3, voxel(11));
+        assert_eq!(array.bits_per_voxel(), 1);
+
+        array.map_voxels(|_| voxel(5));
+        assert!(array.is_uniform());
+        assert_eq!(*array.get_voxel_at_index(0), voxel(5));
+    }
 }
@@ -0,0 +1,540 @@
+//! Block entities, extended data for single voxels
+//!
+//! Voxels like chests, signs or machines need more state than fits into `Voxel::data`.
+//! A `BlockEntity` holds any number of typed components for one voxel, at most one per type,
+//! and every loaded chunk keeps the block entities of its voxels in a `BlockEntities` store.
+//! `VoxelSystem::set_voxel` removes a block entity when the voxel at its position changes,
+//! `VoxelSystem::set_voxel_data` changes only the voxel's data and keeps it.
+//!
+//! Components that implement `PersistentComponent` and are added to a `ComponentRegistry`
+//! are saved together with the chunk. Other components only exist while the chunk is loaded,
+//! and saved components whose name is no longer registered are dropped when the chunk is loaded.
+//!
+//! Encoded block entities are written after the voxels of a chunk and look like this:
+//! - The magic bytes `YBEN`
+//! - The format version (`u8`)
+//! - The number of block entities (`u16`)
+//! - For every block entity, its voxel index in the chunk (`u16`) and its number of components (`u16`),
+//!   followed by the components, each being the length of its name (`u16`), the name as UTF-8,
+//!   the length of its data (`u32`) and the data
+
+//Uses
+use super::array::VOXEL_COUNT;
+use super::serialize::{read_exact, read_u16, read_u8, write_u16};
+use super::{Error, VoxelArray};
+use std::any::{type_name, Any, TypeId};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Write};
+
+const MAGIC: [u8; 4] = *b"YBEN";
+const VERSION: u8 = 1;
+
+pub trait Component: 'static + Any + Send + Sync {}
+impl<C: 'static + Any + Send + Sync> Component for C {}
+
+/// A component that is saved with its chunk
+pub trait PersistentComponent: Component + Sized {
+    /// The name the component is saved under, it must be unique and never change
+    const NAME: &'static str;
+
+    fn write_to(&self, data: &mut Vec<u8>) -> Result<(), Error>;
+    fn read_from(data: &[u8]) -> Result<Self, Error>;
+}
+
+/// The components of a single voxel
+#[derive(Default)]
+pub struct BlockEntity {
+    components: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl BlockEntity {
+    pub fn new() -> BlockEntity {
+        BlockEntity {
+            components: HashMap::new(),
+        }
+    }
+
+    /// Adds a component, returning the previous component of the same type
+    pub fn insert<C: Component>(&mut self, component: C) -> Option<C> {
+        self.components
+            .insert(TypeId::of::<C>(), Box::new(component))
+            .map(|previous| *previous.downcast::<C>().unwrap())
+    }
+
+    pub fn get<C: Component>(&self) -> Option<&C> {
+        self.components.get(&TypeId::of::<C>())?.downcast_ref::<C>()
+    }
+
+    pub fn get_mut<C: Component>(&mut self) -> Option<&mut C> {
+        self.components
+            .get_mut(&TypeId::of::<C>())?
+            .downcast_mut::<C>()
+    }
+
+    pub fn remove<C: Component>(&mut self) -> Option<C> {
+        self.components
+            .remove(&TypeId::of::<C>())
+            .map(|component| *component.downcast::<C>().unwrap())
+    }
+
+    pub fn contains<C: Component>(&self) -> bool {
+        self.components.contains_key(&TypeId::of::<C>())
+    }
+
+    pub fn len(&self) -> usize {
+        self.components.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.components.is_empty()
+    }
+}
+
+/// The block entities of one chunk, keyed by the local position of their voxel
+#[derive(Default)]
+pub struct BlockEntities {
+    entities: BTreeMap<u16, BlockEntity>,
+}
+
+impl BlockEntities {
+    pub fn new() -> BlockEntities {
+        BlockEntities {
+            entities: BTreeMap::new(),
+        }
+    }
+
+    fn index(x: usize, y: usize, z: usize) -> u16 {
+        VoxelArray::get_voxel_index(x, y, z) as u16
+    }
+
+    pub fn get(&self, x: usize, y: usize, z: usize) -> Option<&BlockEntity> {
+        self.entities.get(&Self::index(x, y, z))
+    }
+
+    pub fn get_mut(&mut self, x: usize, y: usize, z: usize) -> Option<&mut BlockEntity> {
+        self.entities.get_mut(&Self::index(x, y, z))
+    }
+
+    /// Returns the block entity at the position, adding an empty one if there is none
+    pub fn get_or_insert(&mut self, x: usize, y: usize, z: usize) -> &mut BlockEntity {
+        self.entities.entry(Self::index(x, y, z)).or_default()
+    }
+
+    /// Sets the block entity at the position, returning the previous one
+    pub fn insert(
+        &mut self,
+        x: usize,
+        y: usize,
+        z: usize,
+        entity: BlockEntity,
+    ) -> Option<BlockEntity> {
+        self.entities.insert(Self::index(x, y, z), entity)
+    }
+
+    pub fn remove(&mut self, x: usize, y: usize, z: usize) -> Option<BlockEntity> {
+        self.entities.remove(&Self::index(x, y, z))
+    }
+
+    /// Iterates over the block entities in voxel buffer order, together with their local positions
+    pub fn iter(&self) -> impl Iterator<Item = ((usize, usize, usize), &BlockEntity)> {
+        self.entities
+            .iter()
+            .map(|(index, entity)| (VoxelArray::get_voxel_position(*index as usize), entity))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entities.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+
+    /// Writes every persistent component, block entities without any are left out
+    pub fn write_to<W: Write>(&self, w: &mut W, registry: &ComponentRegistry) -> Result<(), Error> {
+        let mut encoded = Vec::new();
+        for (index, entity) in self.entities.iter() {
+            let mut components = Vec::new();
+            for (type_id, component) in entity.components.iter() {
+                if let Some(codec) = registry.by_type.get(type_id) {
+                    let mut data = Vec::new();
+                    (codec.write)(component.as_ref(), &mut data)?;
+                    components.push((codec.name, data));
+                }
+            }
+            if !components.is_empty() {
+                //Sort by name so that the same block entity is always encoded the same way
+                components.sort_unstable_by_key(|(name, _)| *name);
+                encoded.push((*index, components));
+            }
+        }
+
+        w.write_all(&MAGIC)?;
+        w.write_all(&[VERSION])?;
+        write_u16(w, encoded.len() as u16)?;
+        for (index, components) in encoded {
+            write_u16(w, index)?;
+            write_u16(w, components.len() as u16)?;
+            for (name, data) in components {
+                write_u16(w, name.len() as u16)?;
+                w.write_all(name.as_bytes())?;
+                w.write_all(&(data.len() as u32).to_le_bytes())?;
+                w.write_all(&data)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(
+        r: &mut R,
+        registry: &ComponentRegistry,
+    ) -> Result<BlockEntities, Error> {
+        let mut magic = [0; 4];
+        read_exact(r, &mut magic)?;
+        if magic != MAGIC {
+            return Err(Error::InvalidBlockEntityMagic);
+        }
+        let version = read_u8(r)?;
+        if version != VERSION {
+            return Err(Error::UnsupportedBlockEntityVersion(version));
+        }
+
+        let mut entities = BlockEntities::new();
+        for _ in 0..read_u16(r)? {
+            let index = read_u16(r)?;
+            if index as usize >= VOXEL_COUNT || entities.entities.contains_key(&index) {
+                return Err(Error::InvalidBlockEntityPosition(index));
+            }
+
+            let mut entity = BlockEntity::new();
+            for _ in 0..read_u16(r)? {
+                let name_length = read_u16(r)?;
+                let name = read_bytes(r, name_length as u64)?;
+                let mut data_length = [0; 4];
+                read_exact(r, &mut data_length)?;
+                let data = read_bytes(r, u32::from_le_bytes(data_length) as u64)?;
+
+                let codec = std::str::from_utf8(&name)
+                    .ok()
+                    .and_then(|name| registry.by_name.get(name))
+                    .and_then(|type_id| registry.by_type.get(type_id));
+                if let Some(codec) = codec {
+                    entity
+                        .components
+                        .insert(codec.type_id, (codec.read)(&data)?);
+                }
+            }
+            if !entity.is_empty() {
+                entities.entities.insert(index, entity);
+            }
+        }
+
+        Ok(entities)
+    }
+}
+
+/// Reads a length-prefixed byte string without trusting the length for the allocation
+fn read_bytes<R: Read>(r: &mut R, length: u64) -> Result<Vec<u8>, Error> {
+    let mut bytes = Vec::new();
+    r.take(length).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != length {
+        return Err(Error::UnexpectedEndOfData);
+    }
+    Ok(bytes)
+}
+
+type WriteFn = fn(&(dyn Any + Send + Sync), &mut Vec<u8>) -> Result<(), Error>;
+type ReadFn = fn(&[u8]) -> Result<Box<dyn Any + Send + Sync>, Error>;
+
+struct ComponentCodec {
+    name: &'static str,
+    type_id: TypeId,
+    write: WriteFn,
+    read: ReadFn,
+}
+
+fn write_component<C: PersistentComponent>(
+    component: &(dyn Any + Send + Sync),
+    data: &mut Vec<u8>,
+) -> Result<(), Error> {
+    component.downcast_ref::<C>().unwrap().write_to(data)
+}
+
+fn read_component<C: PersistentComponent>(
+    data: &[u8],
+) -> Result<Box<dyn Any + Send + Sync>, Error> {
+    Ok(Box::new(C::read_from(data)?))
+}
+
+/// The components that are saved with chunks, and how to read them back
+#[derive(Default)]
+pub struct ComponentRegistry {
+    by_type: HashMap<TypeId, ComponentCodec>,
+    by_name: HashMap<&'static str, TypeId>,
+}
+
+impl ComponentRegistry {
+    pub fn new() -> ComponentRegistry {
+        ComponentRegistry {
+            by_type: HashMap::new(),
+            by_name: HashMap::new(),
+        }
+    }
+
+    pub fn register<C: PersistentComponent>(&mut self) -> Result<(), Error> {
+        let type_id = TypeId::of::<C>();
+        if self.by_type.contains_key(&type_id) || self.by_name.contains_key(C::NAME) {
+            return Err(Error::ComponentAlreadyRegistered(type_name::<C>()));
+        }
+
+        self.by_name.insert(C::NAME, type_id);
+        self.by_type.insert(
+            type_id,
+            ComponentCodec {
+                name: C::NAME,
+                type_id,
+                write: write_component::<C>,
+                read: read_component::<C>,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn is_registered<C: PersistentComponent>(&self) -> bool {
+        self.by_type.contains_key(&TypeId::of::<C>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::voxel::storage::tests::TempDir;
+    use crate::world::voxel::{RegionStorage, Voxel};
+    use std::sync::Arc;
+
+    #[derive(Debug, PartialEq)]
+    struct Label(String);
+
+    impl PersistentComponent for Label {
+        const NAME: &'static str = "label";
+
+        fn write_to(&self, data: &mut Vec<u8>) -> Result<(), Error> {
+            data.extend_from_slice(self.0.as_bytes());
+            Ok(())
+        }
+
+        fn read_from(data: &[u8]) -> Result<Self, Error> {
+            let text =
+                std::str::from_utf8(data).map_err(|_| Error::InvalidComponentData(Self::NAME))?;
+            Ok(Label(text.to_owned()))
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Counter(u32);
+
+    impl PersistentComponent for Counter {
+        const NAME: &'static str = "counter";
+
+        fn write_to(&self, data: &mut Vec<u8>) -> Result<(), Error> {
+            data.extend_from_slice(&self.0.to_le_bytes());
+            Ok(())
+        }
+
+        fn read_from(data: &[u8]) -> Result<Self, Error> {
+            let bytes = data
+                .try_into()
+                .map_err(|_| Error::InvalidComponentData(Self::NAME))?;
+            Ok(Counter(u32::from_le_bytes(bytes)))
+        }
+    }
+
+    /// A component that is never saved
+    struct Cache;
+
+    fn registry() -> ComponentRegistry {
+        let mut registry = ComponentRegistry::new();
+        registry.register::<Label>().unwrap();
+        registry.register::<Counter>().unwrap();
+        registry
+    }
+
+    fn entities() -> BlockEntities {
+        let mut entities = BlockEntities::new();
+        let chest = entities.get_or_insert(1, 2, 3);
+        chest.insert(Label("chest".to_owned()));
+        chest.insert(Counter(27));
+        chest.insert(Cache);
+        entities.get_or_insert(15, 15, 15).insert(Counter(u32::MAX));
+        entities.get_or_insert(0, 0, 0).insert(Cache);
+        entities
+    }
+
+    fn encode(entities: &BlockEntities, registry: &ComponentRegistry) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        entities.write_to(&mut bytes, registry).unwrap();
+        bytes
+    }
+
+    /// Components as their saved name and data
+    type RawComponents<'a> = &'a [(&'a str, &'a [u8])];
+
+    /// Encodes block entities by hand, each given as its voxel index and its components
+    fn encode_raw(entities: &[(u16, RawComponents)]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&MAGIC);
+        bytes.push(VERSION);
+        write_u16(&mut bytes, entities.len() as u16).unwrap();
+        for (index, components) in entities {
+            write_u16(&mut bytes, *index).unwrap();
+            write_u16(&mut bytes, components.len() as u16).unwrap();
+            for (name, data) in components.iter() {
+                write_u16(&mut bytes, name.len() as u16).unwrap();
+                bytes.extend_from_slice(name.as_bytes());
+                bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                bytes.extend_from_slice(data);
+            }
+        }
+        bytes
+    }
+
+    #[test]
+    fn persistent_components_round_trip() {
+        let registry = registry();
+        let bytes = encode(&entities(), &registry);
+        let decoded = BlockEntities::read_from(&mut bytes.as_slice(), &registry).unwrap();
+
+        //Block entities with only unsaved components are left out
+        assert_eq!(decoded.len(), 2);
+        let chest = decoded.get(1, 2, 3).unwrap();
+        assert_eq!(chest.get::<Label>(), Some(&Label("chest".to_owned())));
+        assert_eq!(chest.get::<Counter>(), Some(&Counter(27)));
+        assert!(!chest.contains::<Cache>());
+        assert_eq!(
+            decoded.get(15, 15, 15).unwrap().get::<Counter>(),
+            Some(&Counter(u32::MAX))
+        );
+        assert!(decoded.get(0, 0, 0).is_none());
+        assert_eq!(encode(&decoded, &registry), bytes);
+    }
+
+    #[test]
+    fn unknown_component_names_are_dropped() {
+        let bytes = encode(&entities(), &registry());
+        let mut labels_only = ComponentRegistry::new();
+        labels_only.register::<Label>().unwrap();
+        let decoded = BlockEntities::read_from(&mut bytes.as_slice(), &labels_only).unwrap();
+
+        //The block entity left without components is dropped as well
+        assert_eq!(decoded.len(), 1);
+        let chest = decoded.get(1, 2, 3).unwrap();
+        assert_eq!(chest.len(), 1);
+        assert_eq!(chest.get::<Label>(), Some(&Label("chest".to_owned())));
+
+        let bytes = encode_raw(&[(5, &[("label", b"sign"), ("paint", b"red")])]);
+        let decoded = BlockEntities::read_from(&mut bytes.as_slice(), &labels_only).unwrap();
+        assert_eq!(decoded.get(5, 0, 0).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn invalid_positions_are_rejected() {
+        let registry = registry();
+        let counter: RawComponents = &[("counter", &[1, 0, 0, 0])];
+
+        let duplicate = encode_raw(&[(7, counter), (7, counter)]);
+        assert!(matches!(
+            BlockEntities::read_from(&mut duplicate.as_slice(), &registry),
+            Err(Error::InvalidBlockEntityPosition(7))
+        ));
+
+        let out_of_range = encode_raw(&[(VOXEL_COUNT as u16, counter)]);
+        assert!(matches!(
+            BlockEntities::read_from(&mut out_of_range.as_slice(), &registry),
+            Err(Error::InvalidBlockEntityPosition(index)) if index as usize == VOXEL_COUNT
+        ));
+
+        let bad_data = encode_raw(&[(7, &[("counter", &[1, 0])])]);
+        assert!(matches!(
+            BlockEntities::read_from(&mut bad_data.as_slice(), &registry),
+            Err(Error::InvalidComponentData("counter"))
+        ));
+    }
+
+    #[test]
+    fn invalid_headers_are_reported() {
+        let registry = registry();
+        let bytes = encode(&entities(), &registry);
+
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] = b'X';
+        assert!(matches!(
+            BlockEntities::read_from(&mut bad_magic.as_slice(), &registry),
+            Err(Error::InvalidBlockEntityMagic)
+        ));
+
+        let mut bad_version = bytes;
+        bad_version[4] = VERSION + 1;
+        assert!(matches!(
+            BlockEntities::read_from(&mut bad_version.as_slice(), &registry),
+            Err(Error::UnsupportedBlockEntityVersion(v)) if v == VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn truncated_data_is_rejected() {
+        let registry = registry();
+        let bytes = encode(&entities(), &registry);
+        for len in 0..bytes.len() {
+            assert!(matches!(
+                BlockEntities::read_from(&mut &bytes[..len], &registry),
+                Err(Error::UnexpectedEndOfData)
+            ));
+        }
+
+        //A length far beyond the data must not be trusted for the allocation
+        let mut huge = encode_raw(&[(0, &[("label", b"")])]);
+        let data_length = huge.len() - 4;
+        huge[data_length..].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            BlockEntities::read_from(&mut huge.as_slice(), &registry),
+            Err(Error::UnexpectedEndOfData)
+        ));
+    }
+
+    #[test]
+    fn stored_chunks_reload_with_and_without_block_entities() {
+        let dir = TempDir::new("entity-storage");
+        let voxels = VoxelArray::new(Voxel { id: 3, data: 1 });
+        {
+            let mut storage = RegionStorage::open(dir.path()).unwrap();
+            storage.set_component_registry(Arc::new(registry()));
+            storage.save_chunk(&voxels, 0, 0, 0).unwrap();
+            storage
+                .save_chunk_with_entities(&voxels, &entities(), 1, 0, 0)
+                .unwrap();
+            storage
+                .save_chunk_with_entities(&voxels, &BlockEntities::new(), 2, 0, 0)
+                .unwrap();
+        }
+
+        let mut storage = RegionStorage::open(dir.path()).unwrap();
+        storage.set_component_registry(Arc::new(registry()));
+        for x in [0, 2] {
+            let (loaded, loaded_entities) =
+                storage.load_chunk_with_entities(x, 0, 0).unwrap().unwrap();
+            assert_eq!(*loaded.get_voxel_at_index(0), Voxel { id: 3, data: 1 });
+            assert!(loaded_entities.is_empty());
+        }
+
+        let (loaded, loaded_entities) = storage.load_chunk_with_entities(1, 0, 0).unwrap().unwrap();
+        assert!(loaded.is_uniform());
+        assert_eq!(loaded_entities.len(), 2);
+        assert_eq!(
+            loaded_entities.get(1, 2, 3).unwrap().get::<Label>(),
+            Some(&Label("chest".to_owned()))
+        );
+        //Loading only the voxels skips the block entities
+        assert!(storage.load_chunk(1, 0, 0).unwrap().unwrap().is_uniform());
+    }
+}
@@ -0,0 +1,304 @@
+//! Procedural generation of chunks
+//!
+//! A `ChunkGenerator` turns chunk coordinates and a world seed into the voxels of that chunk.
+//! Generators have to be deterministic, the same coordinates and seed must always give the same chunk,
+//! so chunks that were never saved can simply be generated again.
+
+//Uses
+use super::{Error, NameRegistry, Voxel, VoxelArray};
+use crate::world::chunk::size::*;
+
+pub trait ChunkGenerator: Send + Sync {
+    fn generate(&self, x: i32, y: i32, z: i32, seed: u64) -> VoxelArray;
+
+    /// Returns the global height of the topmost solid voxel in a column, if the generator knows it
+    /// without generating chunks. Light uses it to find out which unloaded chunks are open to the sky.
+    fn surface_height(&self, _x: i64, _z: i64, _seed: u64) -> Option<i64> {
+        None
+    }
+}
+
+/// One layer of noise added to the terrain height
+#[derive(Clone, Copy, Debug)]
+pub struct NoiseLayer {
+    /// Horizontal distance in voxels between the noise's lattice points
+    pub scale: f64,
+    /// Maximum height in voxels this layer adds or removes
+    pub amplitude: f64,
+}
+
+/// Settings of a `HeightmapGenerator`, voxels are given by their registered names
+#[derive(Clone, Debug)]
+pub struct HeightmapSettings {
+    pub air: String,
+    /// The topmost voxel of every column
+    pub surface: String,
+    /// The voxels below the surface
+    pub subsurface: String,
+    pub subsurface_depth: u32,
+    /// Everything below the subsurface
+    pub stone: String,
+    /// Height of the terrain before noise is added
+    pub base_height: i32,
+    pub layers: Vec<NoiseLayer>,
+}
+
+impl Default for HeightmapSettings {
+    fn default() -> HeightmapSettings {
+        HeightmapSettings {
+            air: "air".to_owned(),
+            surface: "grass".to_owned(),
+            subsurface: "dirt".to_owned(),
+            subsurface_depth: 3,
+            stone: "stone".to_owned(),
+            base_height: 0,
+            layers: vec![
+                NoiseLayer {
+                    scale: 128.0,
+                    amplitude: 24.0,
+                },
+                NoiseLayer {
+                    scale: 32.0,
+                    amplitude: 6.0,
+                },
+                NoiseLayer {
+                    scale: 8.0,
+                    amplitude: 1.5,
+                },
+            ],
+        }
+    }
+}
+
+/// Generates terrain from a heightmap made of layered 2D value noise
+pub struct HeightmapGenerator {
+    air: Voxel,
+    surface: Voxel,
+    subsurface: Voxel,
+    subsurface_depth: i64,
+    stone: Voxel,
+    base_height: i64,
+    layers: Vec<NoiseLayer>,
+}
+
+impl HeightmapGenerator {
+    /// Creates the generator, fails if one of the voxel names is not registered
+    pub fn new(
+        names: &NameRegistry,
+        settings: HeightmapSettings,
+    ) -> Result<HeightmapGenerator, Error> {
+        let find = |name: &str| {
+            names
+                .find(name)
+                .map(|id| Voxel { id, data: 0 })
+                .ok_or_else(|| Error::NameMissing(name.to_owned()))
+        };
+        Ok(HeightmapGenerator {
+            air: find(&settings.air)?,
+            surface: find(&settings.surface)?,
+            subsurface: find(&settings.subsurface)?,
+            subsurface_depth: settings.subsurface_depth as i64,
+            stone: find(&settings.stone)?,
+            base_height: settings.base_height as i64,
+            layers: settings.layers,
+        })
+    }
+
+    /// Returns the global height of the surface voxel in the given column
+    pub fn height_at(&self, x: i64, z: i64, seed: u64) -> i64 {
+        let offset: f64 = self
+            .layers
+            .iter()
+            .enumerate()
+            .map(|(i, layer)| {
+                let layer_seed = seed ^ (i as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+                layer.amplitude
+                    * value_noise(x as f64 / layer.scale, z as f64 / layer.scale, layer_seed)
+            })
+            .sum();
+        self.base_height + offset.floor() as i64
+    }
+
+    fn voxel_at_height(&self, y: i64, height: i64) -> Voxel {
+        if y > height {
+            self.air
+        } else if y == height {
+            self.surface
+        } else if y > height - 1 - self.subsurface_depth {
+            self.subsurface
+        } else {
+            self.stone
+        }
+    }
+}
+
+impl ChunkGenerator for HeightmapGenerator {
+    fn generate(&self, x: i32, y: i32, z: i32, seed: u64) -> VoxelArray {
+        let origin_x = x as i64 * CHUNK_SIZE_X as i64;
+        let origin_y = y as i64 * CHUNK_SIZE_Y as i64;
+        let origin_z = z as i64 * CHUNK_SIZE_Z as i64;
+
+        let mut heights = [[0; CHUNK_SIZE_X]; CHUNK_SIZE_Z];
+        for (local_z, row) in heights.iter_mut().enumerate() {
+            for (local_x, height) in row.iter_mut().enumerate() {
+                *height =
+                    self.height_at(origin_x + local_x as i64, origin_z + local_z as i64, seed);
+            }
+        }
+
+        //Chunks entirely above or below the surface don't need to be filled voxel by voxel
+        let min_height = *heights.iter().flatten().min().unwrap();
+        let max_height = *heights.iter().flatten().max().unwrap();
+        let top_y = origin_y + CHUNK_SIZE_Y as i64 - 1;
+        if origin_y > max_height {
+            return VoxelArray::new(self.air);
+        }
+        if top_y <= min_height - 1 - self.subsurface_depth {
+            return VoxelArray::new(self.stone);
+        }
+
+        let mut voxels = VoxelArray::new(self.air);
+        for (local_z, row) in heights.iter().enumerate() {
+            for (local_x, height) in row.iter().enumerate() {
+                for local_y in 0..CHUNK_SIZE_Y {
+                    let voxel = self.voxel_at_height(origin_y + local_y as i64, *height);
+                    voxels.set_voxel_at_position(local_x, local_y, local_z, voxel);
+                }
+            }
+        }
+        voxels
+    }
+
+    fn surface_height(&self, x: i64, z: i64, seed: u64) -> Option<i64> {
+        Some(self.height_at(x, z, seed))
+    }
+}
+
+/// Smoothly interpolated noise between random values at integer lattice points, in the range -1 to 1
+fn value_noise(x: f64, z: f64, seed: u64) -> f64 {
+    let (cell_x, cell_z) = (x.floor(), z.floor());
+    let (ix, iz) = (cell_x as i64, cell_z as i64);
+    let (tx, tz) = (smoothstep(x - cell_x), smoothstep(z - cell_z));
+
+    let lattice = |dx: i64, dz: i64| lattice_value(ix.wrapping_add(dx), iz.wrapping_add(dz), seed);
+    let top = lerp(lattice(0, 0), lattice(1, 0), tx);
+    let bottom = lerp(lattice(0, 1), lattice(1, 1), tx);
+    lerp(top, bottom, tz)
+}
+
+fn lattice_value(x: i64, z: i64, seed: u64) -> f64 {
+    let hash = mix(mix(seed ^ x as u64) ^ (z as u64).rotate_left(32));
+    (hash >> 11) as f64 / (1u64 << 53) as f64 * 2.0 - 1.0
+}
+
+/// The SplitMix64 finalizer, spreads every input bit over the whole output
+fn mix(mut value: u64) -> u64 {
+    value = value.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    value = (value ^ (value >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    value ^ (value >> 31)
+}
+
+fn smoothstep(t: f64) -> f64 {
+    t * t * (3.0 - 2.0 * t)
+}
+
+fn lerp(a: f64, b: f64, t: f64) -> f64 {
+    a + (b - a) * t
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::voxel::array::VOXEL_COUNT;
+
+    fn names() -> NameRegistry {
+        let mut names = NameRegistry::new();
+        for (id, name) in ["air", "grass", "dirt", "stone"].iter().enumerate() {
+            names.add(name, id as u16).unwrap();
+        }
+        names
+    }
+
+    fn generator() -> HeightmapGenerator {
+        HeightmapGenerator::new(&names(), HeightmapSettings::default()).unwrap()
+    }
+
+    fn voxels(array: &VoxelArray) -> Vec<Voxel> {
+        (0..VOXEL_COUNT)
+            .map(|i| *array.get_voxel_at_index(i))
+            .collect()
+    }
+
+    /// Chunks around the surface, including negative coordinates
+    const SURFACE_CHUNKS: [(i32, i32, i32); 5] = [
+        (0, 0, 0),
+        (0, -1, 0),
+        (-3, 0, 7),
+        (12, -1, -5),
+        (-40, 0, -40),
+    ];
+
+    #[test]
+    fn same_coordinates_give_the_same_chunk() {
+        let generator = generator();
+        for (x, y, z) in SURFACE_CHUNKS {
+            let first = generator.generate(x, y, z, 42);
+            //Generate other chunks in between, generation must not depend on earlier calls
+            generator.generate(x + 1, y, z, 42);
+            generator.generate(x, y, z, 7);
+            assert_eq!(voxels(&first), voxels(&generator.generate(x, y, z, 42)));
+        }
+    }
+
+    #[test]
+    fn separate_generators_give_the_same_chunk() {
+        let (a, b) = (generator(), generator());
+        for (x, y, z) in SURFACE_CHUNKS {
+            assert_eq!(
+                voxels(&a.generate(x, y, z, 1234)),
+                voxels(&b.generate(x, y, z, 1234))
+            );
+        }
+    }
+
+    #[test]
+    fn different_seeds_give_different_terrain() {
+        let generator = generator();
+        let heights = |seed| {
+            (0..64)
+                .flat_map(|x| (0..64).map(move |z| (x, z)))
+                .map(|(x, z)| generator.height_at(x * 7, z * 7, seed))
+                .collect::<Vec<i64>>()
+        };
+        assert_ne!(heights(1), heights(2));
+        assert_ne!(heights(0), heights(u64::MAX));
+
+        let chunks = |seed| {
+            SURFACE_CHUNKS
+                .iter()
+                .map(|(x, y, z)| voxels(&generator.generate(*x, *y, *z, seed)))
+                .collect::<Vec<_>>()
+        };
+        assert_ne!(chunks(1), chunks(2));
+    }
+
+    #[test]
+    fn chunks_match_the_heightmap() {
+        let generator = generator();
+        for (x, y, z) in SURFACE_CHUNKS {
+            let chunk = generator.generate(x, y, z, 9);
+            for i in 0..VOXEL_COUNT {
+                let (local_x, local_y, local_z) = VoxelArray::get_voxel_position(i);
+                let global_x = x as i64 * CHUNK_SIZE_X as i64 + local_x as i64;
+                let global_y = y as i64 * CHUNK_SIZE_Y as i64 + local_y as i64;
+                let global_z = z as i64 * CHUNK_SIZE_Z as i64 + local_z as i64;
+                let height = generator.height_at(global_x, global_z, 9);
+                assert_eq!(
+                    *chunk.get_voxel_at_index(i),
+                    generator.voxel_at_height(global_y, height)
+                );
+            }
+        }
+    }
+}
@@ -9,16 +9,36 @@
 
 //Uses
 use super::chunk::ChunkArray;
-use std::sync::Arc;
+use super::coords;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use storage::lock_storage;
+use streaming::ChunkStreamer;
 use thiserror;
 
 //Modules
 mod array;
+mod entity;
+mod generate;
+mod raycast;
 mod registry;
+mod remap;
+mod serialize;
+mod state;
+mod storage;
+mod streaming;
+mod worker;
 
 //Exports
 pub use array::VoxelArray;
+pub use entity::{BlockEntities, BlockEntity, Component, ComponentRegistry, PersistentComponent};
+pub use generate::{ChunkGenerator, HeightmapGenerator, HeightmapSettings, NoiseLayer};
+pub use raycast::RaycastHit;
 pub use registry::{Attribute, AttributeRegistries, AttributeRegistry, NameRegistry};
+pub use state::{BlockStateRegistry, BlockStateSchema, Property, PropertyKind, PropertyValue};
+pub use storage::{RegionStorage, SharedStorage, DEFAULT_MAX_OPEN_REGIONS, REGION_SIZE};
+pub use streaming::{FocusPointId, StreamingSettings};
+pub use worker::{ChunkSource, ChunkWorkerPool, FinishedChunk};
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -28,14 +48,82 @@ pub enum Error {
     AttributeMissing(u16),
     #[error("A name has already been registered with the ID {0}")]
     NameAlreadyRegistered(u16),
+    #[error("No voxel has been registered with the name \"{0}\"")]
+    NameMissing(String),
     #[error("An attribute registry has already been added! Attribute name: {0}")]
     RegistryAlreadyAdded(&'static str),
+    #[error("No attribute registry has been added for the attribute {0}")]
+    RegistryMissing(&'static str),
     #[error("The chunk at ({0}, {1}, {2}) has already been loaded!")]
     ChunkAlreadyLoaded(i32, i32, i32),
+    #[error("The chunk at ({0}, {1}, {2}) is not loaded!")]
+    ChunkNotLoaded(i32, i32, i32),
+    #[error("The chunk data does not start with the expected magic bytes")]
+    InvalidChunkMagic,
+    #[error("The chunk data has unsupported format version {0}")]
+    UnsupportedChunkVersion(u8),
+    #[error("The chunk data has an invalid palette size of {0}")]
+    InvalidPaletteSize(u16),
+    #[error("The chunk data references palette entry {0}, which does not exist")]
+    PaletteIndexOutOfRange(u16),
+    #[error("The chunk data contains an invalid run length of {0}")]
+    InvalidRunLength(u16),
+    #[error("The chunk data ended unexpectedly")]
+    UnexpectedEndOfData,
+    #[error("The name table does not start with the expected magic bytes")]
+    InvalidNameTableMagic,
+    #[error("The name table has unsupported format version {0}")]
+    UnsupportedNameTableVersion(u8),
+    #[error("Every voxel ID in the name table is in use")]
+    NameTableFull,
+    #[error("The voxel name \"{0}\" is invalid")]
+    InvalidVoxelName(String),
+    #[error("The block state property \"{0}\" has an invalid name or values")]
+    InvalidPropertyDefinition(String),
+    #[error("The block state property \"{0}\" has already been defined")]
+    PropertyAlreadyDefined(String),
+    #[error("The block state property \"{0}\" does not fit into the voxel data")]
+    BlockStateTooLarge(String),
+    #[error("A block state schema has already been registered for ID {0}")]
+    SchemaAlreadyRegistered(u16),
+    #[error("The block state has no property \"{0}\"")]
+    PropertyMissing(String),
+    #[error("\"{1}\" is not a valid value for the block state property \"{0}\"")]
+    InvalidPropertyValue(String, String),
+    #[error("The block state \"{0}\" could not be parsed")]
+    InvalidBlockState(String),
+    #[error("The voxel data {0:#06x} is not a valid block state")]
+    InvalidStateData(u16),
+    #[error(
+        "A component has already been registered with the same type or name! Component type: {0}"
+    )]
+    ComponentAlreadyRegistered(&'static str),
+    #[error("The block entity data does not start with the expected magic bytes")]
+    InvalidBlockEntityMagic,
+    #[error("The block entity data has unsupported format version {0}")]
+    UnsupportedBlockEntityVersion(u8),
+    #[error("The block entity data contains an invalid or repeated voxel index {0}")]
+    InvalidBlockEntityPosition(u16),
+    #[error("The data of the component \"{0}\" is invalid")]
+    InvalidComponentData(&'static str),
+    #[error("The region file {0:?} is corrupted")]
+    InvalidRegionFile(std::path::PathBuf),
+    #[error("No chunk storage has been attached")]
+    StorageMissing,
+    #[error("No chunk generator has been set")]
+    GeneratorMissing,
+    #[error("No chunk worker pool has been set")]
+    WorkerPoolMissing,
+    #[error("The chunk worker queue is full")]
+    WorkerQueueFull,
+    #[error("Loading or generating the chunk at ({0}, {1}, {2}) panicked: {3}")]
+    ChunkWorkerPanicked(i32, i32, i32, String),
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
 }
 
 /// One block in a chunk
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub struct Voxel {
     /// Represents the type of this voxel
     pub id: u16,
@@ -55,13 +143,47 @@ pub enum Event {
         coords_y: i32,
         coords_z: i32,
     },
+    ChunkUnloaded {
+        coords_x: i32,
+        coords_y: i32,
+        coords_z: i32,
+    },
+    /// A single voxel was replaced, the coordinates are global coordinates
+    VoxelChanged {
+        global_x: i32,
+        global_y: i32,
+        global_z: i32,
+        old_voxel: Voxel,
+        new_voxel: Voxel,
+    },
+}
+
+/// A chunk that failed to load or unload during `VoxelSystem::update`
+#[derive(Debug)]
+pub struct ChunkError {
+    pub x: i32,
+    pub y: i32,
+    pub z: i32,
+    pub error: Error,
 }
 
 pub struct VoxelSystem {
     chunks: ChunkArray<VoxelArray>,
+    /// The block entities of every loaded chunk
+    block_entities: ChunkArray<BlockEntities>,
+    components: Arc<ComponentRegistry>,
     name_registry: NameRegistry,
     attribute_registries: registry::AttributeRegistries,
+    block_states: BlockStateRegistry,
     recorded_events: Vec<Event>,
+    storage: Option<SharedStorage>,
+    placeholder_voxel: Voxel,
+    generator: Option<Arc<dyn ChunkGenerator>>,
+    seed: u64,
+    worker_pool: Option<ChunkWorkerPool>,
+    /// Chunks that have changed since they were loaded or saved
+    dirty_chunks: HashSet<(i32, i32, i32)>,
+    streamer: ChunkStreamer,
 }
 
 impl VoxelSystem {
@@ -71,9 +193,19 @@ impl VoxelSystem {
     ) -> VoxelSystem {
         VoxelSystem {
             chunks: ChunkArray::new(),
+            block_entities: ChunkArray::new(),
+            components: Arc::new(ComponentRegistry::new()),
             name_registry,
             attribute_registries,
+            block_states: BlockStateRegistry::new(),
             recorded_events: Vec::new(),
+            storage: None,
+            placeholder_voxel: Voxel { id: 0, data: 0 },
+            generator: None,
+            seed: 0,
+            worker_pool: None,
+            dirty_chunks: HashSet::new(),
+            streamer: ChunkStreamer::default(),
         }
     }
 
@@ -89,6 +221,33 @@ impl VoxelSystem {
         self.attribute_registries.get_registry::<A>()
     }
 
+    pub fn block_state_registry(&self) -> &BlockStateRegistry {
+        &self.block_states
+    }
+
+    /// Replaces the block state schemas, returning the previous ones
+    pub fn set_block_state_registry(
+        &mut self,
+        block_states: BlockStateRegistry,
+    ) -> BlockStateRegistry {
+        std::mem::replace(&mut self.block_states, block_states)
+    }
+
+    /// Looks up a voxel by its name and state, for example `find_voxel("furnace", "facing=north")`
+    pub fn find_voxel(&self, name: &str, state: &str) -> Result<Voxel, Error> {
+        let id = self
+            .name_registry
+            .find(name)
+            .ok_or_else(|| Error::NameMissing(name.to_owned()))?;
+        let data = self.block_states.schema(id).parse(state)?;
+        Ok(Voxel { id, data })
+    }
+
+    /// Formats the state of a voxel like `facing=north,half=top`, the inverse of `find_voxel`
+    pub fn format_state(&self, voxel: Voxel) -> Result<String, Error> {
+        self.block_states.schema(voxel.id).format(voxel.data)
+    }
+
     pub fn reset_events(&mut self) {
         self.recorded_events.clear();
     }
@@ -97,10 +256,24 @@ impl VoxelSystem {
         &self.recorded_events
     }
 
+    /// Adds a chunk to the system. The chunk counts as changed, since it may not exist in storage.
     pub fn load_chunk(&mut self, voxels: VoxelArray, x: i32, y: i32, z: i32) -> Result<(), Error> {
-        self.chunks
-            .try_add(voxels, x, y, z)
-            .map_err(|_| Error::ChunkAlreadyLoaded(x, y, z))?;
+        self.insert_chunk(voxels, BlockEntities::new(), x, y, z)?;
+        self.dirty_chunks.insert((x, y, z));
+        Ok(())
+    }
+
+    /// Adds a chunk without marking it as changed
+    fn insert_chunk(
+        &mut self,
+        voxels: VoxelArray,
+        entities: BlockEntities,
+        x: i32,
+        y: i32,
+        z: i32,
+    ) -> Result<(), Error> {
+        self.chunks.try_add(voxels, x, y, z)?;
+        self.block_entities.add(entities, x, y, z);
         self.recorded_events.push(Event::ChunkLoaded {
             coords_x: x,
             coords_y: y,
@@ -109,4 +282,814 @@ impl VoxelSystem {
 
         Ok(())
     }
+
+    /// Removes a chunk from the system and hands back its voxels, its block entities are dropped
+    pub fn unload_chunk(&mut self, x: i32, y: i32, z: i32) -> Result<VoxelArray, Error> {
+        let voxels = self
+            .chunks
+            .remove(x, y, z)
+            .ok_or(Error::ChunkNotLoaded(x, y, z))?;
+        self.block_entities.remove(x, y, z);
+        self.dirty_chunks.remove(&(x, y, z));
+        //Streaming loads the chunk again if it is still wanted
+        if self.streamer.streamed.contains(&(x, y, z)) {
+            self.streamer.outdated = true;
+        }
+        self.recorded_events.push(Event::ChunkUnloaded {
+            coords_x: x,
+            coords_y: y,
+            coords_z: z,
+        });
+
+        Ok(voxels)
+    }
+
+    /// Sets the storage used by `save_chunk` and `load_chunk_from_storage`, returning the previous one.
+    /// The name registry is bound to the storage, so voxel IDs are remapped
+    /// if the world was saved with different IDs.
+    pub fn attach_storage(
+        &mut self,
+        mut storage: RegionStorage,
+    ) -> Result<Option<SharedStorage>, Error> {
+        storage.bind_name_registry(&self.name_registry, self.placeholder_voxel)?;
+        storage.set_component_registry(self.components.clone());
+        Ok(self.storage.replace(Arc::new(Mutex::new(storage))))
+    }
+
+    pub fn component_registry(&self) -> &ComponentRegistry {
+        &self.components
+    }
+
+    /// Sets the block entity components that are saved with chunks, also for the attached storage
+    pub fn set_component_registry(&mut self, components: ComponentRegistry) {
+        self.components = Arc::new(components);
+        if let Some(storage) = &self.storage {
+            lock_storage(storage).set_component_registry(self.components.clone());
+        }
+    }
+
+    /// Sets the voxel that is loaded in place of voxels whose name is no longer registered,
+    /// ID 0 with data 0 by default
+    pub fn set_placeholder_voxel(&mut self, voxel: Voxel) {
+        self.placeholder_voxel = voxel;
+        if let Some(storage) = &self.storage {
+            lock_storage(storage).set_placeholder(voxel);
+        }
+    }
+
+    pub fn placeholder_voxel(&self) -> Voxel {
+        self.placeholder_voxel
+    }
+
+    pub fn detach_storage(&mut self) -> Option<SharedStorage> {
+        self.storage.take()
+    }
+
+    pub fn storage(&self) -> Option<&SharedStorage> {
+        self.storage.as_ref()
+    }
+
+    /// Writes a loaded chunk and its block entities to the attached storage
+    pub fn save_chunk(&mut self, x: i32, y: i32, z: i32) -> Result<(), Error> {
+        let voxels = self
+            .chunks
+            .get(x, y, z)
+            .ok_or(Error::ChunkNotLoaded(x, y, z))?;
+        let entities = self.block_entities.get(x, y, z).unwrap();
+        let storage = self.storage.as_ref().ok_or(Error::StorageMissing)?;
+        lock_storage(storage).save_chunk_with_entities(voxels, entities, x, y, z)?;
+        self.dirty_chunks.remove(&(x, y, z));
+        Ok(())
+    }
+
+    /// Writes every loaded chunk to the attached storage
+    pub fn save_all_chunks(&mut self) -> Result<(), Error> {
+        let mut storage = lock_storage(self.storage.as_ref().ok_or(Error::StorageMissing)?);
+        for ((x, y, z), voxels) in self.chunks.iter() {
+            let entities = self.block_entities.get(*x, *y, *z).unwrap();
+            storage.save_chunk_with_entities(voxels, entities, *x, *y, *z)?;
+        }
+        storage.flush()?;
+        drop(storage);
+        self.dirty_chunks.clear();
+        Ok(())
+    }
+
+    /// Returns true if the chunk has changed since it was loaded or saved.
+    /// Chunks from storage or the generator start out unchanged,
+    /// chunks passed to `load_chunk` start out changed.
+    pub fn is_chunk_dirty(&self, x: i32, y: i32, z: i32) -> bool {
+        self.dirty_chunks.contains(&(x, y, z))
+    }
+
+    /// Loads a chunk from the attached storage.
+    /// Returns `false` if the chunk has never been saved, in which case nothing is loaded.
+    pub fn load_chunk_from_storage(&mut self, x: i32, y: i32, z: i32) -> Result<bool, Error> {
+        if self.chunks.contains(x, y, z) {
+            return Err(Error::ChunkAlreadyLoaded(x, y, z));
+        }
+        let storage = self.storage.as_ref().ok_or(Error::StorageMissing)?;
+        let loaded = lock_storage(storage).load_chunk_with_entities(x, y, z)?;
+        match loaded {
+            Some((voxels, entities)) => {
+                self.insert_chunk(voxels, entities, x, y, z)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Sets the generator used by `generate_chunk` and the seed it is called with
+    pub fn set_generator(&mut self, generator: Arc<dyn ChunkGenerator>, seed: u64) {
+        self.generator = Some(generator);
+        self.seed = seed;
+    }
+
+    pub fn generator(&self) -> Option<&Arc<dyn ChunkGenerator>> {
+        self.generator.as_ref()
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Generates a chunk with the generator and loads it
+    pub fn generate_chunk(&mut self, x: i32, y: i32, z: i32) -> Result<(), Error> {
+        if self.chunks.contains(x, y, z) {
+            return Err(Error::ChunkAlreadyLoaded(x, y, z));
+        }
+        let generator = self.generator.as_ref().ok_or(Error::GeneratorMissing)?;
+        let voxels = generator.generate(x, y, z, self.seed);
+        self.insert_chunk(voxels, BlockEntities::new(), x, y, z)
+    }
+
+    /// Loads a chunk from the attached storage if it has been saved, otherwise generates it.
+    /// Storage is optional, but a generator is required for chunks that are not in storage.
+    pub fn load_or_generate_chunk(&mut self, x: i32, y: i32, z: i32) -> Result<(), Error> {
+        if self.storage.is_some() && self.load_chunk_from_storage(x, y, z)? {
+            return Ok(());
+        }
+        self.generate_chunk(x, y, z)
+    }
+
+    /// Sets the worker pool used by `request_chunk`, returning the previous one
+    pub fn set_worker_pool(&mut self, pool: ChunkWorkerPool) -> Option<ChunkWorkerPool> {
+        self.streamer.outdated = true;
+        self.worker_pool.replace(pool)
+    }
+
+    pub fn worker_pool(&self) -> Option<&ChunkWorkerPool> {
+        self.worker_pool.as_ref()
+    }
+
+    /// Queues a chunk to be loaded from storage or generated on a worker thread.
+    /// The chunk is loaded by `update` once it is ready, lower priority values are processed first.
+    pub fn request_chunk(&mut self, x: i32, y: i32, z: i32, priority: u32) -> Result<(), Error> {
+        if self.chunks.contains(x, y, z) {
+            return Err(Error::ChunkAlreadyLoaded(x, y, z));
+        }
+        let pool = self.worker_pool.as_ref().ok_or(Error::WorkerPoolMissing)?;
+        let source = ChunkSource {
+            storage: self.storage.clone(),
+            generator: self.generator.clone(),
+            seed: self.seed,
+        };
+        pool.request(x, y, z, priority, source)
+    }
+
+    /// Cancels a chunk request, returns false if the chunk was not requested
+    pub fn cancel_chunk_request(&mut self, x: i32, y: i32, z: i32) -> bool {
+        match &self.worker_pool {
+            Some(pool) => pool.cancel(x, y, z),
+            None => false,
+        }
+    }
+
+    /// Registers a point that chunks are loaded around by `update`.
+    /// `x`, `y` and `z` are chunk coordinates.
+    pub fn add_focus_point(&mut self, x: i32, y: i32, z: i32, radius: u32) -> FocusPointId {
+        self.streamer.add_focus_point(x, y, z, radius)
+    }
+
+    /// Moves a focus point, returns false if it does not exist
+    pub fn move_focus_point(&mut self, id: FocusPointId, x: i32, y: i32, z: i32) -> bool {
+        self.streamer.move_focus_point(id, x, y, z)
+    }
+
+    /// Changes the load radius of a focus point, returns false if it does not exist
+    pub fn set_focus_radius(&mut self, id: FocusPointId, radius: u32) -> bool {
+        self.streamer.set_focus_radius(id, radius)
+    }
+
+    /// Removes a focus point,
+    /// its chunks are unloaded by the next `update` unless another point keeps them
+    pub fn remove_focus_point(&mut self, id: FocusPointId) -> bool {
+        self.streamer.remove_focus_point(id)
+    }
+
+    pub fn streaming_settings(&self) -> &StreamingSettings {
+        &self.streamer.settings
+    }
+
+    pub fn set_streaming_settings(&mut self, settings: StreamingSettings) {
+        self.streamer.settings = settings;
+        self.streamer.outdated = true;
+    }
+
+    /// Loads chunks that the worker pool has finished since the last update,
+    /// then loads and unloads chunks around the focus points.
+    ///
+    /// Wanted chunks are requested from the worker pool, closest first, and chunks that are already
+    /// queued are only requested again when their distance changed. Without a worker pool
+    /// a few of them are loaded or generated on the calling thread every update instead.
+    /// Changed chunks are saved before they are unloaded if storage is attached.
+    /// Chunks that fail to load are not retried until they stop being wanted.
+    /// Updates where nothing changed since the last one don't go over the wanted chunks at all.
+    pub fn update(&mut self) -> Vec<ChunkError> {
+        let mut errors = Vec::new();
+        self.load_finished_chunks(&mut errors);
+        if self.streamer.outdated {
+            self.streamer.outdated = false;
+            self.unload_distant_chunks(&mut errors);
+            self.load_wanted_chunks(&mut errors);
+        }
+        errors
+    }
+
+    fn load_finished_chunks(&mut self, errors: &mut Vec<ChunkError>) {
+        let finished = match &self.worker_pool {
+            Some(pool) => pool.poll(),
+            None => return,
+        };
+
+        for FinishedChunk { x, y, z, result } in finished {
+            self.streamer.outdated = true;
+            match result {
+                Ok((voxels, entities)) => {
+                    if !self.chunks.contains(x, y, z) {
+                        self.insert_chunk(voxels, entities, x, y, z).unwrap();
+                    }
+                }
+                Err(error) => {
+                    if self.streamer.streamed.remove(&(x, y, z)) {
+                        self.streamer.failed.insert((x, y, z));
+                    }
+                    errors.push(ChunkError { x, y, z, error });
+                }
+            }
+        }
+    }
+
+    fn unload_distant_chunks(&mut self, errors: &mut Vec<ChunkError>) {
+        let distant: Vec<(i32, i32, i32)> = self
+            .streamer
+            .streamed
+            .iter()
+            .filter(|(x, y, z)| !self.streamer.is_retained(*x, *y, *z))
+            .copied()
+            .collect();
+
+        for (x, y, z) in distant {
+            if self.chunks.contains(x, y, z) {
+                if self.storage.is_some() && self.is_chunk_dirty(x, y, z) {
+                    if let Err(error) = self.save_chunk(x, y, z) {
+                        //Saving is tried again by the next update
+                        self.streamer.outdated = true;
+                        errors.push(ChunkError { x, y, z, error });
+                        continue;
+                    }
+                }
+                self.streamer.streamed.remove(&(x, y, z));
+                self.unload_chunk(x, y, z).unwrap();
+            } else {
+                self.streamer.streamed.remove(&(x, y, z));
+                self.cancel_chunk_request(x, y, z);
+            }
+        }
+    }
+
+    fn load_wanted_chunks(&mut self, errors: &mut Vec<ChunkError>) {
+        let wanted = self.streamer.wanted_chunks();
+        self.streamer
+            .failed
+            .retain(|coords| wanted.coords.contains(coords));
+
+        let mut sync_loads = 0;
+        for &((x, y, z), distance_squared) in &wanted.sorted {
+            if self.chunks.contains(x, y, z) || self.streamer.failed.contains(&(x, y, z)) {
+                continue;
+            }
+
+            let result = if let Some(pool) = &self.worker_pool {
+                let priority = distance_squared.min(u32::MAX as u64) as u32;
+                if pool.requested_priority(x, y, z) == Some(priority) {
+                    continue;
+                }
+                match self.request_chunk(x, y, z, priority) {
+                    Err(Error::WorkerQueueFull) => {
+                        self.streamer.outdated = true;
+                        break;
+                    }
+                    result => result,
+                }
+            } else {
+                if sync_loads == self.streamer.settings.sync_loads_per_update {
+                    self.streamer.outdated = true;
+                    break;
+                }
+                sync_loads += 1;
+                self.load_or_generate_chunk(x, y, z)
+            };
+
+            match result {
+                Ok(()) => {
+                    self.streamer.streamed.insert((x, y, z));
+                }
+                Err(error) => {
+                    self.streamer.failed.insert((x, y, z));
+                    errors.push(ChunkError { x, y, z, error });
+                }
+            }
+        }
+    }
+
+    /// Returns the voxel at the given global coordinates, or `None` if its chunk is not loaded
+    pub fn get_voxel(&self, x: i32, y: i32, z: i32) -> Option<Voxel> {
+        let (xyz_local, xyz_chunk) = coords::global_to_local(x, y, z);
+        let chunk = self.chunks.get(xyz_chunk.0, xyz_chunk.1, xyz_chunk.2)?;
+        Some(*chunk.get_voxel_at_position(
+            xyz_local.0 as usize,
+            xyz_local.1 as usize,
+            xyz_local.2 as usize,
+        ))
+    }
+
+    /// Replaces the voxel at the given global coordinates and returns the old voxel.
+    /// If the voxel actually changed, a `VoxelChanged` event is recorded
+    /// and the block entity at the position is removed.
+    ///
+    /// If the voxel type has a block state schema, its data has to be a valid state of it.
+    pub fn set_voxel(&mut self, x: i32, y: i32, z: i32, voxel: Voxel) -> Result<Voxel, Error> {
+        self.replace_voxel(x, y, z, voxel, false)
+    }
+
+    /// Replaces only the data of the voxel at the given global coordinates and returns the old voxel.
+    /// Unlike `set_voxel` this keeps the block entity, for example when a chest is turned around.
+    pub fn set_voxel_data(&mut self, x: i32, y: i32, z: i32, data: u16) -> Result<Voxel, Error> {
+        let (_, xyz_chunk) = coords::global_to_local(x, y, z);
+        let id = self
+            .get_voxel(x, y, z)
+            .ok_or(Error::ChunkNotLoaded(xyz_chunk.0, xyz_chunk.1, xyz_chunk.2))?
+            .id;
+        self.replace_voxel(x, y, z, Voxel { id, data }, true)
+    }
+
+    fn replace_voxel(
+        &mut self,
+        x: i32,
+        y: i32,
+        z: i32,
+        voxel: Voxel,
+        keep_entity: bool,
+    ) -> Result<Voxel, Error> {
+        if let Some(schema) = self.block_states.find(voxel.id) {
+            schema.validate(voxel.data)?;
+        }
+        let (xyz_local, xyz_chunk) = coords::global_to_local(x, y, z);
+        let chunk = self
+            .chunks
+            .get_mut(xyz_chunk.0, xyz_chunk.1, xyz_chunk.2)
+            .ok_or(Error::ChunkNotLoaded(xyz_chunk.0, xyz_chunk.1, xyz_chunk.2))?;
+        let old_voxel = chunk.set_voxel_at_position(
+            xyz_local.0 as usize,
+            xyz_local.1 as usize,
+            xyz_local.2 as usize,
+            voxel,
+        );
+
+        if old_voxel != voxel {
+            if !keep_entity {
+                let entities = self
+                    .block_entities
+                    .get_mut(xyz_chunk.0, xyz_chunk.1, xyz_chunk.2);
+                if let Some(entities) = entities {
+                    entities.remove(
+                        xyz_local.0 as usize,
+                        xyz_local.1 as usize,
+                        xyz_local.2 as usize,
+                    );
+                }
+            }
+            self.dirty_chunks.insert(xyz_chunk);
+            self.recorded_events.push(Event::VoxelChanged {
+                global_x: x,
+                global_y: y,
+                global_z: z,
+                old_voxel,
+                new_voxel: voxel,
+            });
+        }
+
+        Ok(old_voxel)
+    }
+
+    /// Returns the block entities of a loaded chunk
+    pub fn get_block_entities(&self, x: i32, y: i32, z: i32) -> Option<&BlockEntities> {
+        self.block_entities.get(x, y, z)
+    }
+
+    /// Returns the block entity at the given global coordinates
+    pub fn get_block_entity(&self, x: i32, y: i32, z: i32) -> Option<&BlockEntity> {
+        let (xyz_local, xyz_chunk) = coords::global_to_local(x, y, z);
+        self.block_entities
+            .get(xyz_chunk.0, xyz_chunk.1, xyz_chunk.2)?
+            .get(
+                xyz_local.0 as usize,
+                xyz_local.1 as usize,
+                xyz_local.2 as usize,
+            )
+    }
+
+    /// Gives mutable access to the block entity at the given global coordinates.
+    /// The chunk is marked as changed, since the block entity may be modified.
+    pub fn get_block_entity_mut(&mut self, x: i32, y: i32, z: i32) -> Option<&mut BlockEntity> {
+        let (xyz_local, xyz_chunk) = coords::global_to_local(x, y, z);
+        let entity = self
+            .block_entities
+            .get_mut(xyz_chunk.0, xyz_chunk.1, xyz_chunk.2)?
+            .get_mut(
+                xyz_local.0 as usize,
+                xyz_local.1 as usize,
+                xyz_local.2 as usize,
+            )?;
+        self.dirty_chunks.insert(xyz_chunk);
+        Some(entity)
+    }
+
+    /// Removes the block entity at the given global coordinates and returns it
+    pub fn remove_block_entity(&mut self, x: i32, y: i32, z: i32) -> Option<BlockEntity> {
+        let (xyz_local, xyz_chunk) = coords::global_to_local(x, y, z);
+        let entity = self
+            .block_entities
+            .get_mut(xyz_chunk.0, xyz_chunk.1, xyz_chunk.2)?
+            .remove(
+                xyz_local.0 as usize,
+                xyz_local.1 as usize,
+                xyz_local.2 as usize,
+            )?;
+        self.dirty_chunks.insert(xyz_chunk);
+        Some(entity)
+    }
+
+    pub fn get_component<C: Component>(&self, x: i32, y: i32, z: i32) -> Option<&C> {
+        self.get_block_entity(x, y, z)?.get::<C>()
+    }
+
+    /// Gives mutable access to a component at the given global coordinates and marks the chunk as changed
+    pub fn get_component_mut<C: Component>(&mut self, x: i32, y: i32, z: i32) -> Option<&mut C> {
+        self.get_block_entity_mut(x, y, z)?.get_mut::<C>()
+    }
+
+    /// Adds a component to the block entity at the given global coordinates, creating the block entity
+    /// if needed. Returns the previous component of the same type.
+    pub fn set_component<C: Component>(
+        &mut self,
+        x: i32,
+        y: i32,
+        z: i32,
+        component: C,
+    ) -> Result<Option<C>, Error> {
+        let (xyz_local, xyz_chunk) = coords::global_to_local(x, y, z);
+        let entities = self
+            .block_entities
+            .get_mut(xyz_chunk.0, xyz_chunk.1, xyz_chunk.2)
+            .ok_or(Error::ChunkNotLoaded(xyz_chunk.0, xyz_chunk.1, xyz_chunk.2))?;
+        let entity = entities.get_or_insert(
+            xyz_local.0 as usize,
+            xyz_local.1 as usize,
+            xyz_local.2 as usize,
+        );
+        self.dirty_chunks.insert(xyz_chunk);
+        Ok(entity.insert(component))
+    }
+
+    /// Removes a component at the given global coordinates,
+    /// the block entity is removed as well once it has no components left
+    pub fn remove_component<C: Component>(&mut self, x: i32, y: i32, z: i32) -> Option<C> {
+        let (xyz_local, xyz_chunk) = coords::global_to_local(x, y, z);
+        let (local_x, local_y, local_z) = (
+            xyz_local.0 as usize,
+            xyz_local.1 as usize,
+            xyz_local.2 as usize,
+        );
+        let entities = self
+            .block_entities
+            .get_mut(xyz_chunk.0, xyz_chunk.1, xyz_chunk.2)?;
+        let entity = entities.get_mut(local_x, local_y, local_z)?;
+        let component = entity.remove::<C>()?;
+        if entity.is_empty() {
+            entities.remove(local_x, local_y, local_z);
+        }
+        self.dirty_chunks.insert(xyz_chunk);
+        Some(component)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Condvar;
+    use std::time::Duration;
+
+    /// Generates chunks filled with stone
+    struct StoneGenerator;
+
+    impl ChunkGenerator for StoneGenerator {
+        fn generate(&self, _x: i32, _y: i32, _z: i32, _seed: u64) -> VoxelArray {
+            VoxelArray::new(Voxel { id: 1, data: 0 })
+        }
+    }
+
+    /// Generates chunks filled with stone once the gate is opened, or after waiting for ten seconds
+    struct GatedGenerator {
+        gate_open: Mutex<bool>,
+        gate_opened: Condvar,
+    }
+
+    impl ChunkGenerator for GatedGenerator {
+        fn generate(&self, _x: i32, _y: i32, _z: i32, _seed: u64) -> VoxelArray {
+            let open = self.gate_open.lock().unwrap();
+            let _ = self
+                .gate_opened
+                .wait_timeout_while(open, Duration::from_secs(10), |open| !*open)
+                .unwrap();
+            VoxelArray::new(Voxel { id: 1, data: 0 })
+        }
+    }
+
+    fn streaming_system(unload_margin: u32, sync_loads_per_update: usize) -> VoxelSystem {
+        let mut system =
+            VoxelSystem::new(NameRegistry::new(), registry::AttributeRegistries::new());
+        system.set_generator(Arc::new(StoneGenerator), 0);
+        system.set_streaming_settings(StreamingSettings {
+            unload_margin,
+            sync_loads_per_update,
+        });
+        system
+    }
+
+    fn update(system: &mut VoxelSystem) {
+        system.reset_events();
+        assert!(system.update().is_empty());
+    }
+
+    fn loaded_events(system: &VoxelSystem) -> Vec<(i32, i32, i32)> {
+        system
+            .get_events()
+            .iter()
+            .filter_map(|event| match event {
+                Event::ChunkLoaded {
+                    coords_x,
+                    coords_y,
+                    coords_z,
+                } => Some((*coords_x, *coords_y, *coords_z)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn unloaded_events(system: &VoxelSystem) -> Vec<(i32, i32, i32)> {
+        system
+            .get_events()
+            .iter()
+            .filter_map(|event| match event {
+                Event::ChunkUnloaded {
+                    coords_x,
+                    coords_y,
+                    coords_z,
+                } => Some((*coords_x, *coords_y, *coords_z)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn block_entities_are_removed_when_the_voxel_changes() {
+        let mut system =
+            VoxelSystem::new(NameRegistry::new(), registry::AttributeRegistries::new());
+        system
+            .load_chunk(VoxelArray::new(Voxel { id: 0, data: 0 }), 0, 0, 0)
+            .unwrap();
+        system.set_voxel(1, 2, 3, Voxel { id: 5, data: 0 }).unwrap();
+        system.set_component(1, 2, 3, 42u32).unwrap();
+
+        //Setting the same voxel again changes nothing
+        system.set_voxel(1, 2, 3, Voxel { id: 5, data: 0 }).unwrap();
+        assert_eq!(system.get_component::<u32>(1, 2, 3), Some(&42));
+
+        //Changing only the data keeps the block entity if asked to
+        let old_voxel = system.set_voxel_data(1, 2, 3, 3).unwrap();
+        assert_eq!(old_voxel, Voxel { id: 5, data: 0 });
+        assert_eq!(system.get_voxel(1, 2, 3), Some(Voxel { id: 5, data: 3 }));
+        assert_eq!(system.get_component::<u32>(1, 2, 3), Some(&42));
+        assert!(system.set_voxel_data(1, 2, 16, 3).is_err());
+
+        //Any other change removes it, even with the same ID
+        system.set_voxel(1, 2, 3, Voxel { id: 5, data: 1 }).unwrap();
+        assert!(system.get_block_entity(1, 2, 3).is_none());
+        system.set_component(1, 2, 3, 42u32).unwrap();
+        system.set_voxel(1, 2, 3, Voxel { id: 6, data: 1 }).unwrap();
+        assert!(system.get_block_entity(1, 2, 3).is_none());
+        //Setting the old voxel again doesn't bring it back
+        system.set_voxel(1, 2, 3, Voxel { id: 5, data: 1 }).unwrap();
+        assert!(system.get_block_entity(1, 2, 3).is_none());
+    }
+
+    #[test]
+    fn set_voxel_checks_data_against_the_block_state_schema() {
+        let mut system =
+            VoxelSystem::new(NameRegistry::new(), registry::AttributeRegistries::new());
+        let mut schema = BlockStateSchema::new();
+        schema
+            .add_enum("facing", &["north", "east", "south"])
+            .unwrap();
+        let mut block_states = BlockStateRegistry::new();
+        block_states.register(7, schema).unwrap();
+        system.set_block_state_registry(block_states);
+        system
+            .load_chunk(VoxelArray::new(Voxel { id: 0, data: 0 }), 0, 0, 0)
+            .unwrap();
+
+        system.set_voxel(0, 0, 0, Voxel { id: 7, data: 2 }).unwrap();
+        for data in [3, 4, 0x8000] {
+            assert!(matches!(
+                system.set_voxel(0, 0, 0, Voxel { id: 7, data }),
+                Err(Error::InvalidStateData(d)) if d == data
+            ));
+        }
+        assert_eq!(system.get_voxel(0, 0, 0), Some(Voxel { id: 7, data: 2 }));
+        assert_eq!(system.get_events().len(), 2);
+
+        //Voxel types without a schema are not checked
+        system
+            .set_voxel(
+                0,
+                0,
+                0,
+                Voxel {
+                    id: 8,
+                    data: 0xFFFF,
+                },
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn streaming_loads_the_closest_chunks_first() {
+        let mut system = streaming_system(1, 7);
+        system.add_focus_point(0, 0, 0, 2);
+
+        //The focus chunk and its six neighbours come before anything further away
+        update(&mut system);
+        let mut first = loaded_events(&system);
+        assert_eq!(first[0], (0, 0, 0));
+        first.sort_unstable();
+        assert_eq!(
+            first,
+            vec![
+                (-1, 0, 0),
+                (0, -1, 0),
+                (0, 0, -1),
+                (0, 0, 0),
+                (0, 0, 1),
+                (0, 1, 0),
+                (1, 0, 0)
+            ]
+        );
+
+        let mut distances = Vec::new();
+        while !system.get_events().is_empty() {
+            update(&mut system);
+            distances.extend(
+                loaded_events(&system)
+                    .into_iter()
+                    .map(|(x, y, z)| x * x + y * y + z * z),
+            );
+        }
+        assert!(distances.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(*distances.last().unwrap(), 4);
+    }
+
+    #[test]
+    fn streaming_unloads_chunks_beyond_the_margin() {
+        let mut system = streaming_system(1, usize::MAX);
+        let id = system.add_focus_point(0, 0, 0, 1);
+        update(&mut system);
+        assert!(system.get_chunk(-1, 0, 0).is_some());
+
+        //(-1, 0, 0) is within the radius plus the margin and stays loaded
+        system.move_focus_point(id, 1, 0, 0);
+        update(&mut system);
+        assert!(unloaded_events(&system).is_empty());
+        assert!(system.get_chunk(-1, 0, 0).is_some());
+
+        //Moving back doesn't reload anything
+        system.move_focus_point(id, 0, 0, 0);
+        update(&mut system);
+        assert!(system.get_events().is_empty());
+
+        system.move_focus_point(id, 2, 0, 0);
+        update(&mut system);
+        let mut unloaded = unloaded_events(&system);
+        unloaded.sort_unstable();
+        assert_eq!(
+            unloaded,
+            vec![(-1, 0, 0), (0, -1, 0), (0, 0, -1), (0, 0, 1), (0, 1, 0)]
+        );
+        assert!(system.get_chunk(0, 0, 0).is_some());
+    }
+
+    #[test]
+    fn overlapping_focus_points_share_chunks() {
+        let mut system = streaming_system(0, usize::MAX);
+        let left = system.add_focus_point(0, 0, 0, 1);
+        let right = system.add_focus_point(1, 0, 0, 1);
+        update(&mut system);
+
+        //Both points want (0, 0, 0) and (1, 0, 0), they are loaded once
+        let loaded = loaded_events(&system);
+        let unique: HashSet<_> = loaded.iter().copied().collect();
+        assert_eq!(loaded.len(), unique.len());
+        assert_eq!(loaded.len(), 12);
+
+        //Chunks are kept as long as one of the points still wants them
+        system.remove_focus_point(left);
+        update(&mut system);
+        let mut unloaded = unloaded_events(&system);
+        unloaded.sort_unstable();
+        assert_eq!(
+            unloaded,
+            vec![(-1, 0, 0), (0, -1, 0), (0, 0, -1), (0, 0, 1), (0, 1, 0)]
+        );
+        assert!(system.get_chunk(0, 0, 0).is_some());
+
+        system.remove_focus_point(right);
+        update(&mut system);
+        assert_eq!(unloaded_events(&system).len(), 7);
+        assert_eq!(system.chunks.iter().count(), 0);
+    }
+
+    #[test]
+    fn streaming_leaves_chunks_loaded_by_hand() {
+        let mut system = streaming_system(0, usize::MAX);
+        system
+            .load_chunk(VoxelArray::new(Voxel { id: 2, data: 0 }), 0, 0, 0)
+            .unwrap();
+        let id = system.add_focus_point(0, 0, 0, 1);
+        update(&mut system);
+        system.move_focus_point(id, 10, 0, 0);
+        update(&mut system);
+
+        assert_eq!(unloaded_events(&system).len(), 6);
+        assert_eq!(system.get_voxel(0, 0, 0), Some(Voxel { id: 2, data: 0 }));
+    }
+
+    #[test]
+    fn updates_without_changes_request_nothing() {
+        let generator = Arc::new(GatedGenerator {
+            gate_open: Mutex::new(false),
+            gate_opened: Condvar::new(),
+        });
+        let mut system = streaming_system(0, 0);
+        system.set_generator(generator.clone(), 0);
+        system.set_worker_pool(ChunkWorkerPool::new(1, 64));
+        let requested = |system: &VoxelSystem| {
+            let pool = system.worker_pool().unwrap();
+            (-1..=1)
+                .flat_map(|x| (-1..=1).flat_map(move |y| (-1..=1).map(move |z| (x, y, z))))
+                .filter(|(x, y, z)| pool.is_requested(*x, *y, *z))
+                .count()
+        };
+
+        let id = system.add_focus_point(0, 0, 0, 1);
+        update(&mut system);
+        assert_eq!(requested(&system), 7);
+
+        //Nothing changed and no chunk finished, so cancelled requests are not made again
+        system.worker_pool().unwrap().cancel_all();
+        for _ in 0..3 {
+            update(&mut system);
+            assert_eq!(requested(&system), 0);
+        }
+        system.move_focus_point(id, 0, 0, 0);
+        update(&mut system);
+        assert_eq!(requested(&system), 0);
+
+        system.set_focus_radius(id, 0);
+        update(&mut system);
+        assert_eq!(requested(&system), 1);
+
+        *generator.gate_open.lock().unwrap() = true;
+        generator.gate_opened.notify_all();
+    }
 }
@@ -0,0 +1,252 @@
+//! Casting rays through the voxel grid
+//!
+//! Rays are walked one voxel at a time with the algorithm by Amanatides and Woo,
+//! "A Fast Voxel Traversal Algorithm for Ray Tracing". Every voxel the ray touches is visited
+//! in order, so thin walls and corners are never skipped. Positions are in global voxel coordinates,
+//! where the voxel at `(x, y, z)` spans from `(x, y, z)` to `(x + 1, y + 1, z + 1)`.
+
+//Uses
+use super::{Voxel, VoxelSystem};
+use crate::world::coords::{Direction, GlobalPos};
+use cgmath::{InnerSpace, Point3, Vector3};
+
+#[derive(Clone, Copy, Debug)]
+pub struct RaycastHit {
+    pub position: GlobalPos,
+    pub voxel: Voxel,
+    /// The face of the hit voxel the ray entered through, `None` if the ray started inside of it
+    pub face: Option<Direction>,
+    /// Distance from the origin to the point where the ray entered the hit voxel
+    pub distance: f32,
+    /// The last voxel the ray passed through before the hit, where a new voxel would be placed.
+    /// `None` if the ray started inside of the hit voxel.
+    pub previous: Option<GlobalPos>,
+}
+
+impl VoxelSystem {
+    /// Finds the first voxel along a ray for which `is_solid` returns true.
+    ///
+    /// `direction` does not need to be normalized, distances are measured in voxels.
+    /// Returns `None` if nothing solid is hit within `max_distance`, or if the ray reaches a chunk
+    /// that is not loaded first.
+    ///
+    /// `is_solid` usually looks up an attribute of the voxel, for example
+    /// `|_, voxel| registry.find(voxel.id).map_or(false, |attribute| attribute.solid)`.
+    pub fn raycast<F>(
+        &self,
+        origin: Point3<f32>,
+        direction: Vector3<f32>,
+        max_distance: f32,
+        mut is_solid: F,
+    ) -> Option<RaycastHit>
+    where
+        F: FnMut(GlobalPos, Voxel) -> bool,
+    {
+        let origin = origin.cast::<f64>()?;
+        let direction = direction.cast::<f64>()?;
+        let length = direction.magnitude();
+        if length == 0.0 || !length.is_finite() {
+            return None;
+        }
+        let direction = direction / length;
+        let max_distance = max_distance as f64;
+
+        let mut cell = [0i32; 3];
+        let mut step = [0i32; 3];
+        //Distance along the ray to the next voxel boundary on each axis
+        let mut t_max = [f64::INFINITY; 3];
+        //Distance along the ray between voxel boundaries on each axis
+        let mut t_delta = [f64::INFINITY; 3];
+        for axis in 0..3 {
+            let start = origin[axis].floor();
+            if !(i32::MIN as f64..=i32::MAX as f64).contains(&start) {
+                return None;
+            }
+            cell[axis] = start as i32;
+            if direction[axis] > 0.0 {
+                step[axis] = 1;
+                t_delta[axis] = 1.0 / direction[axis];
+                t_max[axis] = (start + 1.0 - origin[axis]) * t_delta[axis];
+            } else if direction[axis] < 0.0 {
+                step[axis] = -1;
+                t_delta[axis] = -1.0 / direction[axis];
+                t_max[axis] = (origin[axis] - start) * t_delta[axis];
+            }
+        }
+
+        let mut distance = 0.0;
+        let mut face = None;
+        let mut previous = None;
+        loop {
+            let position = GlobalPos::new(cell[0], cell[1], cell[2]);
+            let voxel = self.get_voxel(position.x, position.y, position.z)?;
+            if is_solid(position, voxel) {
+                return Some(RaycastHit {
+                    position,
+                    voxel,
+                    face,
+                    distance: distance as f32,
+                    previous,
+                });
+            }
+
+            let axis = (0..3)
+                .min_by(|a, b| t_max[*a].total_cmp(&t_max[*b]))
+                .unwrap();
+            if t_max[axis] > max_distance {
+                return None;
+            }
+
+            distance = t_max[axis];
+            t_max[axis] += t_delta[axis];
+            cell[axis] = cell[axis].checked_add(step[axis])?;
+            previous = Some(position);
+            //Moving in a positive direction enters the new voxel through its negative face and vice versa
+            let mut offset = (0, 0, 0);
+            match axis {
+                0 => offset.0 = -step[axis],
+                1 => offset.1 = -step[axis],
+                _ => offset.2 = -step[axis],
+            }
+            face = Direction::from_offset(offset);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::test_util::{voxel_system, AIR, STONE};
+    use crate::world::voxel::AttributeRegistries;
+
+    /// Air chunks from -1 to 1 on x and from -1 to 0 on y and z
+    fn world() -> VoxelSystem {
+        voxel_system(
+            AttributeRegistries::new(),
+            &[((-1, -1, -1), (1, 0, 0), AIR)],
+        )
+    }
+
+    fn cast(
+        system: &VoxelSystem,
+        origin: (f32, f32, f32),
+        direction: (f32, f32, f32),
+        max_distance: f32,
+    ) -> Option<RaycastHit> {
+        system.raycast(
+            Point3::new(origin.0, origin.1, origin.2),
+            Vector3::new(direction.0, direction.1, direction.2),
+            max_distance,
+            |_, voxel| voxel != AIR,
+        )
+    }
+
+    #[test]
+    fn rays_hit_voxels_at_negative_coordinates() {
+        let mut system = world();
+        system.set_voxel(-5, -3, -7, STONE).unwrap();
+
+        let hit = cast(&system, (-4.5, -2.5, -1.5), (0.0, 0.0, -1.0), 100.0).unwrap();
+        assert_eq!(hit.position, GlobalPos::new(-5, -3, -7));
+        assert_eq!(hit.voxel, STONE);
+        assert_eq!(hit.face, Some(Direction::North));
+        assert_eq!(hit.distance, 4.5);
+        assert_eq!(hit.previous, Some(GlobalPos::new(-5, -3, -6)));
+
+        //Origins on a boundary belong to the voxel on the positive side of it
+        let hit = cast(&system, (-5.0, -3.0, -1.5), (0.0, 0.0, -1.0), 100.0).unwrap();
+        assert_eq!(hit.position, GlobalPos::new(-5, -3, -7));
+        assert!(cast(&system, (-4.0, -2.0, -1.5), (0.0, 0.0, -1.0), 100.0).is_none());
+
+        let hit = cast(&system, (-0.5, -0.5, -0.5), (-4.5, -2.5, -6.5), 100.0).unwrap();
+        assert_eq!(hit.position, GlobalPos::new(-5, -3, -7));
+    }
+
+    #[test]
+    fn rays_cross_chunk_borders() {
+        let mut system = world();
+        system.set_voxel(20, -1, -1, STONE).unwrap();
+
+        let hit = cast(&system, (-10.5, -0.5, -0.5), (1.0, 0.0, 0.0), 100.0).unwrap();
+        assert_eq!(hit.position, GlobalPos::new(20, -1, -1));
+        assert_eq!(hit.face, Some(Direction::West));
+        assert_eq!(hit.distance, 30.5);
+        assert_eq!(hit.previous, Some(GlobalPos::new(19, -1, -1)));
+
+        //The ray stops at the first chunk that isn't loaded
+        assert!(cast(&system, (-10.5, 0.5, 0.5), (1.0, 0.0, 0.0), 1000.0).is_none());
+        assert!(cast(&system, (0.5, -0.5, 0.5), (0.0, 1.0, 0.0), 1000.0).is_none());
+    }
+
+    #[test]
+    fn diagonal_rays_visit_every_voxel_they_touch() {
+        let system = world();
+        let mut visited = Vec::new();
+        let hit = system.raycast(
+            Point3::new(-3.2, -2.7, -1.1),
+            Vector3::new(1.0, 0.8, 0.3),
+            8.0,
+            |position, _| {
+                visited.push(position);
+                false
+            },
+        );
+        assert!(hit.is_none());
+
+        //Every step moves to a neighbor, including across the borders at 0
+        assert_eq!(visited[0], GlobalPos::new(-4, -3, -2));
+        for pair in visited.windows(2) {
+            let (a, b) = (pair[0], pair[1]);
+            let moved = (b.x - a.x).abs() + (b.y - a.y).abs() + (b.z - a.z).abs();
+            assert_eq!(moved, 1, "{:?} to {:?}", a, b);
+        }
+        assert_eq!(*visited.last().unwrap(), GlobalPos::new(2, 2, 0));
+    }
+
+    #[test]
+    fn axis_aligned_rays_keep_their_other_coordinates() {
+        let mut system = world();
+        system.set_voxel(3, -16, 3, STONE).unwrap();
+        system.set_voxel(4, -16, 3, STONE).unwrap();
+
+        let hit = cast(&system, (3.5, -5.5, 3.5), (0.0, -2.0, 0.0), 100.0).unwrap();
+        assert_eq!(hit.position, GlobalPos::new(3, -16, 3));
+        assert_eq!(hit.face, Some(Direction::Up));
+        assert_eq!(hit.distance, 9.5);
+
+        //Starting exactly on a boundary of an axis with no movement doesn't move along it
+        let hit = cast(&system, (4.0, -5.5, 3.0), (0.0, -1.0, 0.0), 100.0).unwrap();
+        assert_eq!(hit.position, GlobalPos::new(4, -16, 3));
+
+        //The hit has to be reached within the maximum distance
+        assert!(cast(&system, (3.5, -5.5, 3.5), (0.0, -1.0, 0.0), 9.4).is_none());
+        assert!(cast(&system, (3.5, -5.5, 3.5), (0.0, -1.0, 0.0), 9.5).is_some());
+    }
+
+    #[test]
+    fn rays_starting_inside_a_solid_voxel_have_no_previous() {
+        let mut system = world();
+        system.set_voxel(-1, 0, 0, STONE).unwrap();
+        system.set_voxel(0, 0, 0, STONE).unwrap();
+
+        let hit = cast(&system, (-0.5, 0.5, 0.5), (1.0, 0.0, 0.0), 10.0).unwrap();
+        assert_eq!(hit.position, GlobalPos::new(-1, 0, 0));
+        assert_eq!(hit.face, None);
+        assert_eq!(hit.previous, None);
+        assert_eq!(hit.distance, 0.0);
+
+        //Hitting the neighbor of the first voxel makes the first voxel the previous one
+        let hit = cast(&system, (-1.5, 0.5, 0.5), (1.0, 0.0, 0.0), 10.0).unwrap();
+        assert_eq!(hit.position, GlobalPos::new(-1, 0, 0));
+        assert_eq!(hit.previous, Some(GlobalPos::new(-2, 0, 0)));
+        assert_eq!(hit.face, Some(Direction::West));
+    }
+
+    #[test]
+    fn invalid_directions_hit_nothing() {
+        let mut system = world();
+        system.set_voxel(0, 0, 0, STONE).unwrap();
+        assert!(cast(&system, (0.5, 0.5, 0.5), (0.0, 0.0, 0.0), 10.0).is_none());
+        assert!(cast(&system, (0.5, 0.5, 0.5), (f32::NAN, 0.0, 0.0), 10.0).is_none());
+    }
+}
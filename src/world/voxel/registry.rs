@@ -1,8 +1,13 @@
 //Uses
+use super::serialize::{read_exact, read_u16, read_u8, write_u16};
 use std::any::{Any, TypeId};
 use std::collections::HashMap;
+use std::io::{Read, Write};
 use std::sync::Arc;
 
+const NAME_TABLE_MAGIC: [u8; 4] = *b"YNRG";
+const NAME_TABLE_VERSION: u8 = 1;
+
 pub trait Attribute: 'static + Any + Send + Sync {}
 impl<A: 'static + Any + Send + Sync> Attribute for A {}
 
@@ -31,7 +36,10 @@ impl<A: Attribute> AttributeRegistry<A> {
 
         match self.map[id as usize] {
             Some(_) => Err(super::Error::AttributeAlreadyRegistered(id)),
-            None => Ok(self.map[id as usize] = Some(attribute_obj)),
+            None => {
+                self.map[id as usize] = Some(attribute_obj);
+                Ok(())
+            }
         }
     }
 
@@ -86,6 +94,12 @@ impl AttributeRegistries {
     }
 }
 
+impl Default for AttributeRegistries {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Allows for a reverse-lookup of strings to voxel IDs, useful for scripting convenience
 /// and serialization consistency.
 pub struct NameRegistry {
@@ -107,6 +121,82 @@ impl NameRegistry {
     }
 
     pub fn find(&self, name: &str) -> Option<u16> {
-        self.map.get(name).map(|id| *id)
+        self.map.get(name).copied()
+    }
+
+    /// Returns a name registered for the ID, if there are multiple names the first one alphabetically
+    pub fn find_name(&self, id: u16) -> Option<&str> {
+        self.map
+            .iter()
+            .filter(|(_, name_id)| **name_id == id)
+            .map(|(name, _)| name.as_str())
+            .min()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u16)> {
+        self.map.iter().map(|(name, id)| (name.as_str(), *id))
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Writes the name table, entries are sorted by name so the output is deterministic.
+    ///
+    /// The format is the magic bytes `YNRG`, a version (`u8`), the entry count (`u32`),
+    /// and then every entry as the ID (`u16`), the name length (`u16`) and the UTF-8 name.
+    pub fn write_to<W: Write>(&self, w: &mut W) -> Result<(), super::Error> {
+        let mut entries: Vec<(&str, u16)> = self.iter().collect();
+        entries.sort_unstable();
+
+        w.write_all(&NAME_TABLE_MAGIC)?;
+        w.write_all(&[NAME_TABLE_VERSION])?;
+        w.write_all(&(entries.len() as u32).to_le_bytes())?;
+        for (name, id) in entries {
+            let name_bytes = name.as_bytes();
+            let name_len = u16::try_from(name_bytes.len())
+                .map_err(|_| super::Error::InvalidVoxelName(name.to_owned()))?;
+            write_u16(w, id)?;
+            write_u16(w, name_len)?;
+            w.write_all(name_bytes)?;
+        }
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(r: &mut R) -> Result<NameRegistry, super::Error> {
+        let mut magic = [0; 4];
+        read_exact(r, &mut magic)?;
+        if magic != NAME_TABLE_MAGIC {
+            return Err(super::Error::InvalidNameTableMagic);
+        }
+        let version = read_u8(r)?;
+        if version != NAME_TABLE_VERSION {
+            return Err(super::Error::UnsupportedNameTableVersion(version));
+        }
+
+        let mut count = [0; 4];
+        read_exact(r, &mut count)?;
+        let mut registry = NameRegistry::new();
+        for _ in 0..u32::from_le_bytes(count) {
+            let id = read_u16(r)?;
+            let name_len = read_u16(r)?;
+            let mut name_bytes = vec![0; name_len as usize];
+            read_exact(r, &mut name_bytes)?;
+            let name = String::from_utf8(name_bytes).map_err(|err| {
+                super::Error::InvalidVoxelName(String::from_utf8_lossy(err.as_bytes()).into_owned())
+            })?;
+            registry.add(&name, id)?;
+        }
+        Ok(registry)
+    }
+}
+
+impl Default for NameRegistry {
+    fn default() -> Self {
+        Self::new()
     }
 }
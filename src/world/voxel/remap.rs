@@ -0,0 +1,317 @@
+//! Translation between the voxel IDs of the running game and the IDs stored in a world
+//!
+//! Every world keeps its own name table, mapping voxel names to the IDs used in its chunks.
+//! IDs in the table never change once assigned, names registered by the game that are missing
+//! from the table get a new ID appended. When chunks are loaded, stored IDs are translated to
+//! the IDs of the current `NameRegistry`, and names that are no longer registered turn into a
+//! placeholder voxel. IDs that have no name are stored and loaded unchanged.
+//!
+//! A name whose current ID is already used by another stored name gets a stored ID above every
+//! ID of the table and the registry, so it can't be confused with unnamed IDs like air.
+//! Only once those run out are gaps between them used, unnamed voxel types should therefore
+//! have IDs below the highest named ID.
+
+//Uses
+use super::{Error, NameRegistry, Voxel, VoxelArray};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+/// File name of the name table within a storage directory
+pub(super) const NAME_TABLE_FILE_NAME: &str = "names.dat";
+
+#[derive(Clone)]
+pub(super) struct IdMapping {
+    to_stored: HashMap<u16, u16>,
+    from_stored: HashMap<u16, u16>,
+    /// Stored IDs that have a name, so IDs without a current equivalent can be told apart from unnamed ones
+    named_stored_ids: HashSet<u16>,
+    placeholder: Voxel,
+}
+
+impl IdMapping {
+    /// Reads the name table in the directory, adds names of the registry that are missing,
+    /// and writes the table back if it changed
+    pub(super) fn bind(
+        directory: &Path,
+        registry: &NameRegistry,
+        placeholder: Voxel,
+    ) -> Result<IdMapping, Error> {
+        let path = directory.join(NAME_TABLE_FILE_NAME);
+        let mut stored = if path.exists() {
+            NameRegistry::read_from(&mut BufReader::new(File::open(&path)?))?
+        } else {
+            NameRegistry::new()
+        };
+
+        let (mapping, changed) = IdMapping::build(&mut stored, registry, placeholder)?;
+        if changed || !path.exists() {
+            //Write to a temporary file first, so a crash can't leave a truncated table behind
+            let temp_path = directory.join(format!("{}.tmp", NAME_TABLE_FILE_NAME));
+            let mut writer = BufWriter::new(File::create(&temp_path)?);
+            stored.write_to(&mut writer)?;
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+            drop(writer);
+            std::fs::rename(&temp_path, &path)?;
+        }
+        Ok(mapping)
+    }
+
+    /// Builds the mapping between the stored table and the registry, adding missing names to the stored table.
+    /// Returns true as the second value if the stored table was changed.
+    fn build(
+        stored: &mut NameRegistry,
+        registry: &NameRegistry,
+        placeholder: Voxel,
+    ) -> Result<(IdMapping, bool), Error> {
+        let mut mapping = IdMapping {
+            to_stored: HashMap::new(),
+            from_stored: HashMap::new(),
+            named_stored_ids: HashSet::new(),
+            placeholder,
+        };
+
+        for (name, stored_id) in stored.iter() {
+            mapping.named_stored_ids.insert(stored_id);
+            if let Some(id) = registry.find(name) {
+                mapping.from_stored.insert(stored_id, id);
+                mapping.to_stored.entry(id).or_insert(stored_id);
+            }
+        }
+
+        //Sorted so new stored IDs are assigned deterministically
+        let mut new_names: Vec<(u16, &str)> = registry
+            .iter()
+            .filter(|(name, _)| stored.find(name).is_none())
+            .map(|(name, id)| (id, name))
+            .collect();
+        new_names.sort_unstable();
+
+        let changed = !new_names.is_empty();
+        let registry_ids: HashSet<u16> = registry.iter().map(|(_, id)| id).collect();
+        let highest_id = registry_ids
+            .iter()
+            .chain(mapping.named_stored_ids.iter())
+            .copied()
+            .max()
+            .unwrap_or(0);
+        let mut free_ids = (highest_id as u32 + 1..=u16::MAX as u32)
+            .chain(0..highest_id as u32)
+            .map(|id| id as u16);
+        for (id, name) in new_names {
+            //Aliases of an ID that is already stored share its stored ID, otherwise the current ID is kept if possible
+            let stored_id = match mapping.to_stored.get(&id) {
+                Some(stored_id) => *stored_id,
+                None if !mapping.named_stored_ids.contains(&id) => id,
+                None => free_ids
+                    .find(|free_id| {
+                        !mapping.named_stored_ids.contains(free_id)
+                            && !registry_ids.contains(free_id)
+                    })
+                    .ok_or(Error::NameTableFull)?,
+            };
+            stored.add(name, stored_id)?;
+            mapping.named_stored_ids.insert(stored_id);
+            mapping.from_stored.entry(stored_id).or_insert(id);
+            mapping.to_stored.insert(id, stored_id);
+        }
+
+        Ok((mapping, changed))
+    }
+
+    pub(super) fn set_placeholder(&mut self, placeholder: Voxel) {
+        self.placeholder = placeholder;
+    }
+
+    /// Returns true if stored IDs are the same as the current ones
+    fn is_identity(&self) -> bool {
+        self.named_stored_ids.len() == self.from_stored.len()
+            && self
+                .from_stored
+                .iter()
+                .all(|(stored_id, id)| stored_id == id)
+            && self.to_stored.iter().all(|(id, stored_id)| stored_id == id)
+    }
+
+    pub(super) fn map_to_stored(&self, voxels: &VoxelArray) -> Option<VoxelArray> {
+        if self.is_identity() {
+            return None;
+        }
+        let mut voxels = voxels.clone();
+        voxels.map_voxels(|voxel| match self.to_stored.get(&voxel.id) {
+            Some(stored_id) => Voxel {
+                id: *stored_id,
+                data: voxel.data,
+            },
+            None => voxel,
+        });
+        Some(voxels)
+    }
+
+    pub(super) fn map_from_stored(&self, voxels: &mut VoxelArray) {
+        if self.is_identity() {
+            return;
+        }
+        voxels.map_voxels(|voxel| match self.from_stored.get(&voxel.id) {
+            Some(id) => Voxel {
+                id: *id,
+                data: voxel.data,
+            },
+            None if self.named_stored_ids.contains(&voxel.id) => self.placeholder,
+            None => voxel,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::voxel::storage::tests::TempDir;
+
+    const AIR: Voxel = Voxel { id: 0, data: 0 };
+    const PLACEHOLDER: Voxel = Voxel { id: 99, data: 0 };
+
+    fn names(entries: &[(&str, u16)]) -> NameRegistry {
+        let mut registry = NameRegistry::new();
+        for (name, id) in entries {
+            registry.add(name, *id).unwrap();
+        }
+        registry
+    }
+
+    /// A chunk holding the given voxel IDs at the start, and air everywhere else
+    fn chunk(ids: &[u16]) -> VoxelArray {
+        let mut array = VoxelArray::new(AIR);
+        for (i, id) in ids.iter().enumerate() {
+            array.set_voxel_at_index(
+                i,
+                Voxel {
+                    id: *id,
+                    data: i as u16,
+                },
+            );
+        }
+        array
+    }
+
+    fn ids(array: &VoxelArray, count: usize) -> Vec<u16> {
+        (0..count).map(|i| array.get_voxel_at_index(i).id).collect()
+    }
+
+    fn to_stored(mapping: &IdMapping, array: &VoxelArray) -> VoxelArray {
+        mapping
+            .map_to_stored(array)
+            .unwrap_or_else(|| array.clone())
+    }
+
+    #[test]
+    fn new_worlds_store_current_ids() {
+        let registry = names(&[("stone", 1), ("dirt", 2)]);
+        let mut stored = NameRegistry::new();
+        let (mapping, changed) = IdMapping::build(&mut stored, &registry, PLACEHOLDER).unwrap();
+        assert!(changed);
+        assert!(mapping.is_identity());
+        assert_eq!(stored.find("stone"), Some(1));
+        assert_eq!(stored.find("dirt"), Some(2));
+    }
+
+    #[test]
+    fn added_blocks_keep_their_id_if_it_is_free() {
+        let registry = names(&[("stone", 1), ("dirt", 2), ("sand", 3)]);
+        let mut stored = names(&[("stone", 1), ("dirt", 2)]);
+        let (mapping, changed) = IdMapping::build(&mut stored, &registry, PLACEHOLDER).unwrap();
+        assert!(changed);
+        assert_eq!(stored.find("sand"), Some(3));
+        assert!(mapping.is_identity());
+
+        //Binding again doesn't change the table
+        let (_, changed) = IdMapping::build(&mut stored, &registry, PLACEHOLDER).unwrap();
+        assert!(!changed);
+    }
+
+    #[test]
+    fn removed_and_renamed_blocks_load_as_the_placeholder() {
+        //"gravel" was removed and "dirt" was renamed to "soil"
+        let registry = names(&[("stone", 1), ("soil", 2)]);
+        let mut stored = names(&[("stone", 1), ("dirt", 2), ("gravel", 3)]);
+        let (mapping, _) = IdMapping::build(&mut stored, &registry, PLACEHOLDER).unwrap();
+
+        //The stored ID 2 still belongs to "dirt", so "soil" needs a new one
+        let soil_id = stored.find("soil").unwrap();
+        assert!(soil_id > 3);
+        assert_eq!(stored.find("dirt"), Some(2));
+
+        let mut array = chunk(&[1, 2, 3, soil_id, 7]);
+        mapping.map_from_stored(&mut array);
+        assert_eq!(
+            ids(&array, 5),
+            vec![1, PLACEHOLDER.id, PLACEHOLDER.id, 2, 7]
+        );
+        assert_eq!(*array.get_voxel_at_index(1), PLACEHOLDER);
+        assert_eq!(array.get_voxel_at_index(3).data, 3);
+    }
+
+    #[test]
+    fn aliases_share_the_stored_id() {
+        let registry = names(&[("stone", 1), ("rock", 1)]);
+        let mut stored = names(&[("stone", 5)]);
+        let (mapping, changed) = IdMapping::build(&mut stored, &registry, PLACEHOLDER).unwrap();
+        assert!(changed);
+        assert_eq!(stored.find("rock"), Some(5));
+
+        let stored_array = to_stored(&mapping, &chunk(&[1, 0]));
+        assert_eq!(ids(&stored_array, 2), vec![5, 0]);
+        let mut array = stored_array;
+        mapping.map_from_stored(&mut array);
+        assert_eq!(ids(&array, 2), vec![1, 0]);
+    }
+
+    #[test]
+    fn new_stored_ids_never_collide_with_unnamed_ids() {
+        //Air is unnamed and stored as 0, the new stored ID of stone must not be 0 as well
+        let registry = names(&[("stone", 1), ("dirt", 2)]);
+        let mut stored = names(&[("dirt", 1)]);
+        let (mapping, _) = IdMapping::build(&mut stored, &registry, PLACEHOLDER).unwrap();
+
+        let stone_id = stored.find("stone").unwrap();
+        assert!(![0, 1, 2].contains(&stone_id));
+
+        let array = chunk(&[0, 1, 2, 40]);
+        let stored_array = to_stored(&mapping, &array);
+        assert_eq!(ids(&stored_array, 4), vec![0, stone_id, 1, 40]);
+        let mut loaded = stored_array;
+        mapping.map_from_stored(&mut loaded);
+        assert_eq!(ids(&loaded, 4), vec![0, 1, 2, 40]);
+    }
+
+    #[test]
+    fn gaps_are_used_once_high_ids_run_out() {
+        let registry = names(&[("stone", 1), ("top", u16::MAX)]);
+        let mut stored = names(&[("dirt", 1), ("top", u16::MAX)]);
+        IdMapping::build(&mut stored, &registry, PLACEHOLDER).unwrap();
+        assert_eq!(stored.find("stone"), Some(0));
+    }
+
+    #[test]
+    fn name_table_survives_rebinding() {
+        let dir = TempDir::new("remap-rebind");
+        std::fs::create_dir_all(dir.path()).unwrap();
+        let first = IdMapping::bind(dir.path(), &names(&[("dirt", 1)]), PLACEHOLDER).unwrap();
+        let stored_dirt = to_stored(&first, &chunk(&[1]));
+
+        let registry = names(&[("stone", 1), ("dirt", 2)]);
+        let second = IdMapping::bind(dir.path(), &registry, PLACEHOLDER).unwrap();
+        let mut loaded = stored_dirt;
+        second.map_from_stored(&mut loaded);
+        assert_eq!(ids(&loaded, 1), vec![2]);
+
+        //The stored ID given to stone is kept in the table
+        let third = IdMapping::bind(dir.path(), &registry, PLACEHOLDER).unwrap();
+        assert_eq!(
+            ids(&to_stored(&second, &chunk(&[1, 2])), 2),
+            ids(&to_stored(&third, &chunk(&[1, 2])), 2)
+        );
+    }
+}
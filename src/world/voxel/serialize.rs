@@ -0,0 +1,241 @@
+//! Binary encoding of `VoxelArray`s
+//!
+//! All numbers are little-endian. An encoded chunk looks like this:
+//! - The magic bytes `YVXA`
+//! - The format version (`u8`)
+//! - The number of palette entries (`u16`), between 1 and the number of voxels in a chunk
+//! - The palette entries, each being a voxel id (`u16`) followed by its data (`u16`)
+//! - Runs of voxels in voxel buffer order until every voxel is covered,
+//!   each being a run length (`u16`) followed by a palette index (`u16`)
+
+//Uses
+use super::array::VOXEL_COUNT;
+use super::{Error, Voxel, VoxelArray};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+const MAGIC: [u8; 4] = *b"YVXA";
+const VERSION: u8 = 1;
+
+impl VoxelArray {
+    pub fn write_to<W: Write>(&self, w: &mut W) -> Result<(), Error> {
+        let mut palette = Vec::new();
+        let mut palette_lookup = HashMap::new();
+        let mut runs: Vec<(u16, u16)> = Vec::new();
+
+        for i in 0..VOXEL_COUNT {
+            let voxel = *self.get_voxel_at_index(i);
+            let palette_index =
+                *palette_lookup
+                    .entry((voxel.id, voxel.data))
+                    .or_insert_with(|| {
+                        palette.push(voxel);
+                        (palette.len() - 1) as u16
+                    });
+
+            match runs.last_mut() {
+                Some((length, index)) if *index == palette_index => *length += 1,
+                _ => runs.push((1, palette_index)),
+            }
+        }
+
+        w.write_all(&MAGIC)?;
+        w.write_all(&[VERSION])?;
+        write_u16(w, palette.len() as u16)?;
+        for voxel in palette.iter() {
+            write_u16(w, voxel.id)?;
+            write_u16(w, voxel.data)?;
+        }
+        for (length, palette_index) in runs {
+            write_u16(w, length)?;
+            write_u16(w, palette_index)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(r: &mut R) -> Result<VoxelArray, Error> {
+        let mut magic = [0; 4];
+        read_exact(r, &mut magic)?;
+        if magic != MAGIC {
+            return Err(Error::InvalidChunkMagic);
+        }
+        let version = read_u8(r)?;
+        if version != VERSION {
+            return Err(Error::UnsupportedChunkVersion(version));
+        }
+
+        let palette_len = read_u16(r)?;
+        if palette_len == 0 || palette_len as usize > VOXEL_COUNT {
+            return Err(Error::InvalidPaletteSize(palette_len));
+        }
+        let mut palette = Vec::with_capacity(palette_len as usize);
+        for _ in 0..palette_len {
+            let id = read_u16(r)?;
+            let data = read_u16(r)?;
+            palette.push(Voxel { id, data });
+        }
+
+        let mut palette_indices = Vec::with_capacity(VOXEL_COUNT);
+        while palette_indices.len() < VOXEL_COUNT {
+            let length = read_u16(r)?;
+            let palette_index = read_u16(r)?;
+            if length == 0 || palette_indices.len() + length as usize > VOXEL_COUNT {
+                return Err(Error::InvalidRunLength(length));
+            }
+            if palette_index >= palette_len {
+                return Err(Error::PaletteIndexOutOfRange(palette_index));
+            }
+            palette_indices.resize(palette_indices.len() + length as usize, palette_index);
+        }
+
+        Ok(VoxelArray::from_palette(palette, &palette_indices))
+    }
+}
+
+pub(crate) fn write_u16<W: Write>(w: &mut W, value: u16) -> Result<(), Error> {
+    w.write_all(&value.to_le_bytes())?;
+    Ok(())
+}
+
+pub(crate) fn read_exact<R: Read>(r: &mut R, buf: &mut [u8]) -> Result<(), Error> {
+    r.read_exact(buf).map_err(|err| match err.kind() {
+        std::io::ErrorKind::UnexpectedEof => Error::UnexpectedEndOfData,
+        _ => Error::IoError(err),
+    })
+}
+
+pub(crate) fn read_u8<R: Read>(r: &mut R) -> Result<u8, Error> {
+    let mut buf = [0; 1];
+    read_exact(r, &mut buf)?;
+    Ok(buf[0])
+}
+
+pub(crate) fn read_u16<R: Read>(r: &mut R) -> Result<u16, Error> {
+    let mut buf = [0; 2];
+    read_exact(r, &mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::test_util::{assert_same_voxels, XorShift};
+
+    fn encode(array: &VoxelArray) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        array.write_to(&mut bytes).unwrap();
+        bytes
+    }
+
+    /// Builds a chunk out of runs of random length, with up to `voxel_types` distinct voxels
+    fn random_array(rng: &mut XorShift, voxel_types: u32, max_run: u32) -> VoxelArray {
+        let mut array = VoxelArray::new(Voxel { id: 0, data: 0 });
+        let mut i = 0;
+        while i < VOXEL_COUNT {
+            let value = rng.next() % voxel_types;
+            let voxel = Voxel {
+                id: (value / 3) as u16,
+                data: (value % 3) as u16,
+            };
+            let run = 1 + (rng.next() % max_run) as usize;
+            for j in i..(i + run).min(VOXEL_COUNT) {
+                array.set_voxel_at_index(j, voxel);
+            }
+            i += run;
+        }
+        array
+    }
+
+    #[test]
+    fn random_arrays_round_trip() {
+        let mut rng = XorShift(0xDEAD_BEEF);
+        for voxel_types in [1, 2, 3, 16, 17, 300, 4096, 70000] {
+            for max_run in [1, 4, 64, 5000] {
+                let array = random_array(&mut rng, voxel_types, max_run);
+                let bytes = encode(&array);
+                let decoded = VoxelArray::read_from(&mut bytes.as_slice()).unwrap();
+                assert_same_voxels(&array, &decoded);
+                assert_eq!(encode(&decoded), bytes);
+            }
+        }
+    }
+
+    #[test]
+    fn uniform_array_round_trips_compactly() {
+        let array = VoxelArray::new(Voxel { id: 7, data: 3 });
+        let bytes = encode(&array);
+        //Header, one palette entry and a single run
+        assert_eq!(bytes.len(), 4 + 1 + 2 + 4 + 4);
+
+        let decoded = VoxelArray::read_from(&mut bytes.as_slice()).unwrap();
+        assert!(decoded.is_uniform());
+        assert_same_voxels(&array, &decoded);
+    }
+
+    #[test]
+    fn truncated_data_is_rejected() {
+        let mut rng = XorShift(0x1234_5678);
+        let bytes = encode(&random_array(&mut rng, 20, 8));
+        for len in 0..bytes.len() {
+            assert!(matches!(
+                VoxelArray::read_from(&mut &bytes[..len]),
+                Err(Error::UnexpectedEndOfData)
+            ));
+        }
+    }
+
+    #[test]
+    fn corrupted_data_never_panics() {
+        let mut rng = XorShift(0xCAFE_F00D);
+        for _ in 0..200 {
+            let (voxel_types, max_run) = (1 + rng.next() % 40, 1 + rng.next() % 32);
+            let mut bytes = encode(&random_array(&mut rng, voxel_types, max_run));
+            for _ in 0..1 + rng.next() % 4 {
+                let i = rng.next() as usize % bytes.len();
+                bytes[i] = rng.next() as u8;
+            }
+            let _ = VoxelArray::read_from(&mut bytes.as_slice());
+        }
+    }
+
+    #[test]
+    fn invalid_headers_are_reported() {
+        let bytes = encode(&VoxelArray::new(Voxel { id: 1, data: 0 }));
+
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] = b'X';
+        assert!(matches!(
+            VoxelArray::read_from(&mut bad_magic.as_slice()),
+            Err(Error::InvalidChunkMagic)
+        ));
+
+        let mut bad_version = bytes.clone();
+        bad_version[4] = VERSION + 1;
+        assert!(matches!(
+            VoxelArray::read_from(&mut bad_version.as_slice()),
+            Err(Error::UnsupportedChunkVersion(v)) if v == VERSION + 1
+        ));
+
+        let mut empty_palette = bytes.clone();
+        empty_palette[5..7].copy_from_slice(&0u16.to_le_bytes());
+        assert!(matches!(
+            VoxelArray::read_from(&mut empty_palette.as_slice()),
+            Err(Error::InvalidPaletteSize(0))
+        ));
+
+        let mut bad_index = bytes.clone();
+        bad_index[13..15].copy_from_slice(&1u16.to_le_bytes());
+        assert!(matches!(
+            VoxelArray::read_from(&mut bad_index.as_slice()),
+            Err(Error::PaletteIndexOutOfRange(1))
+        ));
+
+        let mut bad_run = bytes;
+        bad_run[11..13].copy_from_slice(&(VOXEL_COUNT as u16 + 1).to_le_bytes());
+        assert!(matches!(
+            VoxelArray::read_from(&mut bad_run.as_slice()),
+            Err(Error::InvalidRunLength(_))
+        ));
+    }
+}
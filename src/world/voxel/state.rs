@@ -0,0 +1,498 @@
+//! Typed block states packed into `Voxel::data`
+//!
+//! A `BlockStateSchema` declares the named properties a voxel type has, for example the direction
+//! a furnace is facing or the growth stage of a crop. Every property gets its own range of bits in `data`,
+//! in the order the properties were added, using as few bits as its possible values need.
+//! The first value of every property is stored as zero, so data 0 is always the default state.
+//!
+//! States can be written as text, like `facing=north,half=top`, to look them up by name.
+
+//Uses
+use super::Error;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Number of bits available in `Voxel::data`
+const DATA_BITS: u32 = 16;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PropertyKind {
+    /// One of a list of named values, the first one is the default
+    Enum(Vec<String>),
+    /// `false` by default
+    Bool,
+    /// An integer from `min` to `max` inclusive, `min` is the default
+    Int { min: i32, max: i32 },
+}
+
+impl PropertyKind {
+    /// The number of different values
+    fn value_count(&self) -> u32 {
+        match self {
+            PropertyKind::Enum(values) => values.len() as u32,
+            PropertyKind::Bool => 2,
+            PropertyKind::Int { min, max } => (*max as i64 - *min as i64 + 1) as u32,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PropertyValue {
+    Enum(String),
+    Bool(bool),
+    Int(i32),
+}
+
+impl fmt::Display for PropertyValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PropertyValue::Enum(value) => write!(f, "{}", value),
+            PropertyValue::Bool(value) => write!(f, "{}", value),
+            PropertyValue::Int(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Property {
+    name: String,
+    kind: PropertyKind,
+    /// Position of the lowest bit in `data`
+    shift: u32,
+    bits: u32,
+}
+
+impl Property {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn kind(&self) -> &PropertyKind {
+        &self.kind
+    }
+
+    fn mask(&self) -> u16 {
+        (((1u32 << self.bits) - 1) << self.shift) as u16
+    }
+
+    fn get_index(&self, data: u16) -> u32 {
+        ((data & self.mask()) >> self.shift) as u32
+    }
+
+    fn set_index(&self, data: u16, index: u32) -> u16 {
+        (data & !self.mask()) | ((index << self.shift) as u16)
+    }
+
+    fn value_at(&self, index: u32) -> PropertyValue {
+        match &self.kind {
+            PropertyKind::Enum(values) => PropertyValue::Enum(values[index as usize].clone()),
+            PropertyKind::Bool => PropertyValue::Bool(index != 0),
+            PropertyKind::Int { min, .. } => {
+                PropertyValue::Int((*min as i64 + index as i64) as i32)
+            }
+        }
+    }
+
+    fn index_of(&self, value: &PropertyValue) -> Option<u32> {
+        match (&self.kind, value) {
+            (PropertyKind::Enum(values), PropertyValue::Enum(value)) => {
+                values.iter().position(|v| v == value).map(|i| i as u32)
+            }
+            (PropertyKind::Bool, PropertyValue::Bool(value)) => Some(*value as u32),
+            (PropertyKind::Int { min, max }, PropertyValue::Int(value)) => (min..=max)
+                .contains(&value)
+                .then(|| (*value as i64 - *min as i64) as u32),
+            _ => None,
+        }
+    }
+
+    fn parse_value(&self, text: &str) -> Option<PropertyValue> {
+        let value = match self.kind {
+            PropertyKind::Enum(_) => PropertyValue::Enum(text.to_owned()),
+            PropertyKind::Bool => PropertyValue::Bool(text.parse().ok()?),
+            PropertyKind::Int { .. } => PropertyValue::Int(text.parse().ok()?),
+        };
+        self.index_of(&value).map(|_| value)
+    }
+}
+
+/// The properties of one voxel type and how they are packed into `Voxel::data`
+#[derive(Clone, Debug, Default)]
+pub struct BlockStateSchema {
+    properties: Vec<Property>,
+}
+
+impl BlockStateSchema {
+    pub fn new() -> BlockStateSchema {
+        BlockStateSchema {
+            properties: Vec::new(),
+        }
+    }
+
+    /// Adds a property that takes one of the given values
+    pub fn add_enum(&mut self, name: &str, values: &[&str]) -> Result<(), Error> {
+        if values.is_empty() {
+            return Err(Error::InvalidPropertyDefinition(name.to_owned()));
+        }
+        for (i, value) in values.iter().enumerate() {
+            if !is_valid_token(value) || values[..i].contains(value) {
+                return Err(Error::InvalidPropertyDefinition(name.to_owned()));
+            }
+        }
+        let values = values.iter().map(|value| (*value).to_owned()).collect();
+        self.add_property(name, PropertyKind::Enum(values))
+    }
+
+    pub fn add_bool(&mut self, name: &str) -> Result<(), Error> {
+        self.add_property(name, PropertyKind::Bool)
+    }
+
+    /// Adds a property that takes an integer from `min` to `max` inclusive
+    pub fn add_int(&mut self, name: &str, min: i32, max: i32) -> Result<(), Error> {
+        if min > max {
+            return Err(Error::InvalidPropertyDefinition(name.to_owned()));
+        }
+        if max as i64 - min as i64 >= 1 << DATA_BITS {
+            return Err(Error::BlockStateTooLarge(name.to_owned()));
+        }
+        self.add_property(name, PropertyKind::Int { min, max })
+    }
+
+    fn add_property(&mut self, name: &str, kind: PropertyKind) -> Result<(), Error> {
+        if !is_valid_token(name) {
+            return Err(Error::InvalidPropertyDefinition(name.to_owned()));
+        }
+        if self.find_property(name).is_some() {
+            return Err(Error::PropertyAlreadyDefined(name.to_owned()));
+        }
+
+        //Enough bits to store the index of the last value
+        let bits = u32::BITS - (kind.value_count() - 1).leading_zeros();
+        let shift = self.bits();
+        if shift + bits > DATA_BITS {
+            return Err(Error::BlockStateTooLarge(name.to_owned()));
+        }
+
+        self.properties.push(Property {
+            name: name.to_owned(),
+            kind,
+            shift,
+            bits,
+        });
+        Ok(())
+    }
+
+    /// The number of bits of `Voxel::data` used by the properties
+    pub fn bits(&self) -> u32 {
+        self.properties.iter().map(|property| property.bits).sum()
+    }
+
+    pub fn properties(&self) -> impl Iterator<Item = &Property> {
+        self.properties.iter()
+    }
+
+    pub fn find_property(&self, name: &str) -> Option<&Property> {
+        self.properties
+            .iter()
+            .find(|property| property.name == name)
+    }
+
+    fn get_property(&self, name: &str) -> Result<&Property, Error> {
+        self.find_property(name)
+            .ok_or_else(|| Error::PropertyMissing(name.to_owned()))
+    }
+
+    /// Checks that the data holds a valid value for every property and no other bits are set
+    pub fn validate(&self, data: u16) -> Result<(), Error> {
+        let used_bits = ((1u32 << self.bits()) - 1) as u16;
+        let is_valid = data & !used_bits == 0
+            && self
+                .properties
+                .iter()
+                .all(|property| property.get_index(data) < property.kind.value_count());
+        if is_valid {
+            Ok(())
+        } else {
+            Err(Error::InvalidStateData(data))
+        }
+    }
+
+    pub fn get(&self, data: u16, name: &str) -> Result<PropertyValue, Error> {
+        self.validate(data)?;
+        let property = self.get_property(name)?;
+        Ok(property.value_at(property.get_index(data)))
+    }
+
+    /// Returns the data with the property changed to the given value
+    pub fn set(&self, data: u16, name: &str, value: &PropertyValue) -> Result<u16, Error> {
+        self.validate(data)?;
+        let property = self.get_property(name)?;
+        let index = property
+            .index_of(value)
+            .ok_or_else(|| Error::InvalidPropertyValue(name.to_owned(), value.to_string()))?;
+        Ok(property.set_index(data, index))
+    }
+
+    /// Parses a state like `facing=north,half=top`. Properties that are left out keep their default value.
+    pub fn parse(&self, state: &str) -> Result<u16, Error> {
+        let mut data = 0;
+        let mut assigned = Vec::new();
+        for assignment in state.split(',').filter(|assignment| !assignment.is_empty()) {
+            let (name, text) = assignment
+                .split_once('=')
+                .ok_or_else(|| Error::InvalidBlockState(state.to_owned()))?;
+            let (name, text) = (name.trim(), text.trim());
+            if assigned.contains(&name) {
+                return Err(Error::InvalidBlockState(state.to_owned()));
+            }
+            assigned.push(name);
+
+            let property = self.get_property(name)?;
+            let value = property
+                .parse_value(text)
+                .ok_or_else(|| Error::InvalidPropertyValue(name.to_owned(), text.to_owned()))?;
+            data = property.set_index(data, property.index_of(&value).unwrap());
+        }
+        Ok(data)
+    }
+
+    /// Writes every property in the order they were added, in the format read by `parse`
+    pub fn format(&self, data: u16) -> Result<String, Error> {
+        self.validate(data)?;
+        let assignments: Vec<String> = self
+            .properties
+            .iter()
+            .map(|property| {
+                format!(
+                    "{}={}",
+                    property.name,
+                    property.value_at(property.get_index(data))
+                )
+            })
+            .collect();
+        Ok(assignments.join(","))
+    }
+}
+
+/// Names and enum values can't be empty or contain the characters used to separate them
+fn is_valid_token(token: &str) -> bool {
+    !token.is_empty()
+        && !token
+            .chars()
+            .any(|c| c == ',' || c == '=' || c.is_whitespace())
+}
+
+/// Stores the block state schema of every voxel type that has one.
+/// Voxel types without a schema have no properties, their data is left to the game and is not checked
+/// by `VoxelSystem::set_voxel`. Only data 0 can be parsed and formatted for them.
+pub struct BlockStateRegistry {
+    map: HashMap<u16, BlockStateSchema>,
+    empty: BlockStateSchema,
+}
+
+impl BlockStateRegistry {
+    pub fn new() -> BlockStateRegistry {
+        BlockStateRegistry {
+            map: HashMap::new(),
+            empty: BlockStateSchema::new(),
+        }
+    }
+
+    pub fn register(&mut self, id: u16, schema: BlockStateSchema) -> Result<(), Error> {
+        if self.map.contains_key(&id) {
+            return Err(Error::SchemaAlreadyRegistered(id));
+        }
+        self.map.insert(id, schema);
+        Ok(())
+    }
+
+    pub fn find(&self, id: u16) -> Option<&BlockStateSchema> {
+        self.map.get(&id)
+    }
+
+    /// Returns the schema of the voxel type, or an empty schema if none has been registered
+    pub fn schema(&self, id: u16) -> &BlockStateSchema {
+        self.map.get(&id).unwrap_or(&self.empty)
+    }
+}
+
+impl Default for BlockStateRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A furnace-like schema: facing uses bits 0-1, lit bit 2 and fuel bits 3-5
+    fn furnace() -> BlockStateSchema {
+        let mut schema = BlockStateSchema::new();
+        schema
+            .add_enum("facing", &["north", "east", "south", "west"])
+            .unwrap();
+        schema.add_bool("lit").unwrap();
+        schema.add_int("fuel", -2, 5).unwrap();
+        schema
+    }
+
+    fn int(value: i32) -> PropertyValue {
+        PropertyValue::Int(value)
+    }
+
+    #[test]
+    fn properties_get_consecutive_bits() {
+        let schema = furnace();
+        assert_eq!(schema.bits(), 6);
+
+        let data = schema.parse("facing=south,lit=true,fuel=3").unwrap();
+        assert_eq!(data, (5 << 3) | (1 << 2) | 2);
+        assert_eq!(
+            schema.get(data, "facing").unwrap(),
+            PropertyValue::Enum("south".to_owned())
+        );
+        assert_eq!(schema.get(data, "lit").unwrap(), PropertyValue::Bool(true));
+        assert_eq!(schema.get(data, "fuel").unwrap(), int(3));
+
+        //Setting one property leaves the others alone
+        let data = schema.set(data, "fuel", &int(-2)).unwrap();
+        assert_eq!(data, (1 << 2) | 2);
+        let data = schema
+            .set(data, "lit", &PropertyValue::Bool(false))
+            .unwrap();
+        assert_eq!(data, 2);
+        assert!(matches!(
+            schema.set(data, "fuel", &int(6)),
+            Err(Error::InvalidPropertyValue(..))
+        ));
+        assert!(matches!(
+            schema.set(data, "lit", &int(1)),
+            Err(Error::InvalidPropertyValue(..))
+        ));
+        assert!(matches!(
+            schema.get(data, "color"),
+            Err(Error::PropertyMissing(..))
+        ));
+    }
+
+    #[test]
+    fn every_state_round_trips_through_text() {
+        let schema = furnace();
+        for data in 0..1u16 << schema.bits() {
+            let text = schema.format(data).unwrap();
+            assert_eq!(schema.parse(&text).unwrap(), data, "{}", text);
+        }
+        assert_eq!(schema.format(0).unwrap(), "facing=north,lit=false,fuel=-2");
+
+        //Left out properties are defaults, whitespace and order don't matter
+        assert_eq!(schema.parse("").unwrap(), 0);
+        assert_eq!(
+            schema.parse(" fuel = 0 ,facing=west").unwrap(),
+            schema.parse("facing=west,lit=false,fuel=0").unwrap()
+        );
+        for state in [
+            "facing",
+            "facing=north,facing=east",
+            "lit=yes",
+            "fuel=9",
+            "color=red",
+        ] {
+            assert!(schema.parse(state).is_err(), "{}", state);
+        }
+    }
+
+    #[test]
+    fn int_ranges_can_use_all_bits() {
+        let mut schema = BlockStateSchema::new();
+        schema.add_int("value", -30000, 35535).unwrap();
+        assert_eq!(schema.bits(), DATA_BITS);
+        for data in [0, 1, 0x7FFF, 0xFFFF] {
+            schema.validate(data).unwrap();
+        }
+        assert_eq!(schema.get(0xFFFF, "value").unwrap(), int(35535));
+        assert_eq!(schema.parse("value=-30000").unwrap(), 0);
+
+        let mut schema = BlockStateSchema::new();
+        assert!(matches!(
+            schema.add_int("value", 0, 65536),
+            Err(Error::BlockStateTooLarge(..))
+        ));
+        assert!(matches!(
+            schema.add_int("value", i32::MIN, i32::MAX),
+            Err(Error::BlockStateTooLarge(..))
+        ));
+        assert!(matches!(
+            schema.add_int("value", 1, 0),
+            Err(Error::InvalidPropertyDefinition(..))
+        ));
+    }
+
+    #[test]
+    fn single_values_use_no_bits() {
+        let mut schema = BlockStateSchema::new();
+        schema.add_enum("kind", &["only"]).unwrap();
+        schema.add_int("level", 3, 3).unwrap();
+        schema.add_bool("open").unwrap();
+        assert_eq!(schema.bits(), 1);
+
+        assert_eq!(schema.format(1).unwrap(), "kind=only,level=3,open=true");
+        assert_eq!(schema.parse("kind=only,level=3").unwrap(), 0);
+        assert!(schema.validate(2).is_err());
+    }
+
+    #[test]
+    fn properties_past_16_bits_are_rejected() {
+        let mut schema = BlockStateSchema::new();
+        schema.add_int("a", 0, 4095).unwrap();
+        schema.add_enum("b", &["x", "y", "z"]).unwrap();
+        schema.add_bool("c").unwrap();
+        assert_eq!(schema.bits(), 15);
+        assert!(matches!(
+            schema.add_enum("d", &["x", "y", "z"]),
+            Err(Error::BlockStateTooLarge(..))
+        ));
+
+        //A failed property leaves the schema unchanged
+        assert!(schema.find_property("d").is_none());
+        schema.add_bool("d").unwrap();
+        assert_eq!(schema.bits(), 16);
+        assert!(matches!(
+            schema.add_bool("e"),
+            Err(Error::BlockStateTooLarge(..))
+        ));
+        assert!(matches!(
+            schema.add_bool("d"),
+            Err(Error::PropertyAlreadyDefined(..))
+        ));
+    }
+
+    #[test]
+    fn validate_rejects_stray_bits_and_unused_values() {
+        let schema = furnace();
+        schema.validate(0x3F).unwrap();
+        for data in [1 << 6, 0x8000, 0xFFFF] {
+            assert!(matches!(
+                schema.validate(data),
+                Err(Error::InvalidStateData(d)) if d == data
+            ));
+            assert!(schema.format(data).is_err());
+        }
+
+        //Three values in two bits leave one index unused
+        let mut schema = BlockStateSchema::new();
+        schema
+            .add_enum("half", &["bottom", "top", "double"])
+            .unwrap();
+        schema.validate(2).unwrap();
+        assert!(matches!(
+            schema.validate(3),
+            Err(Error::InvalidStateData(3))
+        ));
+
+        //Without properties only data 0 is valid
+        let empty = BlockStateRegistry::new();
+        empty.schema(1).validate(0).unwrap();
+        assert!(empty.schema(1).validate(1).is_err());
+    }
+}
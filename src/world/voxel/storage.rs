@@ -0,0 +1,741 @@
+//! Persistent storage of chunks in region files
+//!
+//! Chunks are grouped into regions of `REGION_SIZE`³ chunks, and every region is stored in one file.
+//! A region file starts with a header containing an offset table with one entry per chunk,
+//! followed by the encoded chunks. The file is divided into sectors of `SECTOR_SIZE` bytes and
+//! every chunk occupies a run of whole sectors. Sectors freed by chunks that moved or shrank are
+//! reused for later writes.
+//!
+//! A chunk is never overwritten in place. Its new data goes to free sectors and the offset table
+//! is only pointed at it afterwards, so a write that fails or is cut short by the process exiting
+//! leaves the old chunk intact.
+//!
+//! Region file layout, all numbers are little-endian:
+//! - The magic bytes `YRGN`
+//! - The format version (`u8`), followed by padding up to 8 bytes
+//! - The offset table, one entry per chunk in the same order as voxels in a `VoxelArray`,
+//!   each being the first sector (`u32`) and the length in bytes (`u32`) of the chunk.
+//!   A length of 0 means the chunk is not stored.
+//! - Sectors containing chunks, starting at the first sector after the header
+//!
+//! Every stored chunk is an encoded `VoxelArray`, optionally followed by its encoded block entities.
+//!
+//! Once a `NameRegistry` is bound, the directory also holds the world's name table
+//! and voxel IDs are translated when chunks are saved and loaded, see the `remap` module.
+
+//Uses
+use super::remap::IdMapping;
+use super::{BlockEntities, ComponentRegistry, Error, NameRegistry, Voxel, VoxelArray};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// Number of chunks along each axis of a region
+pub const REGION_SIZE: i32 = 32;
+const CHUNKS_PER_REGION: usize = (REGION_SIZE * REGION_SIZE * REGION_SIZE) as usize;
+
+const SECTOR_SIZE: u64 = 4096;
+const MAGIC: [u8; 4] = *b"YRGN";
+const VERSION: u8 = 1;
+const TABLE_OFFSET: u64 = 8;
+const TABLE_ENTRY_SIZE: u64 = 8;
+const HEADER_SECTORS: u32 =
+    (TABLE_OFFSET + CHUNKS_PER_REGION as u64 * TABLE_ENTRY_SIZE).div_ceil(SECTOR_SIZE) as u32;
+
+/// Number of region files kept open by default, see `RegionStorage::set_max_open_regions`
+pub const DEFAULT_MAX_OPEN_REGIONS: usize = 16;
+
+#[derive(Clone, Copy, Default)]
+struct TableEntry {
+    first_sector: u32,
+    length: u32,
+}
+
+impl TableEntry {
+    fn sector_count(&self) -> u32 {
+        sectors_for_length(self.length)
+    }
+}
+
+fn sectors_for_length(length: u32) -> u32 {
+    (length as u64).div_ceil(SECTOR_SIZE) as u32
+}
+
+struct RegionFile {
+    file: File,
+    table: Box<[TableEntry]>,
+    /// One entry per sector in the file, true if the sector is in use
+    used_sectors: Vec<bool>,
+    /// When the region was last accessed, used to close the least recently used regions
+    last_used: u64,
+}
+
+impl RegionFile {
+    fn create(path: &Path) -> Result<RegionFile, Error> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+
+        let mut header = vec![0; HEADER_SECTORS as usize * SECTOR_SIZE as usize];
+        header[..4].copy_from_slice(&MAGIC);
+        header[4] = VERSION;
+        file.write_all(&header)?;
+
+        Ok(RegionFile {
+            file,
+            table: vec![TableEntry::default(); CHUNKS_PER_REGION].into_boxed_slice(),
+            used_sectors: vec![true; HEADER_SECTORS as usize],
+            last_used: 0,
+        })
+    }
+
+    fn open(path: &Path) -> Result<RegionFile, Error> {
+        let invalid = || Error::InvalidRegionFile(path.to_owned());
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+
+        let mut header =
+            vec![0; (TABLE_OFFSET + CHUNKS_PER_REGION as u64 * TABLE_ENTRY_SIZE) as usize];
+        file.read_exact(&mut header).map_err(|_| invalid())?;
+        if header[..4] != MAGIC || header[4] != VERSION {
+            return Err(invalid());
+        }
+
+        let file_sectors = file.metadata()?.len().div_ceil(SECTOR_SIZE) as usize;
+        let mut used_sectors = vec![false; file_sectors.max(HEADER_SECTORS as usize)];
+        used_sectors[..HEADER_SECTORS as usize].fill(true);
+
+        let mut table = vec![TableEntry::default(); CHUNKS_PER_REGION].into_boxed_slice();
+        let entries = header[TABLE_OFFSET as usize..].chunks_exact(TABLE_ENTRY_SIZE as usize);
+        for (entry, bytes) in table.iter_mut().zip(entries) {
+            *entry = TableEntry {
+                first_sector: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+                length: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+            };
+            if entry.length == 0 {
+                continue;
+            }
+
+            //Chunks must lie within the file and may not overlap the header or each other
+            let first_sector = entry.first_sector as usize;
+            let sectors = first_sector..first_sector + entry.sector_count() as usize;
+            if sectors.end > used_sectors.len() {
+                return Err(invalid());
+            }
+            for used in &mut used_sectors[sectors] {
+                if *used {
+                    return Err(invalid());
+                }
+                *used = true;
+            }
+        }
+
+        Ok(RegionFile {
+            file,
+            table,
+            used_sectors,
+            last_used: 0,
+        })
+    }
+
+    fn read_chunk(&mut self, index: usize) -> Result<Option<Vec<u8>>, Error> {
+        let entry = self.table[index];
+        if entry.length == 0 {
+            return Ok(None);
+        }
+
+        let mut data = vec![0; entry.length as usize];
+        self.file
+            .seek(SeekFrom::Start(entry.first_sector as u64 * SECTOR_SIZE))?;
+        self.file.read_exact(&mut data)?;
+        Ok(Some(data))
+    }
+
+    fn write_chunk(&mut self, index: usize, data: &[u8]) -> Result<(), Error> {
+        let old_entry = self.table[index];
+        let needed_sectors = sectors_for_length(data.len() as u32);
+
+        //The old sectors stay in use until the table points at the new ones
+        let first_sector = self.allocate_sectors(needed_sectors);
+        let new_entry = TableEntry {
+            first_sector,
+            length: data.len() as u32,
+        };
+
+        //The table entry is only updated once the data is written
+        let result = self
+            .write_sectors(first_sector, needed_sectors, data)
+            .and_then(|_| self.write_table_entry(index, new_entry));
+        if let Err(error) = result {
+            self.set_sectors_used(first_sector, needed_sectors, false);
+            return Err(error);
+        }
+
+        self.set_sectors_used(old_entry.first_sector, old_entry.sector_count(), false);
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, first_sector: u32, count: u32, data: &[u8]) -> Result<(), Error> {
+        let mut padded = data.to_vec();
+        padded.resize(count as usize * SECTOR_SIZE as usize, 0);
+        self.file
+            .seek(SeekFrom::Start(first_sector as u64 * SECTOR_SIZE))?;
+        self.file.write_all(&padded)?;
+        Ok(())
+    }
+
+    fn delete_chunk(&mut self, index: usize) -> Result<(), Error> {
+        let old_entry = self.table[index];
+        self.write_table_entry(index, TableEntry::default())?;
+        self.set_sectors_used(old_entry.first_sector, old_entry.sector_count(), false);
+        Ok(())
+    }
+
+    fn write_table_entry(&mut self, index: usize, entry: TableEntry) -> Result<(), Error> {
+        let mut bytes = [0; TABLE_ENTRY_SIZE as usize];
+        bytes[0..4].copy_from_slice(&entry.first_sector.to_le_bytes());
+        bytes[4..8].copy_from_slice(&entry.length.to_le_bytes());
+        self.file.seek(SeekFrom::Start(
+            TABLE_OFFSET + index as u64 * TABLE_ENTRY_SIZE,
+        ))?;
+        self.file.write_all(&bytes)?;
+        self.table[index] = entry;
+        Ok(())
+    }
+
+    /// Finds the first run of free sectors that is large enough, growing the file if there is none
+    fn allocate_sectors(&mut self, count: u32) -> u32 {
+        let mut run_start = 0;
+        let mut run_length = 0;
+        for (sector, used) in self.used_sectors.iter().enumerate() {
+            if *used {
+                run_length = 0;
+                continue;
+            }
+            if run_length == 0 {
+                run_start = sector;
+            }
+            run_length += 1;
+            if run_length == count as usize {
+                break;
+            }
+        }
+
+        //A trailing run of free sectors can be extended at the end of the file
+        if run_length < count as usize {
+            if run_length == 0 {
+                run_start = self.used_sectors.len();
+            }
+            self.used_sectors.resize(run_start + count as usize, false);
+        }
+
+        self.set_sectors_used(run_start as u32, count, true);
+        run_start as u32
+    }
+
+    fn set_sectors_used(&mut self, first_sector: u32, count: u32, used: bool) {
+        let sectors = first_sector as usize..(first_sector + count) as usize;
+        self.used_sectors[sectors].fill(used);
+    }
+}
+
+/// A `RegionStorage` that is shared between the `VoxelSystem` and chunk workers
+pub type SharedStorage = Arc<Mutex<RegionStorage>>;
+
+/// Locks shared storage, a panic on another thread while it was locked doesn't make it unusable
+pub(super) fn lock_storage(storage: &SharedStorage) -> MutexGuard<'_, RegionStorage> {
+    storage.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Stores chunks in region files within a directory
+pub struct RegionStorage {
+    directory: PathBuf,
+    regions: HashMap<(i32, i32, i32), RegionFile>,
+    max_open_regions: usize,
+    /// Counts region accesses, the value at the last access is stored in every region
+    region_accesses: u64,
+    ids: Option<Arc<IdMapping>>,
+    components: Arc<ComponentRegistry>,
+}
+
+impl RegionStorage {
+    /// Opens the storage in the given directory, creating the directory if it does not exist
+    pub fn open<P: Into<PathBuf>>(directory: P) -> Result<RegionStorage, Error> {
+        let directory = directory.into();
+        std::fs::create_dir_all(&directory)?;
+        Ok(RegionStorage {
+            directory,
+            regions: HashMap::new(),
+            max_open_regions: DEFAULT_MAX_OPEN_REGIONS,
+            region_accesses: 0,
+            ids: None,
+            components: Arc::new(ComponentRegistry::new()),
+        })
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// Sets how many region files may be open at once, the least recently used ones are closed
+    /// when another region is needed. At least one region is always kept open.
+    pub fn set_max_open_regions(&mut self, count: usize) -> Result<(), Error> {
+        self.max_open_regions = count.max(1);
+        self.close_regions_until(self.max_open_regions)
+    }
+
+    /// Stores the names of the registry with the world, and translates voxel IDs between the
+    /// registry and the world from now on.
+    /// Voxels whose name is no longer registered are loaded as `placeholder`.
+    pub fn bind_name_registry(
+        &mut self,
+        registry: &NameRegistry,
+        placeholder: Voxel,
+    ) -> Result<(), Error> {
+        self.ids = Some(Arc::new(IdMapping::bind(
+            &self.directory,
+            registry,
+            placeholder,
+        )?));
+        Ok(())
+    }
+
+    /// Sets the voxel that replaces voxels with unknown names, has no effect if no registry is bound
+    pub fn set_placeholder(&mut self, placeholder: Voxel) {
+        if let Some(ids) = &mut self.ids {
+            Arc::make_mut(ids).set_placeholder(placeholder);
+        }
+    }
+
+    /// Sets the components that are saved and loaded with block entities
+    pub fn set_component_registry(&mut self, components: Arc<ComponentRegistry>) {
+        self.components = components;
+    }
+
+    /// Saves a chunk without block entities, replacing any that were saved with it before
+    pub fn save_chunk(&mut self, voxels: &VoxelArray, x: i32, y: i32, z: i32) -> Result<(), Error> {
+        self.save_chunk_with_entities(voxels, &BlockEntities::new(), x, y, z)
+    }
+
+    /// Saves a chunk together with the persistent components of its block entities
+    pub fn save_chunk_with_entities(
+        &mut self,
+        voxels: &VoxelArray,
+        entities: &BlockEntities,
+        x: i32,
+        y: i32,
+        z: i32,
+    ) -> Result<(), Error> {
+        let stored_voxels = self.ids.as_ref().and_then(|ids| ids.map_to_stored(voxels));
+        let mut data = Vec::new();
+        stored_voxels
+            .as_ref()
+            .unwrap_or(voxels)
+            .write_to(&mut data)?;
+        if !entities.is_empty() {
+            entities.write_to(&mut data, &self.components)?;
+        }
+
+        let (region_coords, index) = region_index(x, y, z);
+        self.get_region(region_coords, true)?
+            .unwrap()
+            .write_chunk(index, &data)
+    }
+
+    /// Loads a chunk, returns `None` if the chunk has never been saved. Block entities are skipped.
+    pub fn load_chunk(&mut self, x: i32, y: i32, z: i32) -> Result<Option<VoxelArray>, Error> {
+        Ok(self
+            .load_chunk_with_entities(x, y, z)?
+            .map(|(voxels, _)| voxels))
+    }
+
+    /// Loads a chunk together with its block entities, returns `None` if the chunk has never been saved
+    pub fn load_chunk_with_entities(
+        &mut self,
+        x: i32,
+        y: i32,
+        z: i32,
+    ) -> Result<Option<(VoxelArray, BlockEntities)>, Error> {
+        match self.read_chunk_data(x, y, z)? {
+            Some(data) => self.decoder().decode(&data).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads the encoded chunk without decoding it, so the decoding can happen without holding a lock
+    pub(super) fn read_chunk_data(
+        &mut self,
+        x: i32,
+        y: i32,
+        z: i32,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let (region_coords, index) = region_index(x, y, z);
+        match self.get_region(region_coords, false)? {
+            Some(region) => region.read_chunk(index),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns what is needed to decode chunks read with `read_chunk_data`
+    pub(super) fn decoder(&self) -> ChunkDecoder {
+        ChunkDecoder {
+            ids: self.ids.clone(),
+            components: self.components.clone(),
+        }
+    }
+
+    pub fn delete_chunk(&mut self, x: i32, y: i32, z: i32) -> Result<(), Error> {
+        let (region_coords, index) = region_index(x, y, z);
+        match self.get_region(region_coords, false)? {
+            Some(region) => region.delete_chunk(index),
+            None => Ok(()),
+        }
+    }
+
+    pub fn contains_chunk(&mut self, x: i32, y: i32, z: i32) -> Result<bool, Error> {
+        let (region_coords, index) = region_index(x, y, z);
+        match self.get_region(region_coords, false)? {
+            Some(region) => Ok(region.table[index].length != 0),
+            None => Ok(false),
+        }
+    }
+
+    /// Writes all pending changes to disk
+    pub fn flush(&mut self) -> Result<(), Error> {
+        for region in self.regions.values_mut() {
+            region.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    fn get_region(
+        &mut self,
+        region_coords: (i32, i32, i32),
+        create: bool,
+    ) -> Result<Option<&mut RegionFile>, Error> {
+        if !self.regions.contains_key(&region_coords) {
+            let path = self.directory.join(format!(
+                "r.{}.{}.{}.yrg",
+                region_coords.0, region_coords.1, region_coords.2
+            ));
+            let region = if path.exists() {
+                RegionFile::open(&path)?
+            } else if create {
+                RegionFile::create(&path)?
+            } else {
+                return Ok(None);
+            };
+            self.close_regions_until(self.max_open_regions - 1)?;
+            self.regions.insert(region_coords, region);
+        }
+
+        self.region_accesses += 1;
+        let region = self.regions.get_mut(&region_coords).unwrap();
+        region.last_used = self.region_accesses;
+        Ok(Some(region))
+    }
+
+    /// Closes the least recently used regions until at most `count` are open
+    fn close_regions_until(&mut self, count: usize) -> Result<(), Error> {
+        while self.regions.len() > count {
+            let oldest = *self
+                .regions
+                .iter()
+                .min_by_key(|(_, region)| region.last_used)
+                .unwrap()
+                .0;
+            //Closing a file doesn't sync it, so it is done here to keep the promise of `flush`
+            self.regions.remove(&oldest).unwrap().file.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+/// Decodes chunks read from a `RegionStorage`, independently of the storage
+pub(super) struct ChunkDecoder {
+    ids: Option<Arc<IdMapping>>,
+    components: Arc<ComponentRegistry>,
+}
+
+impl ChunkDecoder {
+    pub(super) fn decode(&self, data: &[u8]) -> Result<(VoxelArray, BlockEntities), Error> {
+        let mut reader = data;
+        let mut voxels = VoxelArray::read_from(&mut reader)?;
+        //Chunks without block entities end right after the voxels
+        let entities = if reader.is_empty() {
+            BlockEntities::new()
+        } else {
+            BlockEntities::read_from(&mut reader, &self.components)?
+        };
+        if let Some(ids) = &self.ids {
+            ids.map_from_stored(&mut voxels);
+        }
+        Ok((voxels, entities))
+    }
+}
+
+/// Returns the coordinates of the region containing a chunk and the index of the chunk within the region
+fn region_index(x: i32, y: i32, z: i32) -> ((i32, i32, i32), usize) {
+    let region_coords = (
+        x.div_euclid(REGION_SIZE),
+        y.div_euclid(REGION_SIZE),
+        z.div_euclid(REGION_SIZE),
+    );
+    let (local_x, local_y, local_z) = (
+        x.rem_euclid(REGION_SIZE) as usize,
+        y.rem_euclid(REGION_SIZE) as usize,
+        z.rem_euclid(REGION_SIZE) as usize,
+    );
+    let size = REGION_SIZE as usize;
+    (
+        region_coords,
+        local_z * size * size + local_y * size + local_x,
+    )
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use crate::world::test_util::assert_same_voxels;
+    use crate::world::voxel::array::VOXEL_COUNT;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A directory in the system's temporary directory that is deleted when dropped
+    pub(in crate::world::voxel) struct TempDir(PathBuf);
+
+    impl TempDir {
+        pub(in crate::world::voxel) fn new(name: &str) -> TempDir {
+            static COUNTER: AtomicUsize = AtomicUsize::new(0);
+            let path = std::env::temp_dir().join(format!(
+                "yamc-{}-{}-{}",
+                name,
+                std::process::id(),
+                COUNTER.fetch_add(1, Ordering::Relaxed)
+            ));
+            let _ = std::fs::remove_dir_all(&path);
+            TempDir(path)
+        }
+
+        pub(in crate::world::voxel) fn path(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// A chunk of distinct voxels that doesn't compress, its encoding spans several sectors
+    fn noisy_chunk(seed: u16) -> VoxelArray {
+        let mut array = VoxelArray::new(Voxel { id: 0, data: 0 });
+        for i in 0..VOXEL_COUNT {
+            array.set_voxel_at_index(
+                i,
+                Voxel {
+                    id: (i as u16).wrapping_mul(31).wrapping_add(seed),
+                    data: seed,
+                },
+            );
+        }
+        array
+    }
+
+    fn uniform_chunk(id: u16) -> VoxelArray {
+        VoxelArray::new(Voxel { id, data: 0 })
+    }
+
+    fn region(storage: &mut RegionStorage) -> &mut RegionFile {
+        storage.get_region((0, 0, 0), false).unwrap().unwrap()
+    }
+
+    fn entry(storage: &mut RegionStorage, x: i32, y: i32, z: i32) -> TableEntry {
+        let (_, index) = region_index(x, y, z);
+        region(storage).table[index]
+    }
+
+    #[test]
+    fn chunks_are_stored_after_the_header_without_overlapping() {
+        let dir = TempDir::new("storage-allocation");
+        let mut storage = RegionStorage::open(dir.path()).unwrap();
+        storage.save_chunk(&noisy_chunk(1), 0, 0, 0).unwrap();
+        storage.save_chunk(&uniform_chunk(2), 1, 0, 0).unwrap();
+
+        let first = entry(&mut storage, 0, 0, 0);
+        let second = entry(&mut storage, 1, 0, 0);
+        assert!(first.sector_count() > 1);
+        assert_eq!(second.sector_count(), 1);
+        assert_eq!(first.first_sector, HEADER_SECTORS);
+        assert_eq!(second.first_sector, HEADER_SECTORS + first.sector_count());
+        assert_eq!(
+            region(&mut storage).used_sectors.len(),
+            (HEADER_SECTORS + first.sector_count() + 1) as usize
+        );
+        assert!(!storage.contains_chunk(2, 0, 0).unwrap());
+        assert!(storage.load_chunk(2, 0, 0).unwrap().is_none());
+    }
+
+    #[test]
+    fn freed_sectors_are_reused() {
+        let dir = TempDir::new("storage-reuse");
+        let mut storage = RegionStorage::open(dir.path()).unwrap();
+        storage.save_chunk(&noisy_chunk(1), 0, 0, 0).unwrap();
+        storage.save_chunk(&uniform_chunk(2), 1, 0, 0).unwrap();
+        let noisy_sectors = entry(&mut storage, 0, 0, 0).sector_count();
+        let file_sectors = region(&mut storage).used_sectors.len();
+
+        //The shrunk chunk can't go to its own sectors, they are in use until it has moved
+        storage.save_chunk(&uniform_chunk(3), 0, 0, 0).unwrap();
+        assert_eq!(
+            entry(&mut storage, 0, 0, 0).first_sector,
+            file_sectors as u32
+        );
+
+        //Its old sectors are free now and get filled before the file grows
+        storage.save_chunk(&noisy_chunk(4), 2, 0, 0).unwrap();
+        storage.save_chunk(&uniform_chunk(5), 3, 0, 0).unwrap();
+        assert_eq!(entry(&mut storage, 2, 0, 0).first_sector, HEADER_SECTORS);
+        assert_eq!(entry(&mut storage, 2, 0, 0).sector_count(), noisy_sectors);
+        assert_eq!(region(&mut storage).used_sectors.len(), file_sectors + 2);
+
+        storage.delete_chunk(1, 0, 0).unwrap();
+        assert!(!storage.contains_chunk(1, 0, 0).unwrap());
+        storage.save_chunk(&uniform_chunk(6), 4, 0, 0).unwrap();
+        assert_eq!(
+            entry(&mut storage, 4, 0, 0).first_sector,
+            HEADER_SECTORS + noisy_sectors
+        );
+
+        assert_same_voxels(
+            &storage.load_chunk(0, 0, 0).unwrap().unwrap(),
+            &uniform_chunk(3),
+        );
+        assert_same_voxels(
+            &storage.load_chunk(2, 0, 0).unwrap().unwrap(),
+            &noisy_chunk(4),
+        );
+        assert_same_voxels(
+            &storage.load_chunk(4, 0, 0).unwrap().unwrap(),
+            &uniform_chunk(6),
+        );
+    }
+
+    #[test]
+    fn growing_chunks_move_to_the_end_of_the_file() {
+        let dir = TempDir::new("storage-growth");
+        let mut storage = RegionStorage::open(dir.path()).unwrap();
+        storage.save_chunk(&uniform_chunk(1), 0, 0, 0).unwrap();
+        storage.save_chunk(&uniform_chunk(2), 1, 0, 0).unwrap();
+
+        storage.save_chunk(&noisy_chunk(3), 0, 0, 0).unwrap();
+        let grown = entry(&mut storage, 0, 0, 0);
+        assert_eq!(grown.first_sector, HEADER_SECTORS + 2);
+        assert_eq!(
+            region(&mut storage).used_sectors.len(),
+            (grown.first_sector + grown.sector_count()) as usize
+        );
+        assert_same_voxels(
+            &storage.load_chunk(0, 0, 0).unwrap().unwrap(),
+            &noisy_chunk(3),
+        );
+        assert_same_voxels(
+            &storage.load_chunk(1, 0, 0).unwrap().unwrap(),
+            &uniform_chunk(2),
+        );
+    }
+
+    #[test]
+    fn chunks_survive_closing_and_reopening() {
+        let dir = TempDir::new("storage-reopen");
+        let chunks = [
+            ((0, 0, 0), noisy_chunk(1)),
+            ((-1, 5, 40), uniform_chunk(2)),
+            ((31, -32, 7), noisy_chunk(3)),
+        ];
+        {
+            let mut storage = RegionStorage::open(dir.path()).unwrap();
+            for ((x, y, z), chunk) in chunks.iter() {
+                storage.save_chunk(&uniform_chunk(9), *x, *y, *z).unwrap();
+                storage.save_chunk(chunk, *x, *y, *z).unwrap();
+            }
+            storage.save_chunk(&uniform_chunk(9), 1, 1, 1).unwrap();
+            storage.delete_chunk(1, 1, 1).unwrap();
+            storage.flush().unwrap();
+        }
+
+        let mut storage = RegionStorage::open(dir.path()).unwrap();
+        for ((x, y, z), chunk) in chunks.iter() {
+            assert_same_voxels(&storage.load_chunk(*x, *y, *z).unwrap().unwrap(), chunk);
+        }
+        assert!(!storage.contains_chunk(1, 1, 1).unwrap());
+
+        //The sectors of replaced and deleted chunks are known to be free after reopening
+        storage.save_chunk(&uniform_chunk(10), 2, 2, 2).unwrap();
+        assert_eq!(entry(&mut storage, 2, 2, 2).first_sector, HEADER_SECTORS);
+    }
+
+    #[test]
+    fn unfinished_writes_keep_the_old_chunk() {
+        let dir = TempDir::new("storage-unfinished");
+        {
+            let mut storage = RegionStorage::open(dir.path()).unwrap();
+            storage.save_chunk(&noisy_chunk(1), 0, 0, 0).unwrap();
+
+            //Write new data the way write_chunk does, but stop before the table is updated
+            let mut data = Vec::new();
+            noisy_chunk(2).write_to(&mut data).unwrap();
+            let region = region(&mut storage);
+            let sectors = sectors_for_length(data.len() as u32);
+            let first_sector = region.allocate_sectors(sectors);
+            assert_ne!(first_sector, HEADER_SECTORS);
+            region.write_sectors(first_sector, sectors, &data).unwrap();
+        }
+
+        let mut storage = RegionStorage::open(dir.path()).unwrap();
+        assert_same_voxels(
+            &storage.load_chunk(0, 0, 0).unwrap().unwrap(),
+            &noisy_chunk(1),
+        );
+    }
+
+    #[test]
+    fn least_recently_used_regions_are_closed() {
+        let dir = TempDir::new("storage-regions");
+        let mut storage = RegionStorage::open(dir.path()).unwrap();
+        storage.set_max_open_regions(2).unwrap();
+
+        for region_x in 0..4 {
+            storage
+                .save_chunk(
+                    &uniform_chunk(region_x as u16),
+                    region_x * REGION_SIZE,
+                    0,
+                    0,
+                )
+                .unwrap();
+        }
+        assert_eq!(storage.regions.len(), 2);
+        assert!(storage.regions.contains_key(&(2, 0, 0)));
+        assert!(storage.regions.contains_key(&(3, 0, 0)));
+
+        //Using a region keeps it open while another one is opened
+        storage.load_chunk(2 * REGION_SIZE, 0, 0).unwrap();
+        storage.load_chunk(0, 0, 0).unwrap();
+        assert!(storage.regions.contains_key(&(2, 0, 0)));
+        assert!(storage.regions.contains_key(&(0, 0, 0)));
+
+        for region_x in 0..4 {
+            let chunk = storage
+                .load_chunk(region_x * REGION_SIZE, 0, 0)
+                .unwrap()
+                .unwrap();
+            assert_same_voxels(&chunk, &uniform_chunk(region_x as u16));
+        }
+        storage.set_max_open_regions(0).unwrap();
+        assert_eq!(storage.regions.len(), 1);
+    }
+}
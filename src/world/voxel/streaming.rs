@@ -0,0 +1,253 @@
+//! Loading and unloading chunks around focus points
+//!
+//! A focus point is a chunk position with a load radius, for example around a player.
+//! Every chunk within the radius of any focus point is wanted and gets loaded, closest chunks first.
+//! Chunks are only unloaded once they are further than the radius plus a margin from every focus point,
+//! so moving back and forth across a chunk border doesn't load and unload chunks repeatedly.
+//!
+//! Only chunks loaded by streaming are ever unloaded by it, chunks loaded by hand are left alone.
+//!
+//! The wanted chunks are only worked out again once a focus point moves to another chunk or
+//! changes its radius, and chunks that are already queued with the same priority are not requested again.
+//! Updates only go over the wanted and streamed chunks when something changed since the last pass:
+//! a focus point or the settings, a chunk finishing or failing in the worker pool, a chunk unloaded by hand,
+//! or the last pass stopping early because of the sync load limit or a full queue.
+
+//Uses
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+/// Identifies a focus point registered with `VoxelSystem::add_focus_point`
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct FocusPointId(u64);
+
+#[derive(Clone, Copy, Debug)]
+pub struct StreamingSettings {
+    /// Extra distance in chunks beyond the load radius before a chunk is unloaded
+    pub unload_margin: u32,
+    /// Chunks loaded on the calling thread per update when no worker pool is set
+    pub sync_loads_per_update: usize,
+}
+
+impl Default for StreamingSettings {
+    fn default() -> StreamingSettings {
+        StreamingSettings {
+            unload_margin: 1,
+            sync_loads_per_update: 4,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct FocusPoint {
+    x: i32,
+    y: i32,
+    z: i32,
+    radius: u32,
+}
+
+impl FocusPoint {
+    fn distance_squared(&self, x: i32, y: i32, z: i32) -> u64 {
+        let dx = (x as i64 - self.x as i64).unsigned_abs();
+        let dy = (y as i64 - self.y as i64).unsigned_abs();
+        let dz = (z as i64 - self.z as i64).unsigned_abs();
+        dx * dx + dy * dy + dz * dz
+    }
+}
+
+/// Every chunk within the radius of a focus point
+pub(super) struct WantedChunks {
+    /// Sorted by the squared distance to the closest focus point, which is stored with every chunk
+    pub(super) sorted: Vec<((i32, i32, i32), u64)>,
+    pub(super) coords: HashSet<(i32, i32, i32)>,
+}
+
+#[derive(Default)]
+pub(super) struct ChunkStreamer {
+    focus_points: HashMap<FocusPointId, FocusPoint>,
+    next_id: u64,
+    pub(super) settings: StreamingSettings,
+    /// Chunks that were requested or loaded by streaming
+    pub(super) streamed: HashSet<(i32, i32, i32)>,
+    /// Chunks that failed to load, they are not retried until they stop being wanted
+    pub(super) failed: HashSet<(i32, i32, i32)>,
+    /// Cached result of `wanted_chunks`, cleared whenever a focus point changes
+    wanted: Option<Arc<WantedChunks>>,
+    /// Whether chunks have to be loaded or unloaded by the next update
+    pub(super) outdated: bool,
+}
+
+impl ChunkStreamer {
+    pub(super) fn add_focus_point(&mut self, x: i32, y: i32, z: i32, radius: u32) -> FocusPointId {
+        let id = FocusPointId(self.next_id);
+        self.next_id += 1;
+        self.focus_points.insert(id, FocusPoint { x, y, z, radius });
+        self.invalidate();
+        id
+    }
+
+    /// Returns false if the focus point does not exist
+    pub(super) fn move_focus_point(&mut self, id: FocusPointId, x: i32, y: i32, z: i32) -> bool {
+        match self.focus_points.get_mut(&id) {
+            Some(point) => {
+                if (point.x, point.y, point.z) != (x, y, z) {
+                    (point.x, point.y, point.z) = (x, y, z);
+                    self.invalidate();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns false if the focus point does not exist
+    pub(super) fn set_focus_radius(&mut self, id: FocusPointId, radius: u32) -> bool {
+        match self.focus_points.get_mut(&id) {
+            Some(point) => {
+                if point.radius != radius {
+                    point.radius = radius;
+                    self.invalidate();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub(super) fn remove_focus_point(&mut self, id: FocusPointId) -> bool {
+        let removed = self.focus_points.remove(&id).is_some();
+        if removed {
+            self.invalidate();
+        }
+        removed
+    }
+
+    fn invalidate(&mut self) {
+        self.wanted = None;
+        self.outdated = true;
+    }
+
+    /// Returns every chunk within the radius of a focus point
+    pub(super) fn wanted_chunks(&mut self) -> Arc<WantedChunks> {
+        let focus_points = &self.focus_points;
+        self.wanted
+            .get_or_insert_with(|| Arc::new(find_wanted_chunks(focus_points)))
+            .clone()
+    }
+
+    /// Returns true if the chunk is close enough to a focus point to stay loaded
+    pub(super) fn is_retained(&self, x: i32, y: i32, z: i32) -> bool {
+        self.focus_points.values().any(|point| {
+            let radius = point.radius as u64 + self.settings.unload_margin as u64;
+            point.distance_squared(x, y, z) <= radius * radius
+        })
+    }
+}
+
+fn find_wanted_chunks(focus_points: &HashMap<FocusPointId, FocusPoint>) -> WantedChunks {
+    let mut wanted: HashMap<(i32, i32, i32), u64> = HashMap::new();
+    for point in focus_points.values() {
+        let radius = point.radius as i64;
+        let radius_squared = (radius * radius) as u64;
+        for dz in -radius..=radius {
+            for dy in -radius..=radius {
+                for dx in -radius..=radius {
+                    let coords = (
+                        point.x.checked_add(dx as i32),
+                        point.y.checked_add(dy as i32),
+                        point.z.checked_add(dz as i32),
+                    );
+                    let (x, y, z) = match coords {
+                        (Some(x), Some(y), Some(z)) => (x, y, z),
+                        _ => continue,
+                    };
+                    let distance = point.distance_squared(x, y, z);
+                    if distance > radius_squared {
+                        continue;
+                    }
+                    wanted
+                        .entry((x, y, z))
+                        .and_modify(|closest| *closest = (*closest).min(distance))
+                        .or_insert(distance);
+                }
+            }
+        }
+    }
+
+    let mut sorted: Vec<_> = wanted.into_iter().collect();
+    sorted.sort_unstable_by_key(|(coords, distance)| (*distance, *coords));
+    let coords = sorted.iter().map(|(coords, _)| *coords).collect();
+    WantedChunks { sorted, coords }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn distance_of(wanted: &WantedChunks, coords: (i32, i32, i32)) -> Option<u64> {
+        wanted
+            .sorted
+            .iter()
+            .find(|(chunk, _)| *chunk == coords)
+            .map(|(_, distance)| *distance)
+    }
+
+    #[test]
+    fn wanted_chunks_are_sorted_by_distance() {
+        let mut streamer = ChunkStreamer::default();
+        streamer.add_focus_point(-5, 3, 10, 2);
+        let wanted = streamer.wanted_chunks();
+
+        assert_eq!(wanted.sorted[0], ((-5, 3, 10), 0));
+        assert!(wanted.sorted.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+        assert_eq!(wanted.sorted.len(), wanted.coords.len());
+        assert_eq!(distance_of(&wanted, (-3, 3, 10)), Some(4));
+        assert_eq!(distance_of(&wanted, (-3, 4, 10)), None);
+    }
+
+    #[test]
+    fn overlapping_focus_points_keep_the_closest_distance() {
+        let mut streamer = ChunkStreamer::default();
+        streamer.add_focus_point(0, 0, 0, 2);
+        streamer.add_focus_point(2, 0, 0, 2);
+        let wanted = streamer.wanted_chunks();
+
+        //Every chunk is wanted once, with its distance to the closer point
+        assert_eq!(wanted.sorted.len(), wanted.coords.len());
+        assert_eq!(distance_of(&wanted, (1, 0, 0)), Some(1));
+        assert_eq!(distance_of(&wanted, (2, 1, 0)), Some(1));
+        assert_eq!(distance_of(&wanted, (-2, 0, 0)), Some(4));
+        assert_eq!(distance_of(&wanted, (4, 0, 0)), Some(4));
+        assert!(streamer.is_retained(4, 0, 0));
+        assert!(!streamer.is_retained(6, 0, 0));
+    }
+
+    #[test]
+    fn wanted_chunks_are_cached_until_a_focus_point_changes() {
+        let mut streamer = ChunkStreamer::default();
+        let id = streamer.add_focus_point(0, 0, 0, 1);
+        let first = streamer.wanted_chunks();
+        assert!(Arc::ptr_eq(&first, &streamer.wanted_chunks()));
+
+        //Staying in the same chunk with the same radius keeps the cache
+        streamer.move_focus_point(id, 0, 0, 0);
+        streamer.set_focus_radius(id, 1);
+        assert!(Arc::ptr_eq(&first, &streamer.wanted_chunks()));
+
+        streamer.move_focus_point(id, 1, 0, 0);
+        let moved = streamer.wanted_chunks();
+        assert!(!Arc::ptr_eq(&first, &moved));
+        assert!(moved.coords.contains(&(2, 0, 0)));
+
+        streamer.set_focus_radius(id, 2);
+        let widened = streamer.wanted_chunks();
+        assert!(!Arc::ptr_eq(&moved, &widened));
+        assert!(widened.coords.contains(&(3, 0, 0)));
+
+        let other = streamer.add_focus_point(10, 0, 0, 0);
+        assert!(streamer.wanted_chunks().coords.contains(&(10, 0, 0)));
+        assert!(streamer.remove_focus_point(other));
+        assert!(!streamer.wanted_chunks().coords.contains(&(10, 0, 0)));
+        assert!(!streamer.remove_focus_point(other));
+    }
+}
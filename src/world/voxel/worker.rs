@@ -0,0 +1,559 @@
+//! Loading and generation of chunks on background threads
+//!
+//! Requests are queued by priority and picked up by a fixed number of worker threads.
+//! Every worker first tries to load the chunk from storage and generates it otherwise.
+//! Finished chunks are collected on the main thread with `ChunkWorkerPool::poll`.
+//!
+//! Requests can be cancelled at any time. Cancelled requests that are still queued are skipped,
+//! and results of cancelled requests that were already running are dropped when polled.
+//!
+//! Storage is only locked while a chunk is read from its region file, decoding happens afterwards
+//! so workers can decode in parallel. A generator that panics doesn't take its worker down,
+//! the panic is reported as the result of the request instead.
+
+//Uses
+use super::storage::{lock_storage, SharedStorage};
+use super::{BlockEntities, ChunkGenerator, Error, VoxelArray};
+use std::any::Any;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread::JoinHandle;
+
+/// Where workers get chunks from
+#[derive(Clone, Default)]
+pub struct ChunkSource {
+    /// Storage is checked first, chunks that have been saved are never generated
+    pub storage: Option<SharedStorage>,
+    pub generator: Option<Arc<dyn ChunkGenerator>>,
+    pub seed: u64,
+}
+
+impl ChunkSource {
+    fn get_chunk(&self, x: i32, y: i32, z: i32) -> Result<(VoxelArray, BlockEntities), Error> {
+        if let Some(storage) = &self.storage {
+            let (data, decoder) = {
+                let mut storage = lock_storage(storage);
+                (storage.read_chunk_data(x, y, z)?, storage.decoder())
+            };
+            if let Some(data) = data {
+                return decoder.decode(&data);
+            }
+        }
+        let generator = self.generator.as_ref().ok_or(Error::GeneratorMissing)?;
+        Ok((generator.generate(x, y, z, self.seed), BlockEntities::new()))
+    }
+}
+
+/// A chunk request that was processed by a worker
+pub struct FinishedChunk {
+    pub x: i32,
+    pub y: i32,
+    pub z: i32,
+    pub result: Result<(VoxelArray, BlockEntities), Error>,
+}
+
+/// An entry in the priority queue, stale entries are skipped when popped
+struct QueuedRequest {
+    priority: u32,
+    sequence: u64,
+    coords: (i32, i32, i32),
+}
+
+impl Ord for QueuedRequest {
+    //`BinaryHeap` pops the greatest entry, so lower priority values and older requests must compare greater
+    fn cmp(&self, other: &QueuedRequest) -> Ordering {
+        other
+            .priority
+            .cmp(&self.priority)
+            .then(other.sequence.cmp(&self.sequence))
+    }
+}
+
+impl PartialOrd for QueuedRequest {
+    fn partial_cmp(&self, other: &QueuedRequest) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for QueuedRequest {
+    fn eq(&self, other: &QueuedRequest) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for QueuedRequest {}
+
+struct ActiveRequest {
+    sequence: u64,
+    priority: u32,
+    running: bool,
+    source: ChunkSource,
+}
+
+struct QueueState {
+    heap: BinaryHeap<QueuedRequest>,
+    /// Requests that are queued or running, results are only accepted for these
+    active: HashMap<(i32, i32, i32), ActiveRequest>,
+    queued_count: usize,
+    next_sequence: u64,
+    shutdown: bool,
+}
+
+struct Shared {
+    state: Mutex<QueueState>,
+    work_available: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, QueueState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+pub struct ChunkWorkerPool {
+    shared: Arc<Shared>,
+    threads: Vec<JoinHandle<()>>,
+    results_rx: mpsc::Receiver<(u64, FinishedChunk)>,
+    queue_capacity: usize,
+}
+
+impl ChunkWorkerPool {
+    /// Starts `thread_count` worker threads, at most `queue_capacity` requests can wait at once
+    pub fn new(thread_count: usize, queue_capacity: usize) -> ChunkWorkerPool {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(QueueState {
+                heap: BinaryHeap::new(),
+                active: HashMap::new(),
+                queued_count: 0,
+                next_sequence: 0,
+                shutdown: false,
+            }),
+            work_available: Condvar::new(),
+        });
+        let (results_tx, results_rx) = mpsc::channel();
+
+        let threads = (0..thread_count.max(1))
+            .map(|i| {
+                let shared = shared.clone();
+                let results_tx = results_tx.clone();
+                std::thread::Builder::new()
+                    .name(format!("chunk-worker-{}", i))
+                    .spawn(move || run_worker(&shared, &results_tx))
+                    .expect("Failed to spawn chunk worker thread")
+            })
+            .collect();
+
+        ChunkWorkerPool {
+            shared,
+            threads,
+            results_rx,
+            queue_capacity,
+        }
+    }
+
+    /// Queues a chunk. Requests with a lower priority value are processed first.
+    ///
+    /// Requesting a chunk that is already queued replaces its priority and source,
+    /// requesting a chunk that is already running does nothing.
+    pub fn request(
+        &self,
+        x: i32,
+        y: i32,
+        z: i32,
+        priority: u32,
+        source: ChunkSource,
+    ) -> Result<(), Error> {
+        let mut state = self.shared.lock();
+        let is_queued = match state.active.get(&(x, y, z)) {
+            Some(request) if request.running => return Ok(()),
+            Some(_) => true,
+            None => false,
+        };
+        if !is_queued {
+            if state.queued_count >= self.queue_capacity {
+                return Err(Error::WorkerQueueFull);
+            }
+            state.queued_count += 1;
+        }
+
+        let sequence = state.next_sequence;
+        state.next_sequence += 1;
+        state.active.insert(
+            (x, y, z),
+            ActiveRequest {
+                sequence,
+                priority,
+                running: false,
+                source,
+            },
+        );
+        state.heap.push(QueuedRequest {
+            priority,
+            sequence,
+            coords: (x, y, z),
+        });
+
+        //Drop stale entries once they make up most of the heap
+        if state.heap.len() > 2 * state.queued_count + 64 {
+            let QueueState { heap, active, .. } = &mut *state;
+            heap.retain(|entry| is_current(active, entry));
+        }
+
+        drop(state);
+        self.shared.work_available.notify_one();
+        Ok(())
+    }
+
+    /// Cancels a queued or running request, returns false if the chunk was not requested
+    pub fn cancel(&self, x: i32, y: i32, z: i32) -> bool {
+        let mut state = self.shared.lock();
+        match state.active.remove(&(x, y, z)) {
+            Some(request) => {
+                if !request.running {
+                    state.queued_count -= 1;
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Cancels every request
+    pub fn cancel_all(&self) {
+        let mut state = self.shared.lock();
+        state.active.clear();
+        state.heap.clear();
+        state.queued_count = 0;
+    }
+
+    /// Returns true if the chunk is queued or running
+    pub fn is_requested(&self, x: i32, y: i32, z: i32) -> bool {
+        self.shared.lock().active.contains_key(&(x, y, z))
+    }
+
+    /// Returns the priority of the chunk's request, `None` if the chunk is neither queued nor running
+    pub fn requested_priority(&self, x: i32, y: i32, z: i32) -> Option<u32> {
+        self.shared
+            .lock()
+            .active
+            .get(&(x, y, z))
+            .map(|request| request.priority)
+    }
+
+    /// The number of requests waiting for a worker
+    pub fn queued_count(&self) -> usize {
+        self.shared.lock().queued_count
+    }
+
+    pub fn queue_capacity(&self) -> usize {
+        self.queue_capacity
+    }
+
+    /// Returns every chunk that has finished since the last call, without blocking
+    pub fn poll(&self) -> Vec<FinishedChunk> {
+        let mut finished = Vec::new();
+        for (sequence, chunk) in self.results_rx.try_iter() {
+            let mut state = self.shared.lock();
+            let coords = (chunk.x, chunk.y, chunk.z);
+            //The request may have been cancelled or replaced while it was running
+            if matches!(state.active.get(&coords), Some(request) if request.sequence == sequence) {
+                state.active.remove(&coords);
+                finished.push(chunk);
+            }
+        }
+        finished
+    }
+}
+
+impl Drop for ChunkWorkerPool {
+    fn drop(&mut self) {
+        self.shared.lock().shutdown = true;
+        self.shared.work_available.notify_all();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn is_current(active: &HashMap<(i32, i32, i32), ActiveRequest>, entry: &QueuedRequest) -> bool {
+    matches!(active.get(&entry.coords), Some(request) if request.sequence == entry.sequence && !request.running)
+}
+
+fn run_worker(shared: &Shared, results_tx: &mpsc::Sender<(u64, FinishedChunk)>) {
+    loop {
+        let (sequence, (x, y, z), source) = {
+            let mut state = shared.lock();
+            loop {
+                if state.shutdown {
+                    return;
+                }
+                match state.heap.pop() {
+                    Some(entry) if is_current(&state.active, &entry) => {
+                        state.queued_count -= 1;
+                        let request = state.active.get_mut(&entry.coords).unwrap();
+                        request.running = true;
+                        break (entry.sequence, entry.coords, request.source.clone());
+                    }
+                    Some(_) => continue,
+                    None => {
+                        state = shared
+                            .work_available
+                            .wait(state)
+                            .unwrap_or_else(PoisonError::into_inner)
+                    }
+                }
+            }
+        };
+
+        //A panic is turned into an error, so the request is finished and the worker keeps running
+        let result = catch_unwind(AssertUnwindSafe(|| source.get_chunk(x, y, z)))
+            .unwrap_or_else(|panic| Err(Error::ChunkWorkerPanicked(x, y, z, panic_message(panic))));
+        if results_tx
+            .send((sequence, FinishedChunk { x, y, z, result }))
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => match panic.downcast::<&'static str>() {
+            Ok(message) => (*message).to_owned(),
+            Err(_) => "unknown panic".to_owned(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::voxel::storage::tests::TempDir;
+    use crate::world::voxel::{RegionStorage, Voxel};
+    use std::time::{Duration, Instant};
+
+    /// Chunks with this x coordinate wait until the gate is opened
+    const BLOCKING_X: i32 = 100;
+    /// Chunks with this x coordinate make the generator panic
+    const PANICKING_X: i32 = -100;
+
+    /// Generates chunks filled with their x coordinate and reports every chunk it starts
+    struct TestGenerator {
+        started: Mutex<mpsc::Sender<(i32, i32, i32)>>,
+        gate_open: Mutex<bool>,
+        gate_opened: Condvar,
+    }
+
+    impl TestGenerator {
+        fn new() -> (Arc<TestGenerator>, mpsc::Receiver<(i32, i32, i32)>) {
+            let (started_tx, started_rx) = mpsc::channel();
+            let generator = TestGenerator {
+                started: Mutex::new(started_tx),
+                gate_open: Mutex::new(false),
+                gate_opened: Condvar::new(),
+            };
+            (Arc::new(generator), started_rx)
+        }
+
+        fn open_gate(&self) {
+            *self.gate_open.lock().unwrap() = true;
+            self.gate_opened.notify_all();
+        }
+    }
+
+    impl ChunkGenerator for TestGenerator {
+        fn generate(&self, x: i32, y: i32, z: i32, _seed: u64) -> VoxelArray {
+            self.started.lock().unwrap().send((x, y, z)).unwrap();
+            if x == PANICKING_X {
+                panic!("generator failure");
+            }
+            if x == BLOCKING_X {
+                let mut open = self.gate_open.lock().unwrap();
+                while !*open {
+                    open = self.gate_opened.wait(open).unwrap();
+                }
+            }
+            VoxelArray::new(Voxel {
+                id: x as u16,
+                data: 0,
+            })
+        }
+    }
+
+    fn source(generator: &Arc<TestGenerator>) -> ChunkSource {
+        ChunkSource {
+            generator: Some(generator.clone()),
+            ..ChunkSource::default()
+        }
+    }
+
+    fn wait_started(started: &mpsc::Receiver<(i32, i32, i32)>) -> (i32, i32, i32) {
+        started.recv_timeout(Duration::from_secs(10)).unwrap()
+    }
+
+    /// Polls until results for all of the chunks arrived, returning everything polled on the way
+    fn poll_until(pool: &ChunkWorkerPool, coords: &[(i32, i32, i32)]) -> Vec<FinishedChunk> {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut finished: Vec<FinishedChunk> = Vec::new();
+        while !coords.iter().all(|coords| {
+            finished
+                .iter()
+                .any(|chunk| (chunk.x, chunk.y, chunk.z) == *coords)
+        }) {
+            assert!(Instant::now() < deadline, "no result for {:?}", coords);
+            finished.extend(pool.poll());
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        finished
+    }
+
+    #[test]
+    fn requests_run_in_priority_order() {
+        let (generator, started) = TestGenerator::new();
+        let pool = ChunkWorkerPool::new(1, 16);
+
+        //Keep the only worker busy while the other requests are queued
+        pool.request(BLOCKING_X, 0, 0, 0, source(&generator))
+            .unwrap();
+        assert_eq!(wait_started(&started), (BLOCKING_X, 0, 0));
+        for (x, priority) in [(1, 5), (2, 1), (3, 9), (4, 1), (5, 3)] {
+            pool.request(x, 0, 0, priority, source(&generator)).unwrap();
+        }
+        //Requesting a queued chunk again replaces its priority
+        pool.request(3, 0, 0, 0, source(&generator)).unwrap();
+        assert_eq!(pool.queued_count(), 5);
+        assert_eq!(pool.requested_priority(3, 0, 0), Some(0));
+        assert_eq!(pool.requested_priority(1, 0, 0), Some(5));
+        assert_eq!(pool.requested_priority(6, 0, 0), None);
+
+        generator.open_gate();
+        let order: Vec<i32> = (0..5).map(|_| wait_started(&started).0).collect();
+        assert_eq!(order, vec![3, 2, 4, 5, 1]);
+        poll_until(&pool, &[(1, 0, 0)]);
+        assert_eq!(pool.requested_priority(1, 0, 0), None);
+    }
+
+    #[test]
+    fn results_of_cancelled_requests_are_dropped() {
+        let (generator, started) = TestGenerator::new();
+        let pool = ChunkWorkerPool::new(1, 16);
+
+        pool.request(BLOCKING_X, 0, 0, 0, source(&generator))
+            .unwrap();
+        wait_started(&started);
+        assert!(pool.cancel(BLOCKING_X, 0, 0));
+        assert!(!pool.is_requested(BLOCKING_X, 0, 0));
+        assert!(!pool.cancel(BLOCKING_X, 0, 0));
+
+        //The same worker finishes the cancelled chunk before this one
+        pool.request(1, 0, 0, 0, source(&generator)).unwrap();
+        generator.open_gate();
+        let finished = poll_until(&pool, &[(1, 0, 0)]);
+        assert_eq!(finished.len(), 1);
+        assert!(!pool.is_requested(1, 0, 0));
+    }
+
+    #[test]
+    fn requests_replaced_while_running_only_finish_once() {
+        let (generator, started) = TestGenerator::new();
+        let pool = ChunkWorkerPool::new(1, 16);
+
+        pool.request(BLOCKING_X, 0, 0, 0, source(&generator))
+            .unwrap();
+        wait_started(&started);
+        pool.cancel(BLOCKING_X, 0, 0);
+        pool.request(BLOCKING_X, 0, 0, 0, source(&generator))
+            .unwrap();
+        generator.open_gate();
+
+        //Only the result of the second request is accepted, the first one is dropped
+        let finished = poll_until(&pool, &[(BLOCKING_X, 0, 0)]);
+        assert_eq!(finished.len(), 1);
+        assert!(!pool.is_requested(BLOCKING_X, 0, 0));
+        assert_eq!(wait_started(&started), (BLOCKING_X, 0, 0));
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(pool.poll().is_empty());
+    }
+
+    #[test]
+    fn full_queues_reject_new_requests() {
+        let (generator, started) = TestGenerator::new();
+        let pool = ChunkWorkerPool::new(1, 2);
+
+        //Running requests don't count towards the capacity
+        pool.request(BLOCKING_X, 0, 0, 0, source(&generator))
+            .unwrap();
+        wait_started(&started);
+        pool.request(1, 0, 0, 0, source(&generator)).unwrap();
+        pool.request(2, 0, 0, 0, source(&generator)).unwrap();
+        assert!(matches!(
+            pool.request(3, 0, 0, 0, source(&generator)),
+            Err(Error::WorkerQueueFull)
+        ));
+
+        //Changing the priority of a queued chunk still works, and cancelling makes room
+        pool.request(2, 0, 0, 5, source(&generator)).unwrap();
+        pool.request(BLOCKING_X, 0, 0, 0, source(&generator))
+            .unwrap();
+        assert!(pool.cancel(1, 0, 0));
+        pool.request(3, 0, 0, 0, source(&generator)).unwrap();
+        assert_eq!(pool.queued_count(), 2);
+
+        generator.open_gate();
+        poll_until(&pool, &[(2, 0, 0)]);
+    }
+
+    #[test]
+    fn panicking_generators_report_an_error_and_keep_the_worker() {
+        let (generator, _started) = TestGenerator::new();
+        let pool = ChunkWorkerPool::new(1, 16);
+
+        pool.request(PANICKING_X, 0, 0, 0, source(&generator))
+            .unwrap();
+        let finished = poll_until(&pool, &[(PANICKING_X, 0, 0)]);
+        assert!(matches!(
+            &finished[0].result,
+            Err(Error::ChunkWorkerPanicked(PANICKING_X, 0, 0, message)) if message == "generator failure"
+        ));
+        assert!(!pool.is_requested(PANICKING_X, 0, 0));
+
+        pool.request(1, 0, 0, 0, source(&generator)).unwrap();
+        let finished = poll_until(&pool, &[(1, 0, 0)]);
+        assert!(finished[0].result.is_ok());
+    }
+
+    #[test]
+    fn saved_chunks_are_loaded_instead_of_generated() {
+        let dir = TempDir::new("worker-storage");
+        let mut storage = RegionStorage::open(dir.path()).unwrap();
+        storage
+            .save_chunk(&VoxelArray::new(Voxel { id: 7, data: 2 }), 1, 2, 3)
+            .unwrap();
+
+        let (generator, started) = TestGenerator::new();
+        let source = ChunkSource {
+            storage: Some(Arc::new(Mutex::new(storage))),
+            ..source(&generator)
+        };
+        let pool = ChunkWorkerPool::new(2, 16);
+        pool.request(1, 2, 3, 0, source.clone()).unwrap();
+        pool.request(4, 2, 3, 0, source).unwrap();
+
+        let finished = poll_until(&pool, &[(1, 2, 3), (4, 2, 3)]);
+        assert_eq!(finished.len(), 2);
+        for chunk in finished {
+            let (voxels, _) = chunk.result.unwrap();
+            let expected = match chunk.x {
+                1 => Voxel { id: 7, data: 2 },
+                _ => Voxel { id: 4, data: 0 },
+            };
+            assert_eq!(*voxels.get_voxel_at_index(0), expected);
+        }
+        assert_eq!(wait_started(&started), (4, 2, 3));
+        assert!(started.try_recv().is_err());
+    }
+}